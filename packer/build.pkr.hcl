@@ -42,6 +42,25 @@ build {
     ]
   }
 
+  provisioner "file" {
+    source      = "${path.root}/files/multipath.conf"
+    destination = "/etc/multipath.conf"
+  }
+
+  provisioner "file" {
+    source      = "${path.root}/files/iscsi-multipath"
+    destination = "/etc/initramfs-tools/scripts/local-top/iscsi-multipath"
+  }
+
+  provisioner "shell" {
+    inline = [
+      "echo 'Setting up multipath boot...'",
+      "apt install -y multipath-tools multipath-tools-boot",
+      "chmod 755 /etc/initramfs-tools/scripts/local-top/iscsi-multipath",
+      "update-initramfs -u -k all",
+    ]
+  }
+
   provisioner "shell" {
     inline = [
       "mkdir -p /etc/network/interfaces.d",
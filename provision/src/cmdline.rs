@@ -0,0 +1,309 @@
+use std::error;
+use std::fmt;
+
+/// A kernel command line parameter, either a flag such as `rootwait` or a key/value parameter
+/// such as `root=PARTUUID=...`. Values are kept as written, including any quotes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl Param {
+    /// Creates a key/value parameter.
+    pub fn new(key: &str, value: &str) -> Param {
+        Param {
+            key: key.to_string(),
+            value: Some(value.to_string()),
+        }
+    }
+
+    /// Creates a flag parameter.
+    pub fn flag(key: &str) -> Param {
+        Param {
+            key: key.to_string(),
+            value: None,
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={}", self.key, value),
+            None => write!(f, "{}", self.key),
+        }
+    }
+}
+
+/// Parameters that cannot both be set. Setting one removes the other.
+const CONFLICTING_FLAGS: [(&str, &str); 1] = [("ro", "rw")];
+
+/// Parameters the kernel uses every instance of, rather than only the last. Setting them adds to
+/// the instances already present.
+const MULTI_VALUED_KEYS: [&str; 1] = ["console"];
+
+/// A kernel command line, as found in a Raspberry Pi's `cmdline.txt`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cmdline {
+    params: Vec<Param>,
+}
+
+impl Cmdline {
+    /// Parses a kernel command line. Parameters are separated by whitespace, except inside
+    /// double quotes, which the kernel allows around values containing spaces.
+    pub fn parse(s: &str) -> Result<Cmdline, Box<dyn error::Error>> {
+        let mut words = Vec::new();
+        let mut word = String::new();
+        let mut in_quotes = false;
+
+        for c in s.chars() {
+            if c.is_whitespace() && !in_quotes {
+                if !word.is_empty() {
+                    words.push(word);
+                    word = String::new();
+                }
+
+                continue;
+            }
+
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+
+            word.push(c);
+        }
+
+        if in_quotes {
+            return Err(format!("unterminated quote in kernel command line '{}'", s).into());
+        }
+
+        if !word.is_empty() {
+            words.push(word);
+        }
+
+        let params = words
+            .iter()
+            .map(|word| match word.split_once('=') {
+                Some((key, value)) => Param::new(key, value),
+                None => Param::flag(word),
+            })
+            .collect();
+
+        Ok(Cmdline { params })
+    }
+
+    /// Returns the parameters, in order.
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    /// Sets a parameter. If parameters with the same key exist, the first is replaced in place and
+    /// the rest are removed; otherwise the parameter is appended. Setting a parameter that
+    /// conflicts with another, such as `rw` and `ro`, removes the other.
+    pub fn set(&mut self, param: Param) {
+        for (a, b) in CONFLICTING_FLAGS {
+            if param.key == a {
+                self.remove(b);
+            } else if param.key == b {
+                self.remove(a);
+            }
+        }
+
+        match self.params.iter().position(|p| p.key == param.key) {
+            Some(i) => {
+                self.params[i] = param;
+
+                let key = self.params[i].key.clone();
+                let mut seen = 0;
+
+                self.params.retain(|p| {
+                    if p.key == key {
+                        seen += 1;
+                    }
+
+                    p.key != key || seen == 1
+                });
+            }
+            None => self.params.push(param),
+        }
+    }
+
+    /// Adds a parameter after the last parameter with the same key, or at the end if there is
+    /// none, unless an identical parameter is already present.
+    pub fn add(&mut self, param: Param) {
+        if self.params.contains(&param) {
+            return;
+        }
+
+        match self.params.iter().rposition(|p| p.key == param.key) {
+            Some(i) => self.params.insert(i + 1, param),
+            None => self.params.push(param),
+        }
+    }
+
+    /// Sets each of the given parameters, in order. Multi-valued parameters such as `console` are
+    /// added alongside the existing ones with `add`. Parameters given once are set with `set`, and
+    /// those given more than once together replace every existing parameter with their key, in
+    /// place of the first.
+    pub fn set_all(&mut self, params: &[Param]) {
+        let mut keys: Vec<&str> = Vec::new();
+
+        for param in params {
+            if !keys.contains(&param.key.as_str()) {
+                keys.push(&param.key);
+            }
+        }
+
+        for key in keys {
+            let mut given: Vec<Param> = Vec::new();
+
+            for param in params.iter().filter(|p| p.key == key) {
+                if !given.contains(param) {
+                    given.push(param.clone());
+                }
+            }
+
+            if MULTI_VALUED_KEYS.contains(&key) {
+                for param in given {
+                    self.add(param);
+                }
+            } else if given.len() == 1 {
+                self.set(given.remove(0));
+            } else {
+                let i = self
+                    .params
+                    .iter()
+                    .position(|p| p.key == key)
+                    .unwrap_or(self.params.len());
+
+                self.remove(key);
+
+                let i = i.min(self.params.len());
+
+                self.params.splice(i..i, given);
+            }
+        }
+    }
+
+    /// Removes all parameters with the given key.
+    pub fn remove(&mut self, key: &str) {
+        self.params.retain(|p| p.key != key);
+    }
+}
+
+impl fmt::Display for Cmdline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(|p| p.to_string()).collect();
+
+        write!(f, "{}", params.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RASPIOS_CMDLINE: &str = "console=serial0,115200 console=tty1 root=PARTUUID=abcd1234-02 rootfstype=ext4 fsck.repair=yes rootwait quiet\n";
+
+    #[test]
+    fn round_trips_unchanged() {
+        let cmdline = Cmdline::parse(RASPIOS_CMDLINE).unwrap();
+
+        assert_eq!(cmdline.to_string(), RASPIOS_CMDLINE.trim_end());
+        assert_eq!(
+            cmdline.params[2],
+            Param::new("root", "PARTUUID=abcd1234-02")
+        );
+        assert_eq!(cmdline.params[5], Param::flag("rootwait"));
+    }
+
+    #[test]
+    fn parses_quoted_values() {
+        let cmdline = Cmdline::parse(r#"dyndbg="file drivers/net/* +p" quiet"#).unwrap();
+
+        assert_eq!(
+            cmdline.params,
+            [
+                Param::new("dyndbg", r#""file drivers/net/* +p""#),
+                Param::flag("quiet"),
+            ]
+        );
+        assert_eq!(
+            cmdline.to_string(),
+            r#"dyndbg="file drivers/net/* +p" quiet"#
+        );
+
+        assert_eq!(
+            Cmdline::parse(r#"dyndbg="file"#).unwrap_err().to_string(),
+            r#"unterminated quote in kernel command line 'dyndbg="file'"#
+        );
+    }
+
+    #[test]
+    fn sets_params_idempotently() {
+        let mut cmdline = Cmdline::parse(RASPIOS_CMDLINE).unwrap();
+
+        for _ in 0..2 {
+            cmdline.set(Param::new("root", "PARTUUID=5e3da7a1-01"));
+            cmdline.set(Param::new("ip", "dhcp"));
+            cmdline.set(Param::flag("rw"));
+        }
+
+        assert_eq!(
+            cmdline.to_string(),
+            "console=serial0,115200 console=tty1 root=PARTUUID=5e3da7a1-01 rootfstype=ext4 fsck.repair=yes rootwait quiet ip=dhcp rw"
+        );
+
+        // Setting a repeated parameter leaves a single one in place of the first.
+        cmdline.set(Param::new("console", "ttyAMA0,115200"));
+
+        assert_eq!(
+            cmdline.to_string(),
+            "console=ttyAMA0,115200 root=PARTUUID=5e3da7a1-01 rootfstype=ext4 fsck.repair=yes rootwait quiet ip=dhcp rw"
+        );
+    }
+
+    #[test]
+    fn keeps_every_console_and_repeated_param() {
+        let mut cmdline = Cmdline::parse(RASPIOS_CMDLINE).unwrap();
+
+        let params = [
+            Param::new("root", "PARTUUID=5e3da7a1-01"),
+            Param::new("console", "ttyAMA0"),
+            Param::new("module_blacklist", "btusb"),
+            Param::new("module_blacklist", "hci_uart"),
+            Param::new("console", "tty1"),
+        ];
+
+        for _ in 0..2 {
+            cmdline.set_all(&params);
+        }
+
+        // Consoles are added to the image's, and a parameter given twice keeps both values.
+        assert_eq!(
+            cmdline.to_string(),
+            "console=serial0,115200 console=tty1 console=ttyAMA0 root=PARTUUID=5e3da7a1-01 rootfstype=ext4 fsck.repair=yes rootwait quiet module_blacklist=btusb module_blacklist=hci_uart"
+        );
+
+        cmdline.set_all(&[Param::new("module_blacklist", "btusb")]);
+
+        assert_eq!(
+            cmdline.to_string(),
+            "console=serial0,115200 console=tty1 console=ttyAMA0 root=PARTUUID=5e3da7a1-01 rootfstype=ext4 fsck.repair=yes rootwait quiet module_blacklist=btusb"
+        );
+    }
+
+    #[test]
+    fn replaces_conflicting_flags() {
+        let mut cmdline = Cmdline::parse("root=/dev/sda2 ro quiet").unwrap();
+
+        cmdline.set(Param::flag("rw"));
+
+        assert_eq!(cmdline.to_string(), "root=/dev/sda2 quiet rw");
+
+        cmdline.set(Param::flag("ro"));
+
+        assert_eq!(cmdline.to_string(), "root=/dev/sda2 quiet ro");
+    }
+}
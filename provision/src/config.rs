@@ -1,30 +1,198 @@
+use std::collections;
 use std::error;
+use std::fmt;
 use std::fs;
+use std::net;
 use std::path;
 
-use serde;
+use clap::CommandFactory;
+use clap::Parser;
 use serde::de;
-use serde_json;
 
-/// A configuration for a given instance provisioning run.
+use crate::validate;
+
+/// The default path to the workspace configuration file.
+const DEFAULT_WORKSPACE_CONFIG_PATH: &str = "configs/workspace.json";
+/// The default path to the directory of instance configuration files.
+const DEFAULT_INSTANCES_CONFIG_DIR: &str = "configs/instances";
+
+/// Shown in place of passwords in plans and printed configs.
+pub const SECRET_PLACEHOLDER: &str = "REDACTED";
+
+/// A command to run against the selected instances.
+#[derive(clap::Subcommand, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Provisions the selected instances.
+    Provision {
+        /// Only run the steps whose desired state does not already hold, e.g. skipping formatting
+        /// and copying if the instance already holds a copy of the current image.
+        #[arg(long)]
+        reconcile: bool,
+    },
+    /// Prints the steps that would run for each selected instance without running them.
+    Plan {
+        /// The operation to plan.
+        #[arg(value_enum, default_value_t = crate::Operation::Provision)]
+        operation: crate::Operation,
+    },
+    /// Checks that the selected instances are provisioned as configured.
+    Verify,
+    /// Wipes the boot directory and root filesystem of the selected instances.
+    Deprovision,
+    /// Tears down mounts and iSCSI sessions left behind by a crashed run of the selected instances.
+    Cleanup,
+    /// Lists the selected instances.
+    List,
+    /// Checks the workspace config and every instance config, reporting every problem found.
+    Validate,
+    /// Prints an instance's config in JSON, after applying the workspace's instance defaults and
+    /// the profiles it extends.
+    ShowConfig {
+        /// The ID of the instance.
+        id: String,
+    },
+    /// Prints the step graph for an operation in DOT format.
+    Graph {
+        /// The operation whose graph should be printed.
+        #[arg(value_enum, default_value_t = crate::Operation::Provision)]
+        operation: crate::Operation,
+    },
+}
+
+#[derive(clap::Parser)]
+#[command(about = "Provisions Raspberry Pi instances to boot over iSCSI and NFS")]
+struct Cli {
+    /// The path to the workspace configuration file, in JSON, TOML or YAML.
+    #[arg(long, global = true, default_value = DEFAULT_WORKSPACE_CONFIG_PATH)]
+    workspace: path::PathBuf,
+    /// The path to the directory of instance configuration files, in JSON, TOML or YAML.
+    #[arg(long, global = true, default_value = DEFAULT_INSTANCES_CONFIG_DIR)]
+    instances_dir: path::PathBuf,
+    /// Only operate on instances whose IDs match the given ID or glob pattern. May be given more
+    /// than once.
+    #[arg(long, global = true, value_name = "PATTERN", value_parser = parse_pattern)]
+    only: Vec<glob::Pattern>,
+    /// Only operate on instances with the given label. May be given more than once, in which
+    /// case instances must have all of the given labels.
+    #[arg(long, global = true, value_name = "KEY=VALUE", value_parser = parse_label)]
+    label: Vec<(String, String)>,
+    /// The maximum number of instances to operate on at once. Overrides
+    /// `max_parallel_instances` in the workspace config.
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_parallel: Option<u64>,
+    /// Write a JSON report of every step's outcome and timing to the given path when the
+    /// command finishes.
+    #[arg(long, global = true, value_name = "PATH")]
+    report: Option<path::PathBuf>,
+    /// Take over workspace and instance locks held by other runs, e.g. runs that were killed
+    /// before they could release them.
+    #[arg(long, global = true)]
+    break_stale_locks: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+fn parse_pattern(s: &str) -> Result<glob::Pattern, glob::PatternError> {
+    glob::Pattern::new(s)
+}
+
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid label '{}', expected KEY=VALUE", s)),
+    }
+}
+
+/// Criteria for selecting a subset of instances.
+#[derive(Default)]
+pub struct Selection {
+    /// Patterns matched against instance IDs. An instance is selected if its ID matches any
+    /// pattern. Empty means all instance IDs are selected.
+    pub only: Vec<glob::Pattern>,
+    /// Labels an instance must have to be selected.
+    pub labels: Vec<(String, String)>,
+}
+
+impl Selection {
+    /// Returns whether the given instance config is selected.
+    pub fn matches(&self, instance_spec: &InstanceConfig) -> bool {
+        let id_matches =
+            self.only.is_empty() || self.only.iter().any(|p| p.matches(&instance_spec.id));
+
+        let labels_match = self
+            .labels
+            .iter()
+            .all(|(key, value)| instance_spec.labels.get(key) == Some(value));
+
+        id_matches && labels_match
+    }
+}
+
+/// A configuration for a given invocation of the provisioning binary.
 pub struct Config {
-    /// The path to the workspace configuration JSON file.
+    /// The path to the workspace configuration file.
     pub workspace_config_path: path::PathBuf,
-    /// The path to the directory of instance configuration JSON files.
+    /// The path to the directory of instance configuration files.
     pub instances_config_dir: path::PathBuf,
+    /// The instances to operate on.
+    pub selection: Selection,
+    /// The maximum number of instances to operate on at once, if overridden.
+    pub max_parallel_instances: Option<usize>,
+    /// The path to write a JSON run report to, if any.
+    pub report_path: Option<path::PathBuf>,
+    /// Whether to take over locks held by other runs.
+    pub break_stale_locks: bool,
+    /// The command to run.
+    pub command: Command,
 }
 
 impl Config {
-    /// Builds a configuration from the given command line arguments.
-    pub fn build(args: &[String]) -> Result<Config, Box<dyn error::Error>> {
-        let workspace_config_path = args.get(1).ok_or("missing workspace config path")?.into();
+    /// Builds a configuration from the given command line arguments. For compatibility, the form
+    /// `<workspace config path> <instances config dir>` is treated as the `provision` command.
+    /// Returns an error, which also carries help and version output, if the arguments are invalid
+    /// or help is requested.
+    pub fn build(args: &[String]) -> Result<Config, clap::Error> {
+        if let [bin, workspace, instances_dir] = args {
+            // Building the command adds the generated `help` subcommand.
+            let mut command = Cli::command();
+            command.build();
 
-        let instances_config_dir = args.get(2).ok_or("missing instances config dir")?.into();
+            let is_legacy = !workspace.starts_with('-')
+                && !instances_dir.starts_with('-')
+                && command.find_subcommand(workspace).is_none();
 
-        Ok(Config {
-            workspace_config_path,
-            instances_config_dir,
-        })
+            if is_legacy {
+                let legacy_args = [
+                    bin.as_str(),
+                    "--workspace",
+                    workspace,
+                    "--instances-dir",
+                    instances_dir,
+                    "provision",
+                ];
+
+                return Ok(Cli::try_parse_from(legacy_args)?.into());
+            }
+        }
+
+        Ok(Cli::try_parse_from(args)?.into())
+    }
+}
+
+impl From<Cli> for Config {
+    fn from(cli: Cli) -> Config {
+        Config {
+            workspace_config_path: cli.workspace,
+            instances_config_dir: cli.instances_dir,
+            selection: Selection {
+                only: cli.only,
+                labels: cli.label,
+            },
+            max_parallel_instances: cli.max_parallel.map(|n| n as usize),
+            report_path: cli.report,
+            break_stale_locks: cli.break_stale_locks,
+            command: cli.command,
+        }
     }
 }
 
@@ -35,53 +203,761 @@ pub struct WorkspaceConfig {
     pub path: String,
     /// The path to the Raspberry Pi OS image to use.
     pub img_path: String,
-    /// The offset for the rootfs partition in the image in bytes. Use `fdisk -l` to find this.
-    pub img_rootfs_offset: u64,
-    /// The offset for the boot partition in the image in bytes. Use `fdisk -l` to find this.
-    pub img_boot_offset: u64,
-    /// The iSCSI target IP address. Used for mounting root partitions.
-    pub iscsi_target_ip: String,
+    /// The offset for the rootfs partition in the image in bytes. Detected from the image's
+    /// partition table if not set; if set, it must be the start of an ext4 partition.
+    pub img_rootfs_offset: Option<u64>,
+    /// The offset for the boot partition in the image in bytes. Detected from the image's
+    /// partition table if not set; if set, it must be the start of a FAT partition.
+    pub img_boot_offset: Option<u64>,
+    /// The iSCSI target IP address, for a single portal on the default port. Shorthand for an
+    /// `iscsi_portals` list with one portal; only one of the two may be set.
+    pub iscsi_target_ip: Option<net::IpAddr>,
+    /// The iSCSI portals instances' targets are reached through. Instances use the first unless
+    /// they pick another with `iscsi_portal`.
+    #[serde(default)]
+    pub iscsi_portals: Vec<PortalConfig>,
     /// The NFS server IP address. Used for mounting TFTP boot partitions.
     pub nfs_server_ip: String,
     /// The TFTP directory on the NFS server.
     pub nfs_tftp_dir: String,
+    /// The maximum number of instances to operate on at once. Defaults to 1, since concurrent
+    /// provisioning can swamp the NAS.
+    #[serde(default = "default_max_parallel_instances")]
+    pub max_parallel_instances: usize,
+    /// How to wait for iSCSI devices to appear after logging in or partitioning.
+    #[serde(default)]
+    pub device_wait: DeviceWaitConfig,
+    /// How to create and mount instances' root filesystems. Defaults to ext4.
+    #[serde(default)]
+    pub rootfs: FilesystemConfig,
+    /// Extra kernel parameters to set in every instance's `cmdline.txt`, e.g.
+    /// `["cgroup_enable=memory"]`. Each replaces any existing parameters with the same name,
+    /// except `console`, which is added to the existing consoles.
+    #[serde(default)]
+    pub kernel_params: Vec<String>,
+    /// Per-step settings keyed by step name, e.g. `{"copy data": {"max_concurrent": 1}}`.
+    #[serde(default)]
+    pub steps: collections::HashMap<String, StepConfig>,
+    /// Instance config fields shared by every instance, e.g. `{"root_ssh_key": "ssh-ed25519 ..."}`.
+    /// Instance configs override them field by field. `{id}` in any string is replaced with the
+    /// instance's ID, e.g. `"iqn.2024-01.com.example:{id}"`.
+    #[serde(default)]
+    pub instance_defaults: serde_json::Map<String, serde_json::Value>,
+    /// Named sets of instance config fields, applied on top of `instance_defaults` to instances
+    /// that list them in `extends`. Expanded like `instance_defaults`.
+    #[serde(default)]
+    pub profiles: collections::BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
 }
 
-/// A configuration for an instance. An instance is a single Raspberry Pi machine.
+fn default_max_parallel_instances() -> usize {
+    1
+}
+
+impl WorkspaceConfig {
+    /// Returns the configured iSCSI portals. Returns an error if there are none, if both
+    /// `iscsi_target_ip` and `iscsi_portals` are set, or if two portals have the same name.
+    pub fn iscsi_portals(&self) -> Result<Vec<PortalConfig>, Box<dyn error::Error>> {
+        match (&self.iscsi_target_ip, self.iscsi_portals.is_empty()) {
+            (Some(_), false) => {
+                Err("only one of iscsi_target_ip and iscsi_portals may be set".into())
+            }
+            (None, true) => Err("no iSCSI portals configured".into()),
+            (Some(host), true) => Ok(vec![PortalConfig {
+                name: None,
+                host: *host,
+                port: default_iscsi_port(),
+            }]),
+            (None, false) => {
+                let mut names = collections::HashSet::new();
+
+                for name in self.iscsi_portals.iter().filter_map(|p| p.name.as_ref()) {
+                    if !names.insert(name) {
+                        return Err(format!("more than one iSCSI portal is named {}", name).into());
+                    }
+                }
+
+                Ok(self.iscsi_portals.clone())
+            }
+        }
+    }
+}
+
+fn default_iscsi_port() -> u16 {
+    3260
+}
+
+/// An iSCSI portal: an address and port an iSCSI target is reached through.
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct PortalConfig {
+    /// The name instances use to pick the portal with `iscsi_portal`.
+    pub name: Option<String>,
+    /// The IPv4 or IPv6 address of the portal.
+    pub host: net::IpAddr,
+    /// The TCP port of the portal. Defaults to 3260.
+    #[serde(default = "default_iscsi_port")]
+    pub port: u16,
+}
+
+impl PortalConfig {
+    /// Returns the portal's address in the form iscsiadm expects, e.g. `10.0.0.2:3260` or
+    /// `[fd00::2]:3260`.
+    pub fn address(&self) -> String {
+        net::SocketAddr::new(self.host, self.port).to_string()
+    }
+}
+
+/// A configuration for polling for a device node to appear. The poll interval starts at
+/// `initial_interval_ms` and doubles after each poll up to `max_interval_ms`.
 #[derive(serde::Deserialize)]
+#[serde(default)]
+pub struct DeviceWaitConfig {
+    /// How long to wait for a device before failing, in seconds.
+    pub timeout_secs: u64,
+    /// The interval between the first two polls, in milliseconds.
+    pub initial_interval_ms: u64,
+    /// The maximum interval between polls, in milliseconds.
+    pub max_interval_ms: u64,
+}
+
+impl Default for DeviceWaitConfig {
+    fn default() -> Self {
+        DeviceWaitConfig {
+            timeout_secs: 30,
+            initial_interval_ms: 100,
+            max_interval_ms: 2_000,
+        }
+    }
+}
+
+/// A filesystem type supported for instance partitions.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilesystemType {
+    #[default]
+    Ext4,
+    Xfs,
+    Btrfs,
+    F2fs,
+    Swap,
+}
+
+impl fmt::Display for FilesystemType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilesystemType::Ext4 => write!(f, "ext4"),
+            FilesystemType::Xfs => write!(f, "xfs"),
+            FilesystemType::Btrfs => write!(f, "btrfs"),
+            FilesystemType::F2fs => write!(f, "f2fs"),
+            FilesystemType::Swap => write!(f, "swap"),
+        }
+    }
+}
+
+/// A configuration for creating and mounting a filesystem on an instance's iSCSI LUN.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct FilesystemConfig {
+    /// The filesystem type to create.
+    #[serde(rename = "type")]
+    pub fstype: FilesystemType,
+    /// The filesystem label. Unlabeled if unset.
+    pub label: Option<String>,
+    /// Extra options passed to `mkfs`, or `mkswap` for swap, e.g. `["-O", "^has_journal"]`.
+    pub mkfs_options: Vec<String>,
+    /// The mount options written to the instance's fstab, and used when mounting the filesystem to
+    /// provision it. `_netdev` is always added to the fstab, since the filesystem is on an iSCSI
+    /// device. Ignored for swap.
+    pub mount_options: Vec<String>,
+}
+
+impl Default for FilesystemConfig {
+    fn default() -> Self {
+        FilesystemConfig {
+            fstype: FilesystemType::Ext4,
+            label: None,
+            mkfs_options: Vec::new(),
+            mount_options: vec![String::from("noatime")],
+        }
+    }
+}
+
+/// A configuration for the iSCSI LUN an instance boots from and the partitions on it.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DiskConfig {
+    /// The LUN of the instance's iSCSI target to use.
+    pub lun: u32,
+    /// The partitions to create, in order. If empty, a single root partition spanning the LUN is
+    /// created with the `rootfs` settings.
+    pub partitions: Vec<PartitionConfig>,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        DiskConfig {
+            lun: 1,
+            partitions: Vec::new(),
+        }
+    }
+}
+
+/// A configuration for a partition on an instance's iSCSI LUN.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct PartitionConfig {
+    /// Where the partition is mounted, e.g. `/` or `/var`. Must be unset for swap.
+    pub mount_point: Option<String>,
+    /// The size of the partition, e.g. `512MiB` or `8GiB`. If unset, the partition fills the rest
+    /// of the LUN, so only the last partition may leave it unset.
+    pub size: Option<String>,
+    /// The filesystem to create on the partition.
+    #[serde(flatten)]
+    pub filesystem: FilesystemConfig,
+}
+
+impl PartitionConfig {
+    /// Returns the size of the partition in MiB, or `None` if it fills the rest of the LUN.
+    pub fn size_mib(&self) -> Result<Option<u64>, Box<dyn error::Error>> {
+        let size = match &self.size {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+
+        let units = [("MiB", 1), ("GiB", 1024), ("TiB", 1024 * 1024)];
+
+        for (suffix, mib) in units {
+            if let Some(number) = size.strip_suffix(suffix) {
+                let number: u64 = number
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid partition size '{}'", size))?;
+
+                if number == 0 {
+                    return Err(
+                        format!("invalid partition size '{}': must not be zero", size).into(),
+                    );
+                }
+
+                return Ok(Some(number * mib));
+            }
+        }
+
+        Err(format!(
+            "invalid partition size '{}': expected a whole number of MiB, GiB or TiB",
+            size
+        )
+        .into())
+    }
+
+    /// Describes the partition by its mount point, or as swap.
+    pub fn describe(&self) -> String {
+        match &self.mount_point {
+            Some(mount_point) => format!("{} partition", mount_point),
+            None => format!("{} partition", self.filesystem.fstype),
+        }
+    }
+}
+
+/// A CHAP username and password.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct ChapCredentials {
+    /// The CHAP username. Must not be empty or contain whitespace or quotes.
+    pub username: String,
+    /// The CHAP password. Must not be empty or contain whitespace or quotes. Serialized as
+    /// `SECRET_PLACEHOLDER`.
+    #[serde(serialize_with = "serialize_secret")]
+    pub password: String,
+}
+
+/// Serializes a secret as a placeholder, so that printed configs do not reveal it.
+fn serialize_secret<S: serde::Serializer>(_: &String, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(SECRET_PLACEHOLDER)
+}
+
+impl ChapCredentials {
+    /// Returns an error if the credentials cannot be passed on the kernel command line.
+    fn validate(&self, field: &str) -> Result<(), Box<dyn error::Error>> {
+        for (name, value) in [("username", &self.username), ("password", &self.password)] {
+            if value.is_empty() {
+                return Err(format!("{} {} must not be empty", field, name).into());
+            }
+
+            if value.contains(|c: char| c.is_whitespace() || c == '"') {
+                return Err(
+                    format!("{} {} must not contain whitespace or quotes", field, name).into(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A configuration for a step, shared by all instances in a workspace.
+#[derive(serde::Deserialize, Default)]
+pub struct StepConfig {
+    /// The maximum number of instances that may run the step at once. Unlimited if unset.
+    pub max_concurrent: Option<usize>,
+    /// The maximum time in seconds a single attempt of the step may take. Overrides the step's
+    /// default timeout.
+    pub timeout_secs: Option<u64>,
+    /// The maximum number of times to attempt the step. Overrides the step's default.
+    pub max_attempts: Option<u32>,
+    /// The delay before retrying the step in milliseconds. Doubles after each failed attempt.
+    pub retry_delay_ms: Option<u64>,
+    /// The maximum delay between retries in milliseconds.
+    pub max_retry_delay_ms: Option<u64>,
+}
+
+/// A configuration for an instance. An instance is a single Raspberry Pi machine.
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct InstanceConfig {
     /// The ID of the instance. Used to determine the hostname.
     pub id: String,
+    /// The names of the workspace profiles the instance's config extends, in the order they are
+    /// applied. Fields set in the instance's config take precedence over all of them.
+    #[serde(default)]
+    pub extends: Vec<String>,
     /// The iSCSI initiator IQN. Used when mounting the root filesystem.
     pub iscsi_initiator_iqn: String,
     /// The iSCSI target IQN. Used to determine which target to mount as the root filesystem.
     pub iscsi_target_iqn: String,
+    /// The name of the workspace iSCSI portal the target is reached through. Defaults to the first
+    /// portal.
+    pub iscsi_portal: Option<String>,
+    /// The names of the workspace iSCSI portals the target is reached through, for targets with
+    /// more than one network path. With more than one, both provisioning and the instance's
+    /// initramfs log in through each and use the multipath device combining them, so the image
+    /// must be built with multipath boot support. Only one of `iscsi_portal` and `iscsi_portals`
+    /// may be set.
+    #[serde(default)]
+    pub iscsi_portals: Vec<String>,
     /// The MAC address for the Raspberry Pi in the form `aa-bb-cc-dd-ee-ff`.
     pub mac_addr: String,
     /// The username and password to use in the form `<username>:<hash>`. Use `openssl passwd -6` to generate the hash.
     pub user_password: String,
     /// The SSH key to use for root login.
     pub root_ssh_key: String,
+    /// The CHAP credentials the initiator uses to log into the iSCSI target. No authentication is
+    /// used if unset.
+    pub chap: Option<ChapCredentials>,
+    /// The CHAP credentials the target uses to authenticate itself to the initiator, for mutual
+    /// CHAP. Requires `chap`.
+    pub mutual_chap: Option<ChapCredentials>,
+    /// How to create and mount the instance's root filesystem. Replaces the workspace's `rootfs`
+    /// if set.
+    pub rootfs: Option<FilesystemConfig>,
+    /// The iSCSI LUN to use and the partitions to create on it.
+    #[serde(default)]
+    pub disk: DiskConfig,
+    /// Extra kernel parameters to set in the instance's `cmdline.txt`. Set after the workspace's
+    /// `kernel_params`, so they take precedence.
+    #[serde(default)]
+    pub kernel_params: Vec<String>,
+    /// Arbitrary key/value labels used to select instances, e.g. `{"role": "worker"}`.
+    #[serde(default)]
+    pub labels: collections::BTreeMap<String, String>,
+}
+
+impl InstanceConfig {
+    /// Returns the root filesystem configuration for the instance: its own if set, otherwise the
+    /// workspace's.
+    pub fn rootfs<'a>(&'a self, workspace_spec: &'a WorkspaceConfig) -> &'a FilesystemConfig {
+        self.rootfs.as_ref().unwrap_or(&workspace_spec.rootfs)
+    }
+
+    /// Returns the iSCSI portals the instance's target is reached through, in order. Returns an
+    /// error if the workspace's portals are invalid, if both `iscsi_portal` and `iscsi_portals`
+    /// are set, or if a name is unknown or repeated.
+    pub fn iscsi_portals(
+        &self,
+        workspace_spec: &WorkspaceConfig,
+    ) -> Result<Vec<PortalConfig>, Box<dyn error::Error>> {
+        let portals = workspace_spec.iscsi_portals()?;
+
+        let names: Vec<&String> = match (&self.iscsi_portal, self.iscsi_portals.is_empty()) {
+            (Some(_), false) => {
+                return Err("only one of iscsi_portal and iscsi_portals may be set".into());
+            }
+            (Some(name), true) => vec![name],
+            (None, false) => self.iscsi_portals.iter().collect(),
+            (None, true) => return Ok(vec![portals[0].clone()]),
+        };
+
+        let mut selected: Vec<PortalConfig> = Vec::new();
+
+        for name in names {
+            let portal = portals
+                .iter()
+                .find(|p| p.name.as_ref() == Some(name))
+                .ok_or_else(|| format!("no iSCSI portal named {} found", name))?;
+
+            if selected.contains(portal) {
+                return Err(format!("iSCSI portal {} is listed more than once", name).into());
+            }
+
+            selected.push(portal.clone());
+        }
+
+        Ok(selected)
+    }
+
+    /// Returns the iSCSI portal the instance's target is reached through, or the first of them if
+    /// there are several. This is the portal the instance's initramfs logs in through first.
+    pub fn iscsi_portal(
+        &self,
+        workspace_spec: &WorkspaceConfig,
+    ) -> Result<PortalConfig, Box<dyn error::Error>> {
+        let portal = self
+            .iscsi_portals(workspace_spec)?
+            .into_iter()
+            .next()
+            .expect("portals are not empty");
+
+        Ok(portal)
+    }
+
+    /// Returns whether the instance's target is reached through more than one portal, and so
+    /// through a multipath device.
+    pub fn multipath(&self) -> bool {
+        self.iscsi_portals.len() > 1
+    }
+
+    /// Returns an error if `mutual_chap` is set without `chap`, or if the CHAP credentials cannot
+    /// be passed on the kernel command line.
+    pub fn validate_chap(&self) -> Result<(), Box<dyn error::Error>> {
+        if self.chap.is_none() && self.mutual_chap.is_some() {
+            return Err("mutual_chap is set but chap is not".into());
+        }
+
+        if let Some(chap) = &self.chap {
+            chap.validate("chap")?;
+        }
+
+        if let Some(mutual_chap) = &self.mutual_chap {
+            mutual_chap.validate("mutual_chap")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the partitions to create on the instance's LUN, in order. Returns an error if the
+    /// partitions do not include exactly one root partition, if swap has a mount point, if a
+    /// mount point is used twice or missing, or if a partition other than the last has no size.
+    pub fn partitions(
+        &self,
+        workspace_spec: &WorkspaceConfig,
+    ) -> Result<Vec<PartitionConfig>, Box<dyn error::Error>> {
+        if self.disk.partitions.is_empty() {
+            if self.rootfs(workspace_spec).fstype == FilesystemType::Swap {
+                return Err("the root filesystem cannot be swap".into());
+            }
+
+            return Ok(vec![PartitionConfig {
+                mount_point: Some(String::from("/")),
+                size: None,
+                filesystem: self.rootfs(workspace_spec).clone(),
+            }]);
+        }
+
+        let mut mount_points = collections::HashSet::new();
+
+        for (i, partition) in self.disk.partitions.iter().enumerate() {
+            let is_swap = partition.filesystem.fstype == FilesystemType::Swap;
+
+            match &partition.mount_point {
+                Some(_) if is_swap => {
+                    return Err(format!("partition {} is swap but has a mount point", i + 1).into());
+                }
+                Some(mount_point) if !mount_point.starts_with('/') => {
+                    return Err(format!(
+                        "partition {} has mount point '{}', which is not an absolute path",
+                        i + 1,
+                        mount_point
+                    )
+                    .into());
+                }
+                Some(mount_point) if !is_normal_mount_point(mount_point) => {
+                    return Err(format!(
+                        "partition {} has mount point '{}', which must not have empty, '.' or '..' components or whitespace",
+                        i + 1,
+                        mount_point
+                    )
+                    .into());
+                }
+                Some(mount_point) if !mount_points.insert(mount_point.clone()) => {
+                    return Err(
+                        format!("more than one partition is mounted at {}", mount_point).into(),
+                    );
+                }
+                None if !is_swap => {
+                    return Err(format!("partition {} has no mount point", i + 1).into());
+                }
+                _ => {}
+            }
+
+            let size = partition.size_mib()?;
+
+            if size.is_none() && i + 1 < self.disk.partitions.len() {
+                return Err(format!(
+                    "partition {} has no size, but only the last partition may fill the rest of the LUN",
+                    i + 1
+                )
+                .into());
+            }
+        }
+
+        if !mount_points.contains("/") {
+            return Err("no partition is mounted at /".into());
+        }
+
+        Ok(self.disk.partitions.clone())
+    }
+}
+
+/// Returns whether the given absolute mount point is `/` or a path of named components below it,
+/// e.g. `/var/lib`, so that it stays inside the instance's root filesystem when the partition is
+/// mounted to provision it.
+fn is_normal_mount_point(mount_point: &str) -> bool {
+    mount_point == "/"
+        || mount_point.split('/').skip(1).all(|component| {
+            !component.is_empty()
+                && component != "."
+                && component != ".."
+                && !component.chars().any(char::is_whitespace)
+        })
+}
+
+/// The formats config files can be written in, detected from their extensions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Returns the format of the config file at the given path, if its extension is a known one.
+    fn from_path(path: &path::Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the name of the field a deserialization error is in, if it is in a field. Errors in the
+/// file as a whole, like missing top-level fields, have the path ".", and syntax errors that cannot
+/// be placed in a field have the path "?".
+fn error_field(path: &serde_path_to_error::Path) -> Option<String> {
+    match path.to_string().as_str() {
+        "." | "?" => None,
+        field => Some(field.to_string()),
+    }
+}
+
+/// Describes a TOML error with its line and column, in the same form as JSON and YAML errors.
+fn toml_error_msg(contents: &str, e: &toml::de::Error) -> String {
+    let start = match e.span() {
+        Some(span) => span.start,
+        None => return e.message().to_string(),
+    };
+
+    let before = &contents[..start];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+
+    format!("{} at line {} column {}", e.message(), line, column)
 }
 
-fn load_from_path<T: de::DeserializeOwned>(path: &path::Path) -> Result<T, Box<dyn error::Error>> {
-    let f = fs::File::open(path)?;
+/// Loads a config from the given path, in the format its extension names. If the file cannot be
+/// parsed, the problem names the field that could not be, if there is one, and the line and
+/// column of the error.
+pub fn load_from_path<T: de::DeserializeOwned>(path: &path::Path) -> Result<T, validate::Problem> {
+    let problem = |field: Option<String>, msg: String| validate::Problem {
+        path: path.to_path_buf(),
+        field,
+        msg,
+    };
+
+    let format = Format::from_path(path).ok_or_else(|| {
+        problem(
+            None,
+            String::from("unknown config file extension, expected .json, .toml, .yaml or .yml"),
+        )
+    })?;
+
+    let contents = fs::read_to_string(path).map_err(|e| problem(None, e.to_string()))?;
+
+    match format {
+        Format::Json => {
+            let mut de = serde_json::Deserializer::from_str(&contents);
+
+            let config = serde_path_to_error::deserialize(&mut de)
+                .map_err(|e| problem(error_field(e.path()), e.inner().to_string()))?;
+
+            de.end().map_err(|e| problem(None, e.to_string()))?;
+
+            Ok(config)
+        }
+        Format::Toml => {
+            let de = toml::de::Deserializer::parse(&contents)
+                .map_err(|e| problem(None, toml_error_msg(&contents, &e)))?;
+
+            serde_path_to_error::deserialize(de)
+                .map_err(|e| problem(error_field(e.path()), toml_error_msg(&contents, e.inner())))
+        }
+        Format::Yaml => {
+            let de = serde_yaml_ng::Deserializer::from_str(&contents);
+
+            serde_path_to_error::deserialize(de).map_err(|e| {
+                let field = error_field(e.path());
+
+                // YAML errors start with the field they are in, which the problem already names.
+                let msg = e.inner().to_string();
+
+                let msg = match &field {
+                    Some(field) => msg
+                        .strip_prefix(&format!("{}: ", field))
+                        .map(String::from)
+                        .unwrap_or(msg),
+                    None => msg,
+                };
 
-    match serde_json::from_reader(f) {
-        Ok(c) => Ok(c),
-        Err(e) => Err(e.into()),
+                problem(field, msg)
+            })
+        }
     }
 }
 
 /// Loads the workspace config from the given path.
-pub fn load_workspace_config(path: &path::Path) -> Result<WorkspaceConfig, Box<dyn error::Error>> {
+pub fn load_workspace_config(path: &path::Path) -> Result<WorkspaceConfig, validate::Problem> {
     load_from_path(path)
 }
 
-/// Loads all instance configs from the given directory path. Any file with the extension `json` is considered to be an instance config.
+/// Returns a copy of the given instance config fields with `{id}` in every string replaced by the
+/// given instance ID.
+fn expand_fields(value: &serde_json::Value, id: &str) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(s) => serde_json::Value::String(s.replace("{id}", id)),
+        serde_json::Value::Array(values) => serde_json::Value::Array(
+            values
+                .iter()
+                .map(|value| expand_fields(value, id))
+                .collect(),
+        ),
+        serde_json::Value::Object(fields) => serde_json::Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), expand_fields(value, id)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// Applies the fields in `overrides` on top of `base`. Objects, like `disk` or `labels`, are merged
+/// field by field; any other value, including lists and `null`, replaces the one in `base`.
+fn merge_fields(base: &mut serde_json::Value, overrides: serde_json::Value) {
+    match (base, overrides) {
+        (serde_json::Value::Object(base_fields), serde_json::Value::Object(override_fields)) => {
+            for (key, value) in override_fields {
+                match base_fields.get_mut(&key) {
+                    Some(base_value) => merge_fields(base_value, value),
+                    None => {
+                        base_fields.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// Loads an instance config from the given path, applying the workspace's instance defaults and the
+/// profiles the instance extends.
+fn load_instance_config(
+    path: &path::Path,
+    workspace_spec: &WorkspaceConfig,
+) -> Result<InstanceConfig, validate::Problem> {
+    let problem = |field: Option<String>, msg: String| validate::Problem {
+        path: path.to_path_buf(),
+        field,
+        msg,
+    };
+
+    let fields: serde_json::Value = load_from_path(path)?;
+
+    let extends: Vec<String> = match fields.get("extends") {
+        Some(extends) => serde_json::from_value(extends.clone())
+            .map_err(|e| problem(Some(String::from("extends")), e.to_string()))?,
+        None => Vec::new(),
+    };
+
+    // Without anything to apply, the file is loaded on its own, so that errors keep their line and
+    // column.
+    if workspace_spec.instance_defaults.is_empty() && extends.is_empty() {
+        return load_from_path(path);
+    }
+
+    let id = fields
+        .get("id")
+        .and_then(|id| id.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut merged = expand_fields(
+        &serde_json::Value::Object(workspace_spec.instance_defaults.clone()),
+        &id,
+    );
+
+    for name in &extends {
+        let profile = workspace_spec.profiles.get(name).ok_or_else(|| {
+            problem(
+                Some(String::from("extends")),
+                format!("no profile named {} in the workspace config", name),
+            )
+        })?;
+
+        merge_fields(
+            &mut merged,
+            expand_fields(&serde_json::Value::Object(profile.clone()), &id),
+        );
+    }
+
+    merge_fields(&mut merged, fields);
+
+    serde_path_to_error::deserialize(merged).map_err(|e| {
+        let field = error_field(e.path());
+
+        // Fields in the merged config may come from the workspace config, so errors in them have no
+        // position. If the file on its own fails to load at the same field, the error is in the
+        // file, and loading it on its own gives its line and column.
+        match load_from_path::<InstanceConfig>(path) {
+            Err(file_problem) if field.is_some() && file_problem.field == field => file_problem,
+            _ => problem(field, e.inner().to_string()),
+        }
+    })
+}
+
+/// An instance config file, with the config loaded from it or the problem that kept it from
+/// loading.
+pub struct InstanceFile {
+    /// The path of the file.
+    pub path: path::PathBuf,
+    /// The config loaded from the file.
+    pub instance_spec: Result<InstanceConfig, validate::Problem>,
+}
+
+/// Loads all instance configs from the given directory path, in order of their paths, applying the
+/// workspace's instance defaults and profiles. Any file with the extension `json`, `toml`, `yaml`
+/// or `yml` is considered to be an instance config. Every file is loaded, even if some cannot be
+/// parsed. Returns an error if the directory cannot be read.
 pub fn load_instance_configs(
     dir: &path::Path,
-) -> Result<Vec<InstanceConfig>, Box<dyn error::Error>> {
+    workspace_spec: &WorkspaceConfig,
+) -> Result<Vec<InstanceFile>, Box<dyn error::Error>> {
     let mut paths = Vec::new();
 
     for entry_result in fs::read_dir(dir)? {
@@ -90,12 +966,774 @@ pub fn load_instance_configs(
             continue;
         }
 
-        if let Some(ext) = entry_path.extension() {
-            if ext == "json" {
-                paths.push(entry_path);
+        if Format::from_path(&entry_path).is_some() {
+            paths.push(entry_path);
+        }
+    }
+
+    paths.sort();
+
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let instance_spec = load_instance_config(&path, workspace_spec);
+
+            InstanceFile {
+                path,
+                instance_spec,
             }
+        })
+        .collect())
+}
+
+/// Returns the instance configs matched by the given selection. Returns an error if any ID
+/// pattern in the selection matches no instance config, or if no instance configs are selected.
+pub fn select_instances(
+    instance_specs: Vec<InstanceConfig>,
+    selection: &Selection,
+) -> Result<Vec<InstanceConfig>, Box<dyn error::Error>> {
+    for pattern in &selection.only {
+        if !instance_specs.iter().any(|spec| pattern.matches(&spec.id)) {
+            return Err(format!("no instance config found matching {}", pattern).into());
+        }
+    }
+
+    let selected: Vec<InstanceConfig> = instance_specs
+        .into_iter()
+        .filter(|spec| selection.matches(spec))
+        .collect();
+
+    if selected.is_empty() {
+        return Err("no instance configs selected".into());
+    }
+
+    Ok(selected)
+}
+
+/// Configs that pass validation, for tests to build on.
+#[cfg(test)]
+pub mod fixtures {
+    use std::path;
+
+    use super::{InstanceConfig, WorkspaceConfig};
+
+    pub const SSH_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f me@host";
+
+    pub const USER_PASSWORD: &str = "pi:$6$saltsalt$qFmFH.bQmmtXzyBY0s9v7Oicd2z4XSIecDzlB5KiA2/jctKu9YterLp8wwnSq.qc.eoxqOmSuNp2xS0ktL3nh/";
+
+    pub fn workspace_json(path: &path::Path) -> serde_json::Value {
+        serde_json::json!({
+            "path": path,
+            "img_path": path.join("os.img"),
+            "iscsi_target_ip": "10.0.0.2",
+            "nfs_server_ip": "10.0.0.3",
+            "nfs_tftp_dir": "/tftp",
+        })
+    }
+
+    pub fn instance_json(id: &str, mac_addr: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "iscsi_initiator_iqn": format!("iqn.2024-01.test:{}", id),
+            "iscsi_target_iqn": format!("iqn.2024-01.test:target-{}", id),
+            "mac_addr": mac_addr,
+            "user_password": USER_PASSWORD,
+            "root_ssh_key": SSH_KEY,
+        })
+    }
+
+    pub fn workspace_spec(path: &path::Path) -> WorkspaceConfig {
+        serde_json::from_value(workspace_json(path)).unwrap()
+    }
+
+    pub fn instance_spec(id: &str, mac_addr: &str) -> InstanceConfig {
+        serde_json::from_value(instance_json(id, mac_addr)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(args: &[&str]) -> Result<Config, clap::Error> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+        Config::build(&args)
+    }
+
+    #[test]
+    fn builds_legacy_provision_command() {
+        let cfg = build(&["provision", "ws.json", "instances"]).unwrap();
+
+        assert_eq!(cfg.workspace_config_path, path::Path::new("ws.json"));
+        assert_eq!(cfg.instances_config_dir, path::Path::new("instances"));
+        assert_eq!(cfg.command, Command::Provision { reconcile: false });
+    }
+
+    #[test]
+    fn builds_subcommands() {
+        let cases = [
+            (vec!["provision", "list"], Command::List),
+            (
+                vec!["provision", "show-config", "node1"],
+                Command::ShowConfig {
+                    id: String::from("node1"),
+                },
+            ),
+            (
+                vec!["provision", "provision", "--reconcile"],
+                Command::Provision { reconcile: true },
+            ),
+            (
+                vec!["provision", "plan", "deprovision"],
+                Command::Plan {
+                    operation: crate::Operation::Deprovision,
+                },
+            ),
+        ];
+
+        for (args, command) in cases {
+            let cfg = build(&args).unwrap();
+
+            assert_eq!(cfg.command, command, "{:?}", args);
+            assert_eq!(
+                cfg.workspace_config_path,
+                path::Path::new(DEFAULT_WORKSPACE_CONFIG_PATH)
+            );
+        }
+
+        let cfg = build(&["provision", "--workspace", "ws.json", "verify"]).unwrap();
+
+        assert_eq!(cfg.workspace_config_path, path::Path::new("ws.json"));
+        assert_eq!(cfg.command, Command::Verify);
+    }
+
+    #[test]
+    fn returns_help_and_invalid_arguments_as_errors() {
+        let cases = [
+            (
+                vec!["provision", "help", "list"],
+                clap::error::ErrorKind::DisplayHelp,
+            ),
+            (
+                vec!["provision", "list", "--help"],
+                clap::error::ErrorKind::DisplayHelp,
+            ),
+            (
+                vec!["provision", "frobnicate"],
+                clap::error::ErrorKind::InvalidSubcommand,
+            ),
+            (
+                vec!["provision", "list", "--bogus"],
+                clap::error::ErrorKind::UnknownArgument,
+            ),
+        ];
+
+        for (args, kind) in cases {
+            let err = build(&args).err().unwrap();
+
+            assert_eq!(err.kind(), kind, "{:?}", args);
+        }
+    }
+
+    fn specs(disk: serde_json::Value) -> (WorkspaceConfig, InstanceConfig) {
+        let workspace_spec = fixtures::workspace_spec(path::Path::new("/srv/provision"));
+
+        let mut instance_json = fixtures::instance_json("node1", "dc-a6-32-00-00-01");
+
+        instance_json["disk"] = disk;
+
+        let instance_spec = serde_json::from_value(instance_json).unwrap();
+
+        (workspace_spec, instance_spec)
+    }
+
+    fn partitions_error(partitions: serde_json::Value) -> String {
+        let (workspace_spec, instance_spec) =
+            specs(serde_json::json!({ "partitions": partitions }));
+
+        instance_spec
+            .partitions(&workspace_spec)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn defaults_to_single_root_partition() {
+        let (workspace_spec, instance_spec) = specs(serde_json::json!({}));
+
+        assert_eq!(instance_spec.disk.lun, 1);
+        assert_eq!(
+            instance_spec.partitions(&workspace_spec).unwrap(),
+            [PartitionConfig {
+                mount_point: Some(String::from("/")),
+                size: None,
+                filesystem: FilesystemConfig::default(),
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_partition_sizes() {
+        let (workspace_spec, instance_spec) = specs(serde_json::json!({
+            "partitions": [
+                {"mount_point": "/", "size": "512MiB"},
+                {"mount_point": "/var", "size": "2GiB"},
+                {"mount_point": "/srv", "size": "1TiB"},
+            ],
+        }));
+
+        let sizes: Vec<Option<u64>> = instance_spec
+            .partitions(&workspace_spec)
+            .unwrap()
+            .iter()
+            .map(|p| p.size_mib().unwrap())
+            .collect();
+
+        assert_eq!(sizes, [Some(512), Some(2048), Some(1024 * 1024)]);
+
+        assert_eq!(
+            partitions_error(serde_json::json!([{"mount_point": "/", "size": "2GB"}])),
+            "invalid partition size '2GB': expected a whole number of MiB, GiB or TiB"
+        );
+        assert_eq!(
+            partitions_error(serde_json::json!([{"mount_point": "/", "size": "0GiB"}])),
+            "invalid partition size '0GiB': must not be zero"
+        );
+    }
+
+    #[test]
+    fn resolves_iscsi_portals() {
+        let (mut workspace_spec, mut instance_spec) = specs(serde_json::json!({}));
+
+        assert_eq!(
+            instance_spec
+                .iscsi_portal(&workspace_spec)
+                .unwrap()
+                .address(),
+            "10.0.0.2:3260"
+        );
+
+        workspace_spec.iscsi_portals = serde_json::from_value(serde_json::json!([
+            {"name": "nas1", "host": "10.0.0.4", "port": 3261},
+            {"name": "nas2", "host": "fd00::2"},
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            workspace_spec.iscsi_portals().unwrap_err().to_string(),
+            "only one of iscsi_target_ip and iscsi_portals may be set"
+        );
+
+        workspace_spec.iscsi_target_ip = None;
+
+        assert_eq!(
+            instance_spec
+                .iscsi_portal(&workspace_spec)
+                .unwrap()
+                .address(),
+            "10.0.0.4:3261"
+        );
+
+        instance_spec.iscsi_portal = Some(String::from("nas2"));
+
+        assert_eq!(
+            instance_spec
+                .iscsi_portal(&workspace_spec)
+                .unwrap()
+                .address(),
+            "[fd00::2]:3260"
+        );
+
+        instance_spec.iscsi_portals = vec![String::from("nas2"), String::from("nas1")];
+
+        assert_eq!(
+            instance_spec
+                .iscsi_portals(&workspace_spec)
+                .unwrap_err()
+                .to_string(),
+            "only one of iscsi_portal and iscsi_portals may be set"
+        );
+
+        instance_spec.iscsi_portal = None;
+
+        let addresses: Vec<String> = instance_spec
+            .iscsi_portals(&workspace_spec)
+            .unwrap()
+            .iter()
+            .map(|p| p.address())
+            .collect();
+
+        assert_eq!(addresses, ["[fd00::2]:3260", "10.0.0.4:3261"]);
+        assert!(instance_spec.multipath());
+
+        instance_spec.iscsi_portals = vec![String::from("nas1"), String::from("nas1")];
+
+        assert_eq!(
+            instance_spec
+                .iscsi_portals(&workspace_spec)
+                .unwrap_err()
+                .to_string(),
+            "iSCSI portal nas1 is listed more than once"
+        );
+
+        instance_spec.iscsi_portals.clear();
+        instance_spec.iscsi_portal = Some(String::from("nas3"));
+
+        assert_eq!(
+            instance_spec
+                .iscsi_portal(&workspace_spec)
+                .unwrap_err()
+                .to_string(),
+            "no iSCSI portal named nas3 found"
+        );
+
+        workspace_spec.iscsi_portals[1].name = Some(String::from("nas1"));
+
+        assert_eq!(
+            workspace_spec.iscsi_portals().unwrap_err().to_string(),
+            "more than one iSCSI portal is named nas1"
+        );
+
+        workspace_spec.iscsi_portals.clear();
+
+        assert_eq!(
+            workspace_spec.iscsi_portals().unwrap_err().to_string(),
+            "no iSCSI portals configured"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_chap_credentials() {
+        let (_, mut instance_spec) = specs(serde_json::json!({}));
+
+        let credentials = |username: &str, password: &str| ChapCredentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+
+        instance_spec.validate_chap().unwrap();
+
+        instance_spec.mutual_chap = Some(credentials("nas", "target-secret"));
+
+        assert_eq!(
+            instance_spec.validate_chap().unwrap_err().to_string(),
+            "mutual_chap is set but chap is not"
+        );
+
+        instance_spec.chap = Some(credentials("node1", "initiator secret"));
+
+        assert_eq!(
+            instance_spec.validate_chap().unwrap_err().to_string(),
+            "chap password must not contain whitespace or quotes"
+        );
+
+        instance_spec.chap = Some(credentials("node1", "initiator-secret"));
+        instance_spec.mutual_chap = Some(credentials("", "target-secret"));
+
+        assert_eq!(
+            instance_spec.validate_chap().unwrap_err().to_string(),
+            "mutual_chap username must not be empty"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_partitions() {
+        assert_eq!(
+            partitions_error(serde_json::json!([{"mount_point": "/swap", "type": "swap"}])),
+            "partition 1 is swap but has a mount point"
+        );
+        assert_eq!(
+            partitions_error(serde_json::json!([{"mount_point": "var"}])),
+            "partition 1 has mount point 'var', which is not an absolute path"
+        );
+
+        for mount_point in [
+            "/../../etc",
+            "/var/../etc",
+            "/./var",
+            "/var/",
+            "//var",
+            "/my data",
+        ] {
+            assert_eq!(
+                partitions_error(serde_json::json!([
+                    {"mount_point": "/", "size": "1GiB"},
+                    {"mount_point": mount_point},
+                ])),
+                format!(
+                    "partition 2 has mount point '{}', which must not have empty, '.' or '..' components or whitespace",
+                    mount_point
+                )
+            );
+        }
+        assert_eq!(
+            partitions_error(serde_json::json!([
+                {"mount_point": "/", "size": "1GiB"},
+                {"mount_point": "/"},
+            ])),
+            "more than one partition is mounted at /"
+        );
+        assert_eq!(
+            partitions_error(serde_json::json!([{"type": "xfs"}])),
+            "partition 1 has no mount point"
+        );
+        assert_eq!(
+            partitions_error(serde_json::json!([
+                {"mount_point": "/"},
+                {"mount_point": "/var", "size": "1GiB"},
+            ])),
+            "partition 1 has no size, but only the last partition may fill the rest of the LUN"
+        );
+        assert_eq!(
+            partitions_error(serde_json::json!([{"mount_point": "/var"}])),
+            "no partition is mounted at /"
+        );
+
+        let (workspace_spec, mut instance_spec) = specs(serde_json::json!({}));
+
+        instance_spec.rootfs = Some(FilesystemConfig {
+            fstype: FilesystemType::Swap,
+            ..FilesystemConfig::default()
+        });
+
+        assert_eq!(
+            instance_spec
+                .partitions(&workspace_spec)
+                .unwrap_err()
+                .to_string(),
+            "the root filesystem cannot be swap"
+        );
+    }
+
+    #[test]
+    fn loads_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let files = [
+            (
+                "node1.json",
+                format!(
+                    r#"{{
+  "id": "node1",
+  "iscsi_initiator_iqn": "iqn.2024-01.test:node1",
+  "iscsi_target_iqn": "iqn.2024-01.test:target-node1",
+  "mac_addr": "dc-a6-32-00-00-01",
+  "user_password": "{}",
+  "root_ssh_key": "{}",
+  "disk": {{"lun": 2, "partitions": [{{"mount_point": "/", "type": "xfs"}}]}}
+}}"#,
+                    fixtures::USER_PASSWORD,
+                    fixtures::SSH_KEY
+                ),
+            ),
+            (
+                "node1.toml",
+                format!(
+                    r#"id = "node1"
+iscsi_initiator_iqn = "iqn.2024-01.test:node1"
+iscsi_target_iqn = "iqn.2024-01.test:target-node1"
+mac_addr = "dc-a6-32-00-00-01"
+user_password = '{}'
+root_ssh_key = "{}"
+
+[disk]
+lun = 2
+
+[[disk.partitions]]
+mount_point = "/"
+type = "xfs"
+"#,
+                    fixtures::USER_PASSWORD,
+                    fixtures::SSH_KEY
+                ),
+            ),
+            (
+                "node1.yaml",
+                format!(
+                    r#"id: node1
+iscsi_initiator_iqn: iqn.2024-01.test:node1
+iscsi_target_iqn: iqn.2024-01.test:target-node1
+mac_addr: dc-a6-32-00-00-01
+user_password: '{}'
+root_ssh_key: {}
+disk:
+  lun: 2
+  partitions:
+    - mount_point: /
+      type: xfs
+"#,
+                    fixtures::USER_PASSWORD,
+                    fixtures::SSH_KEY
+                ),
+            ),
+        ];
+
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+
+        // Files with other extensions are not instance configs.
+        fs::write(dir.path().join("README.md"), "# Instances").unwrap();
+
+        let (workspace_spec, _) = specs(serde_json::json!({}));
+
+        let instance_files = load_instance_configs(dir.path(), &workspace_spec).unwrap();
+
+        assert_eq!(instance_files.len(), 3);
+
+        for instance_file in instance_files {
+            let name = instance_file.path.display().to_string();
+            let instance_spec = instance_file.instance_spec.unwrap();
+
+            assert_eq!(instance_spec.id, "node1", "{}", name);
+            assert_eq!(
+                instance_spec.user_password,
+                fixtures::USER_PASSWORD,
+                "{}",
+                name
+            );
+            assert_eq!(instance_spec.disk.lun, 2, "{}", name);
+            assert_eq!(
+                instance_spec.disk.partitions[0].filesystem.fstype,
+                FilesystemType::Xfs,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn applies_instance_defaults_and_profiles() {
+        let mut workspace_json = fixtures::workspace_json(path::Path::new("/srv/provision"));
+
+        workspace_json["instance_defaults"] = serde_json::json!({
+            "iscsi_initiator_iqn": "iqn.2024-01.test:{id}",
+            "iscsi_target_iqn": "iqn.2024-01.test:target-{id}",
+            "user_password": fixtures::USER_PASSWORD,
+            "root_ssh_key": fixtures::SSH_KEY,
+            "chap": {"username": "{id}", "password": "secret"},
+            "labels": {"site": "home"},
+        });
+        workspace_json["profiles"] = serde_json::json!({
+            "worker": {
+                "disk": {"lun": 2, "partitions": [{"mount_point": "/", "type": "xfs"}]},
+                "labels": {"role": "worker"},
+            },
+            "big": {"disk": {"lun": 3}},
+        });
+
+        let workspace_spec: WorkspaceConfig = serde_json::from_value(workspace_json).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let files = [
+            (
+                "node1.toml",
+                r#"
+id = "node1"
+mac_addr = "dc-a6-32-00-00-01"
+extends = ["worker", "big"]
+chap = { password = "other" }
+
+[labels]
+role = "storage"
+"#,
+            ),
+            (
+                "node2.json",
+                r#"{"id": "node2", "mac_addr": "dc-a6-32-00-00-02", "chap": null}"#,
+            ),
+            (
+                "node3.json",
+                r#"{"id": "node3", "mac_addr": "dc-a6-32-00-00-03", "extends": ["gpu"]}"#,
+            ),
+        ];
+
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+
+        let mut instance_files = load_instance_configs(dir.path(), &workspace_spec)
+            .unwrap()
+            .into_iter();
+
+        let node1 = instance_files.next().unwrap().instance_spec.unwrap();
+
+        assert_eq!(node1.extends, ["worker", "big"]);
+        assert_eq!(node1.iscsi_initiator_iqn, "iqn.2024-01.test:node1");
+        assert_eq!(node1.iscsi_target_iqn, "iqn.2024-01.test:target-node1");
+        assert_eq!(node1.user_password, fixtures::USER_PASSWORD);
+        assert_eq!(
+            node1.chap,
+            Some(ChapCredentials {
+                username: String::from("node1"),
+                password: String::from("other"),
+            })
+        );
+        // Later profiles and the instance's own fields override earlier ones field by field.
+        assert_eq!(node1.disk.lun, 3);
+        assert_eq!(
+            node1.disk.partitions[0].filesystem.fstype,
+            FilesystemType::Xfs
+        );
+        assert_eq!(
+            node1.labels,
+            collections::BTreeMap::from([
+                (String::from("role"), String::from("storage")),
+                (String::from("site"), String::from("home")),
+            ])
+        );
+
+        // Printed configs hide CHAP passwords.
+        let printed = serde_json::to_value(&node1).unwrap();
+
+        assert_eq!(
+            printed["chap"],
+            serde_json::json!({"username": "node1", "password": "REDACTED"})
+        );
+
+        let node2 = instance_files.next().unwrap().instance_spec.unwrap();
+
+        assert_eq!(node2.iscsi_initiator_iqn, "iqn.2024-01.test:node2");
+        assert_eq!(node2.chap, None);
+        assert_eq!(node2.disk.lun, 1);
+
+        let problem = match instance_files.next().unwrap().instance_spec {
+            Ok(_) => panic!("config extending a missing profile loaded"),
+            Err(problem) => problem,
+        };
+
+        assert_eq!(problem.field.as_deref(), Some("extends"));
+        assert_eq!(problem.msg, "no profile named gpu in the workspace config");
+    }
+
+    #[test]
+    fn reports_positions_of_errors_in_files_with_defaults() {
+        let mut workspace_json = fixtures::workspace_json(path::Path::new("/srv/provision"));
+
+        workspace_json["instance_defaults"] = serde_json::json!({
+            "iscsi_initiator_iqn": "iqn.2024-01.test:{id}",
+            "iscsi_target_iqn": "iqn.2024-01.test:target-{id}",
+            "user_password": fixtures::USER_PASSWORD,
+            "root_ssh_key": fixtures::SSH_KEY,
+        });
+        workspace_json["profiles"] = serde_json::json!({"bad": {"disk": {"lun": "two"}}});
+
+        let workspace_spec: WorkspaceConfig = serde_json::from_value(workspace_json).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let files = [
+            (
+                "node1.json",
+                "{\n  \"id\": \"node1\",\n  \"disk\": {\"lun\": \"one\"}\n}",
+            ),
+            ("node2.toml", "id = \"node2\"\n\n[disk]\nlun = \"one\"\n"),
+            ("node3.yaml", "id: node3\ndisk:\n  lun: one\n"),
+            ("node4.json", r#"{"id": "node4", "extends": ["bad"]}"#),
+        ];
+
+        for (name, contents) in files {
+            fs::write(dir.path().join(name), contents).unwrap();
         }
+
+        let problems: Vec<(Option<String>, String)> =
+            load_instance_configs(dir.path(), &workspace_spec)
+                .unwrap()
+                .into_iter()
+                .map(|instance_file| match instance_file.instance_spec {
+                    Ok(_) => panic!("{} loaded", instance_file.path.display()),
+                    Err(problem) => (problem.field, problem.msg),
+                })
+                .collect();
+
+        let lun = Some(String::from("disk.lun"));
+
+        // Errors in a profile have no position in the instance file.
+        assert_eq!(
+            problems,
+            [
+                (
+                    lun.clone(),
+                    String::from("invalid type: string \"one\", expected u32 at line 3 column 23")
+                ),
+                (
+                    lun.clone(),
+                    String::from("invalid type: string \"one\", expected u32 at line 4 column 7")
+                ),
+                (
+                    lun.clone(),
+                    String::from("invalid type: string \"one\", expected u32 at line 3 column 8")
+                ),
+                (
+                    lun,
+                    String::from("invalid type: string \"two\", expected u32")
+                ),
+            ]
+        );
     }
 
-    paths.iter().map(|f| load_from_path(f)).collect()
+    #[test]
+    fn reports_parse_errors_with_positions() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let files = [
+            (
+                "bad.json",
+                "{\n  \"id\": \"node1\",\n  \"disk\": {\"lun\": \"one\"}\n}",
+            ),
+            ("bad.toml", "id = \"node1\"\n\n[disk]\nlun = \"one\"\n"),
+            ("bad.yaml", "id: node1\ndisk:\n  lun: one\n"),
+            ("bad.yml", "id: node1\nmac_addr: \"dc-a6\n"),
+            ("bad.ini", "id = node1\n"),
+        ];
+
+        let problems: Vec<(Option<String>, String)> = files
+            .iter()
+            .map(|(name, contents)| {
+                let path = dir.path().join(name);
+
+                fs::write(&path, contents).unwrap();
+
+                match load_from_path::<InstanceConfig>(&path) {
+                    Ok(_) => panic!("{} loaded", name),
+                    Err(problem) => (problem.field, problem.msg),
+                }
+            })
+            .collect();
+
+        let lun = Some(String::from("disk.lun"));
+
+        assert_eq!(
+            problems,
+            [
+                (
+                    lun.clone(),
+                    String::from("invalid type: string \"one\", expected u32 at line 3 column 23")
+                ),
+                (
+                    lun.clone(),
+                    String::from("invalid type: string \"one\", expected u32 at line 4 column 7")
+                ),
+                (
+                    lun,
+                    String::from("invalid type: string \"one\", expected u32 at line 3 column 8")
+                ),
+                (
+                    Some(String::from("mac_addr")),
+                    String::from(
+                        "found unexpected end of stream at line 3 column 1, while scanning a quoted scalar at line 2 column 11"
+                    )
+                ),
+                (
+                    None,
+                    String::from(
+                        "unknown config file extension, expected .json, .toml, .yaml or .yml"
+                    )
+                ),
+            ]
+        );
+    }
 }
@@ -0,0 +1,538 @@
+use std::collections;
+use std::error;
+use std::ffi;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path;
+use std::sync::atomic;
+
+/// The amount of data copied so far.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Progress {
+    /// The number of entries copied, including directories, links and special files.
+    pub files: u64,
+    /// The number of bytes of regular file data copied.
+    pub bytes: u64,
+}
+
+/// Options that control which metadata is copied.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Whether to copy the owner and group of each entry. Copying ownership generally requires
+    /// root, and is pointless when copying from filesystems like FAT that don't store it.
+    pub preserve_ownership: bool,
+}
+
+struct Copier<'a, F: FnMut(&Progress)> {
+    options: Options,
+    /// Set to stop the copy before the next entry.
+    cancel: &'a atomic::AtomicBool,
+    progress: Progress,
+    progress_fn: F,
+    /// Maps the device and inode of source files with multiple links to the first destination
+    /// path they were copied to, so later links can be recreated as hard links.
+    links: collections::HashMap<(u64, u64), path::PathBuf>,
+}
+
+fn c_path(path: &path::Path) -> io::Result<ffi::CString> {
+    ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn check_size(ret: libc::ssize_t) -> io::Result<usize> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret as usize)
+}
+
+/// Returns the extended attributes of a path without following symlinks.
+fn xattrs(path: &path::Path) -> io::Result<Vec<(ffi::CString, Vec<u8>)>> {
+    let c = c_path(path)?;
+
+    let len = match check_size(unsafe { libc::llistxattr(c.as_ptr(), std::ptr::null_mut(), 0) }) {
+        Ok(len) => len,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut names = vec![0u8; len];
+
+    let len = check_size(unsafe {
+        libc::llistxattr(c.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, len)
+    })?;
+
+    names.truncate(len);
+
+    let mut result = Vec::new();
+
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let name = ffi::CString::new(name).expect("xattr names are NUL-separated");
+
+        let len = check_size(unsafe {
+            libc::lgetxattr(c.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0)
+        })?;
+
+        let mut value = vec![0u8; len];
+
+        let len = check_size(unsafe {
+            libc::lgetxattr(
+                c.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                len,
+            )
+        })?;
+
+        value.truncate(len);
+
+        result.push((name, value));
+    }
+
+    Ok(result)
+}
+
+fn set_xattr(path: &path::Path, name: &ffi::CStr, value: &[u8]) -> io::Result<()> {
+    let c = c_path(path)?;
+
+    check(unsafe {
+        libc::lsetxattr(
+            c.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    })
+}
+
+fn set_times(path: &path::Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let c = c_path(path)?;
+
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime() as libc::time_t,
+            tv_nsec: metadata.atime_nsec() as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime() as libc::time_t,
+            tv_nsec: metadata.mtime_nsec() as libc::c_long,
+        },
+    ];
+
+    check(unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })
+}
+
+fn make_node(path: &path::Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let c = c_path(path)?;
+
+    check(unsafe {
+        libc::mknod(
+            c.as_ptr(),
+            metadata.mode() as libc::mode_t,
+            metadata.rdev() as libc::dev_t,
+        )
+    })
+}
+
+/// Removes whatever is at `dst` unless it is a directory and `src_is_dir` is set, so that
+/// directories are merged and everything else is replaced.
+fn remove_existing(dst: &path::Path, src_is_dir: bool) -> io::Result<()> {
+    match fs::symlink_metadata(dst) {
+        Ok(m) if m.is_dir() && src_is_dir => Ok(()),
+        Ok(m) if m.is_dir() => fs::remove_dir_all(dst),
+        Ok(_) => fs::remove_file(dst),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn with_path(src: &path::Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", src.display(), e))
+}
+
+impl<F: FnMut(&Progress)> Copier<'_, F> {
+    /// Creates the entry at `dst` without its children or metadata. Returns the source metadata,
+    /// or `None` if the entry was a hard link to an entry that has already been copied.
+    fn create_entry(
+        &mut self,
+        src: &path::Path,
+        dst: &path::Path,
+    ) -> io::Result<Option<fs::Metadata>> {
+        let metadata = fs::symlink_metadata(src)?;
+        let file_type = metadata.file_type();
+
+        remove_existing(dst, file_type.is_dir())?;
+
+        if !file_type.is_dir() && metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+
+            if let Some(first) = self.links.get(&key) {
+                fs::hard_link(first, dst)?;
+
+                return Ok(None);
+            }
+
+            self.links.insert(key, dst.to_path_buf());
+        }
+
+        if file_type.is_dir() {
+            if !dst.exists() {
+                fs::create_dir(dst)?;
+            }
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(src)?, dst)?;
+        } else if file_type.is_file() {
+            self.progress.bytes += fs::copy(src, dst)?;
+        } else {
+            // Block and character devices, FIFOs and sockets.
+            make_node(dst, &metadata)?;
+        }
+
+        Ok(Some(metadata))
+    }
+
+    /// Copies metadata in an order where each change can't undo an earlier one: changing the owner
+    /// clears setuid bits and file capabilities, and writing to a directory updates its times.
+    fn copy_metadata(
+        &self,
+        src: &path::Path,
+        dst: &path::Path,
+        metadata: &fs::Metadata,
+    ) -> io::Result<()> {
+        if self.options.preserve_ownership {
+            std::os::unix::fs::lchown(dst, Some(metadata.uid()), Some(metadata.gid()))?;
+        }
+
+        for (name, value) in xattrs(src)? {
+            set_xattr(dst, &name, &value)?;
+        }
+
+        if !metadata.file_type().is_symlink() {
+            fs::set_permissions(dst, fs::Permissions::from_mode(metadata.mode() & 0o7777))?;
+        }
+
+        set_times(dst, metadata)
+    }
+
+    fn copy(&mut self, src: &path::Path, dst: &path::Path) -> io::Result<()> {
+        if self.cancel.load(atomic::Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "copy cancelled"));
+        }
+
+        let metadata = self.create_entry(src, dst).map_err(|e| with_path(src, e))?;
+
+        self.progress.files += 1;
+        (self.progress_fn)(&self.progress);
+
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => return Ok(()),
+        };
+
+        if metadata.is_dir() {
+            let mut entries: Vec<ffi::OsString> = fs::read_dir(src)
+                .and_then(|d| d.map(|e| e.map(|e| e.file_name())).collect())
+                .map_err(|e| with_path(src, e))?;
+
+            entries.sort();
+
+            for name in entries {
+                self.copy(&src.join(&name), &dst.join(&name))?;
+            }
+        }
+
+        self.copy_metadata(src, dst, &metadata)
+            .map_err(|e| with_path(src, e))
+    }
+}
+
+/// Copies the contents of the `src` directory into the `dst` directory, preserving permissions,
+/// symlinks, hard links, special files, extended attributes (including file capabilities and ACLs),
+/// timestamps and, if enabled, ownership. Existing directories in `dst` are merged and other
+/// existing entries are replaced. `progress_fn` is called after each entry is copied. Setting
+/// `cancel` stops the copy before the next entry, leaving `dst` partly copied.
+pub fn copy_tree(
+    src: &path::Path,
+    dst: &path::Path,
+    options: Options,
+    cancel: &atomic::AtomicBool,
+    progress_fn: impl FnMut(&Progress),
+) -> Result<Progress, Box<dyn error::Error>> {
+    if !fs::metadata(dst)?.is_dir() {
+        return Err(format!("{} is not a directory", dst.display()).into());
+    }
+
+    let mut copier = Copier {
+        options,
+        cancel,
+        progress: Progress::default(),
+        progress_fn,
+        links: collections::HashMap::new(),
+    };
+
+    copier.copy(src, dst)?;
+
+    Ok(copier.progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::FileTypeExt;
+
+    fn is_root() -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    fn options() -> Options {
+        Options {
+            preserve_ownership: is_root(),
+        }
+    }
+
+    fn assert_same_metadata(src: &path::Path, dst: &path::Path) {
+        let s = fs::symlink_metadata(src).unwrap();
+        let d = fs::symlink_metadata(dst).unwrap();
+
+        assert_eq!(s.mode(), d.mode(), "mode of {}", dst.display());
+        assert_eq!(s.uid(), d.uid(), "uid of {}", dst.display());
+        assert_eq!(s.gid(), d.gid(), "gid of {}", dst.display());
+        assert_eq!(s.mtime(), d.mtime(), "mtime of {}", dst.display());
+        assert_eq!(s.mtime_nsec(), d.mtime_nsec(), "mtime of {}", dst.display());
+        assert_eq!(xattrs(src).unwrap(), xattrs(dst).unwrap());
+    }
+
+    #[test]
+    fn copies_tree_exactly() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path();
+        let dst = dst_dir.path();
+
+        fs::create_dir_all(src.join("root/.ssh")).unwrap();
+        fs::write(src.join("root/.ssh/authorized_keys"), "ssh-ed25519 AAAA").unwrap();
+        fs::set_permissions(src.join("root/.ssh"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(
+            src.join("root/.ssh/authorized_keys"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+
+        fs::create_dir(src.join("bin")).unwrap();
+        fs::write(src.join("bin/su"), vec![7u8; 100_000]).unwrap();
+        fs::set_permissions(src.join("bin/su"), fs::Permissions::from_mode(0o4755)).unwrap();
+        fs::hard_link(src.join("bin/su"), src.join("bin/su-link")).unwrap();
+
+        std::os::unix::fs::symlink("su", src.join("bin/relative")).unwrap();
+        std::os::unix::fs::symlink("/does/not/exist", src.join("bin/dangling")).unwrap();
+
+        let fifo = c_path(&src.join("fifo")).unwrap();
+        check(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }).unwrap();
+
+        // Not every filesystem used for temporary directories supports user xattrs.
+        let has_xattrs = set_xattr(&src.join("bin/su"), c"user.test", b"value").is_ok();
+
+        if is_root() {
+            std::os::unix::fs::lchown(src.join("root/.ssh"), Some(1234), Some(5678)).unwrap();
+            std::os::unix::fs::lchown(src.join("bin/dangling"), Some(42), Some(43)).unwrap();
+
+            let null = c_path(&src.join("null")).unwrap();
+            check(unsafe {
+                libc::mknod(null.as_ptr(), libc::S_IFCHR | 0o666, libc::makedev(1, 3))
+            })
+            .unwrap();
+        }
+
+        let old = libc::timespec {
+            tv_sec: 1_000_000_000,
+            tv_nsec: 123_456_789,
+        };
+        let c = c_path(&src.join("bin/dangling")).unwrap();
+        check(unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                c.as_ptr(),
+                [old, old].as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        })
+        .unwrap();
+
+        // Directories are merged with anything already at the destination.
+        fs::create_dir(dst.join("lost+found")).unwrap();
+
+        let mut calls = 0;
+
+        let progress = copy_tree(src, dst, options(), &atomic::AtomicBool::new(false), |_| {
+            calls += 1
+        })
+        .unwrap();
+
+        assert_eq!(progress.bytes, 100_000 + 16);
+        assert_eq!(progress.files, calls);
+
+        assert!(dst.join("lost+found").is_dir());
+
+        for entry in [
+            "root",
+            "root/.ssh",
+            "root/.ssh/authorized_keys",
+            "bin",
+            "bin/su",
+            "bin/su-link",
+            "bin/relative",
+            "bin/dangling",
+            "fifo",
+        ] {
+            assert_same_metadata(&src.join(entry), &dst.join(entry));
+        }
+
+        assert_eq!(
+            fs::read(dst.join("root/.ssh/authorized_keys")).unwrap(),
+            b"ssh-ed25519 AAAA"
+        );
+
+        assert_eq!(
+            fs::symlink_metadata(dst.join("bin/su")).unwrap().ino(),
+            fs::symlink_metadata(dst.join("bin/su-link")).unwrap().ino()
+        );
+
+        assert_eq!(
+            fs::read_link(dst.join("bin/relative")).unwrap(),
+            path::Path::new("su")
+        );
+        assert_eq!(
+            fs::read_link(dst.join("bin/dangling")).unwrap(),
+            path::Path::new("/does/not/exist")
+        );
+
+        assert!(
+            fs::symlink_metadata(dst.join("fifo"))
+                .unwrap()
+                .file_type()
+                .is_fifo()
+        );
+
+        if has_xattrs {
+            assert_eq!(
+                xattrs(&dst.join("bin/su")).unwrap(),
+                vec![(c"user.test".to_owned(), b"value".to_vec())]
+            );
+        }
+
+        if is_root() {
+            assert_same_metadata(&src.join("null"), &dst.join("null"));
+            assert_eq!(
+                fs::symlink_metadata(dst.join("null")).unwrap().rdev(),
+                libc::makedev(1, 3)
+            );
+        }
+    }
+
+    #[test]
+    fn preserves_setuid_and_capabilities_after_ownership_change() {
+        if !is_root() {
+            return;
+        }
+
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path();
+        let dst = dst_dir.path();
+
+        fs::write(src.join("passwd"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::lchown(src.join("passwd"), Some(0), Some(42)).unwrap();
+        fs::set_permissions(src.join("passwd"), fs::Permissions::from_mode(0o6755)).unwrap();
+
+        // A revision 2 capability set granting cap_net_raw (bit 13), as used by ping.
+        let mut cap = Vec::new();
+        cap.extend_from_slice(&0x0200_0001u32.to_le_bytes());
+        cap.extend_from_slice(&(1u32 << 13).to_le_bytes());
+        cap.extend_from_slice(&[0; 12]);
+
+        fs::write(src.join("ping"), "#!/bin/sh\n").unwrap();
+        let has_caps = set_xattr(&src.join("ping"), c"security.capability", &cap).is_ok();
+
+        copy_tree(src, dst, options(), &atomic::AtomicBool::new(false), |_| {}).unwrap();
+
+        assert_same_metadata(&src.join("passwd"), &dst.join("passwd"));
+
+        if has_caps {
+            assert_same_metadata(&src.join("ping"), &dst.join("ping"));
+            assert!(
+                xattrs(&dst.join("ping"))
+                    .unwrap()
+                    .contains(&(c"security.capability".to_owned(), cap))
+            );
+        }
+    }
+
+    #[test]
+    fn replaces_existing_entries() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path();
+        let dst = dst_dir.path();
+
+        fs::write(src.join("config"), "new").unwrap();
+        std::os::unix::fs::symlink("target", src.join("link")).unwrap();
+
+        fs::create_dir(dst.join("config")).unwrap();
+        fs::write(dst.join("config/stale"), "old").unwrap();
+        fs::write(dst.join("link"), "old").unwrap();
+
+        copy_tree(src, dst, options(), &atomic::AtomicBool::new(false), |_| {}).unwrap();
+
+        assert_eq!(fs::read(dst.join("config")).unwrap(), b"new");
+        assert_eq!(
+            fs::read_link(dst.join("link")).unwrap(),
+            path::Path::new("target")
+        );
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let src_dir = tempfile::tempdir().unwrap();
+        let dst_dir = tempfile::tempdir().unwrap();
+        let src = src_dir.path();
+        let dst = dst_dir.path();
+
+        for name in ["a", "b", "c"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+
+        let cancel = atomic::AtomicBool::new(false);
+
+        let err = copy_tree(src, dst, options(), &cancel, |p| {
+            if p.files == 2 {
+                cancel.store(true, atomic::Ordering::Relaxed);
+            }
+        })
+        .unwrap_err();
+
+        assert!(err.to_string().contains("copy cancelled"), "{}", err);
+        assert!(dst.join("a").exists());
+        assert!(!dst.join("b").exists());
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path;
+use std::process;
+use std::sync;
+use tokio::process as t_process;
+
+use async_trait::async_trait;
+use sys_mount::Unmount;
+
+/// Renders a command line, quoting arguments the way a shell would need them quoted.
+pub fn command_line(program: &str, args: &[String]) -> String {
+    let mut line = String::from(program);
+
+    for arg in args {
+        let needs_quotes = arg.is_empty()
+            || arg
+                .chars()
+                .any(|c| c.is_whitespace() || "'\"$\\;&|<>()*?!`".contains(c));
+
+        if needs_quotes {
+            line.push_str(&format!(" '{}'", arg.replace('\'', "'\\''")));
+        } else {
+            line.push(' ');
+            line.push_str(arg);
+        }
+    }
+
+    line
+}
+
+/// A filesystem to mount.
+#[derive(Clone, Debug, Default)]
+pub struct Mount {
+    /// The device, image or remote export to mount.
+    pub source: String,
+    /// The directory to mount it at.
+    pub target: path::PathBuf,
+    /// The filesystem type. Detected automatically if unset.
+    pub fstype: Option<String>,
+    /// Mount options as written in fstab, e.g. `noatime,compress=zstd`.
+    pub data: Option<String>,
+    /// Whether to mount read-only.
+    pub read_only: bool,
+    /// If set, the source is an image mounted through a loop device starting at this offset.
+    pub loop_offset: Option<u64>,
+}
+
+impl fmt::Display for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mount")?;
+
+        if let Some(fstype) = &self.fstype {
+            write!(f, " -t {}", fstype)?;
+        }
+
+        let mut options = Vec::new();
+
+        if self.read_only {
+            options.push(String::from("ro"));
+        }
+
+        if let Some(offset) = self.loop_offset {
+            options.push(format!("loop,offset={}", offset));
+        }
+
+        if let Some(data) = &self.data {
+            options.push(data.clone());
+        }
+
+        if !options.is_empty() {
+            write!(f, " -o {}", options.join(","))?;
+        }
+
+        write!(f, " {} {}", self.source, self.target.display())
+    }
+}
+
+/// Performs the system operations that steps need: running commands, mounting and unmounting
+/// filesystems, and checking for devices. Steps go through an executor rather than the system
+/// directly so that they can be tested without root or real hardware.
+#[async_trait]
+pub trait Executor: Send + Sync {
+    /// Runs a command, returning its stdout or an error if it did not exit successfully.
+    async fn output(&self, program: &str, args: &[String])
+    -> Result<String, Box<dyn error::Error>>;
+
+    /// Mounts a filesystem. The mount persists until `unmount` is called with its target.
+    fn mount(&self, mount: &Mount) -> Result<(), Box<dyn error::Error>>;
+
+    /// Lazily unmounts the filesystem mounted at the given target.
+    fn unmount(&self, target: &path::Path) -> Result<(), Box<dyn error::Error>>;
+
+    /// Returns whether a block device exists at the given path. Returns an error if something
+    /// other than a block device exists there.
+    fn block_device_exists(&self, path: &str) -> Result<bool, Box<dyn error::Error>>;
+}
+
+/// CommandError represents a command that did not exit successfully.
+#[derive(Clone, thiserror::Error, Debug, PartialEq)]
+pub struct CommandError {
+    /// The command line that was run.
+    pub command: String,
+    /// The command's exit code, or `None` if it was killed by a signal.
+    pub code: Option<i32>,
+    /// What the command wrote to stderr, trimmed.
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{} exited with code {}", self.command, code)?,
+            None => write!(f, "{} was killed by a signal", self.command)?,
+        }
+
+        if !self.stderr.is_empty() {
+            write!(f, ": {}", self.stderr)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn output_or_err(
+    program: &str,
+    args: &[String],
+    output: process::Output,
+) -> Result<String, Box<dyn error::Error>> {
+    if !output.status.success() {
+        return Err(CommandError {
+            command: command_line(program, args),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
+    }
+
+    let res = String::from_utf8(output.stdout)?;
+
+    Ok(res)
+}
+
+/// Mount options that mount(2) takes as flags rather than as filesystem data.
+const FLAG_MOUNT_OPTIONS: [(&str, sys_mount::MountFlags); 10] = [
+    ("ro", sys_mount::MountFlags::RDONLY),
+    ("noatime", sys_mount::MountFlags::NOATIME),
+    ("nodiratime", sys_mount::MountFlags::NODIRATIME),
+    ("relatime", sys_mount::MountFlags::RELATIME),
+    ("strictatime", sys_mount::MountFlags::STRICTATIME),
+    ("nodev", sys_mount::MountFlags::NODEV),
+    ("noexec", sys_mount::MountFlags::NOEXEC),
+    ("nosuid", sys_mount::MountFlags::NOSUID),
+    ("sync", sys_mount::MountFlags::SYNCHRONOUS),
+    ("dirsync", sys_mount::MountFlags::DIRSYNC),
+];
+
+/// Mount options that only mean something to userspace, or that name the defaults, and are not
+/// passed to mount(2).
+const IGNORED_MOUNT_OPTIONS: [&str; 13] = [
+    "defaults", "rw", "auto", "noauto", "nofail", "_netdev", "user", "nouser", "users", "exec",
+    "suid", "dev", "async",
+];
+
+/// Splits mount options as written in fstab into the flags and the filesystem-specific data that
+/// mount(2) takes, the way `mount` does.
+fn split_mount_options(options: &str) -> (sys_mount::MountFlags, String) {
+    let mut flags = sys_mount::MountFlags::empty();
+    let mut data = Vec::new();
+
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        if let Some((_, flag)) = FLAG_MOUNT_OPTIONS.iter().find(|(name, _)| *name == option) {
+            flags |= *flag;
+        } else if !IGNORED_MOUNT_OPTIONS.contains(&option) && !option.starts_with("x-") {
+            data.push(option);
+        }
+    }
+
+    (flags, data.join(","))
+}
+
+/// Performs operations on the real system. Mounting and unmounting require root.
+#[derive(Default)]
+pub struct SystemExecutor {
+    /// Loop mounts by target. These are kept so that unmounting also detaches the loop device.
+    loop_mounts: sync::Mutex<collections::HashMap<path::PathBuf, sys_mount::Mount>>,
+}
+
+impl SystemExecutor {
+    /// Creates a new SystemExecutor.
+    pub fn new() -> SystemExecutor {
+        SystemExecutor::default()
+    }
+}
+
+#[async_trait]
+impl Executor for SystemExecutor {
+    async fn output(
+        &self,
+        program: &str,
+        args: &[String],
+    ) -> Result<String, Box<dyn error::Error>> {
+        // Kill the command if the step times out and its future is dropped.
+        let output = t_process::Command::new(program)
+            .args(args)
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        output_or_err(program, args, output)
+    }
+
+    fn mount(&self, mount: &Mount) -> Result<(), Box<dyn error::Error>> {
+        let mut builder = sys_mount::Mount::builder();
+
+        if let Some(fstype) = &mount.fstype {
+            builder = builder.fstype(fstype.as_str());
+        }
+
+        let (mut flags, data) = split_mount_options(mount.data.as_deref().unwrap_or_default());
+
+        if !data.is_empty() {
+            builder = builder.data(&data);
+        }
+
+        if mount.read_only {
+            flags |= sys_mount::MountFlags::RDONLY;
+        }
+
+        builder = builder.flags(flags);
+
+        if let Some(offset) = mount.loop_offset {
+            builder = builder.explicit_loopback().loopback_offset(offset);
+        }
+
+        let m = builder.mount(&mount.source, &mount.target)?;
+
+        if mount.loop_offset.is_some() {
+            self.loop_mounts
+                .lock()
+                .expect("loop mounts lock poisoned")
+                .insert(mount.target.clone(), m);
+        }
+
+        Ok(())
+    }
+
+    fn unmount(&self, target: &path::Path) -> Result<(), Box<dyn error::Error>> {
+        let loop_mount = self
+            .loop_mounts
+            .lock()
+            .expect("loop mounts lock poisoned")
+            .remove(target);
+
+        match loop_mount {
+            Some(m) => m.unmount(sys_mount::UnmountFlags::DETACH)?,
+            None => sys_mount::unmount(target, sys_mount::UnmountFlags::DETACH)?,
+        }
+
+        Ok(())
+    }
+
+    fn block_device_exists(&self, path: &str) -> Result<bool, Box<dyn error::Error>> {
+        match fs::metadata(path) {
+            Ok(m) if m.file_type().is_block_device() => Ok(true),
+            Ok(_) => Err(format!("{} exists but is not a block device", path).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A scripted result for commands run by a FakeExecutor.
+#[cfg(test)]
+enum FakeResponse {
+    /// The command succeeds and prints the given stdout.
+    Stdout(String),
+    /// The command fails with the given message.
+    Fail(String),
+    /// The command exits with the given code and stderr.
+    Exit(i32, String),
+}
+
+/// Records operations instead of performing them, with scripted command outputs.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeExecutor {
+    calls: sync::Mutex<Vec<String>>,
+    responses: sync::Mutex<Vec<(String, FakeResponse)>>,
+    mount_contents: sync::Mutex<collections::HashMap<path::PathBuf, path::PathBuf>>,
+    missing_devices: sync::Mutex<collections::HashSet<String>>,
+}
+
+#[cfg(test)]
+impl FakeExecutor {
+    /// Creates a new FakeExecutor. Commands succeed with empty output and all devices exist
+    /// unless scripted otherwise.
+    pub fn new() -> FakeExecutor {
+        FakeExecutor::default()
+    }
+
+    /// Makes commands whose command line starts with `prefix` print `stdout`.
+    pub fn respond(&self, prefix: &str, stdout: &str) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), FakeResponse::Stdout(stdout.to_string())));
+    }
+
+    /// Makes commands whose command line starts with `prefix` fail with `msg`.
+    pub fn fail(&self, prefix: &str, msg: &str) {
+        self.responses
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), FakeResponse::Fail(msg.to_string())));
+    }
+
+    /// Makes commands whose command line starts with `prefix` exit with `code`, printing `stderr`.
+    pub fn exit(&self, prefix: &str, code: i32, stderr: &str) {
+        self.responses.lock().unwrap().push((
+            prefix.to_string(),
+            FakeResponse::Exit(code, stderr.to_string()),
+        ));
+    }
+
+    /// Makes mounting a filesystem at `target` populate it with a copy of `contents`, and
+    /// unmounting it empty it again.
+    pub fn mount_contents(&self, target: &path::Path, contents: &path::Path) {
+        self.mount_contents
+            .lock()
+            .unwrap()
+            .insert(target.to_path_buf(), contents.to_path_buf());
+    }
+
+    /// Makes the block device at `path` never appear.
+    pub fn missing_device(&self, path: &str) {
+        self.missing_devices
+            .lock()
+            .unwrap()
+            .insert(path.to_string());
+    }
+
+    /// Returns the command lines, mounts and unmounts performed so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Returns the calls that start with the given prefix, in order.
+    pub fn calls_starting_with(&self, prefix: &str) -> Vec<String> {
+        self.calls()
+            .into_iter()
+            .filter(|c| c.starts_with(prefix))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Executor for FakeExecutor {
+    async fn output(
+        &self,
+        program: &str,
+        args: &[String],
+    ) -> Result<String, Box<dyn error::Error>> {
+        let line = command_line(program, args);
+
+        self.calls.lock().unwrap().push(line.clone());
+
+        let responses = self.responses.lock().unwrap();
+
+        match responses
+            .iter()
+            .find(|(prefix, _)| line.starts_with(prefix))
+        {
+            Some((_, FakeResponse::Stdout(stdout))) => Ok(stdout.clone()),
+            Some((_, FakeResponse::Fail(msg))) => Err(msg.clone().into()),
+            Some((_, FakeResponse::Exit(code, stderr))) => Err(CommandError {
+                command: line,
+                code: Some(*code),
+                stderr: stderr.clone(),
+            }
+            .into()),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn mount(&self, mount: &Mount) -> Result<(), Box<dyn error::Error>> {
+        self.calls.lock().unwrap().push(mount.to_string());
+
+        if let Some(contents) = self.mount_contents.lock().unwrap().get(&mount.target) {
+            crate::copy::copy_tree(
+                contents,
+                &mount.target,
+                crate::copy::Options {
+                    preserve_ownership: false,
+                },
+                &sync::atomic::AtomicBool::new(false),
+                |_| {},
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn unmount(&self, target: &path::Path) -> Result<(), Box<dyn error::Error>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("umount {}", target.display()));
+
+        if self.mount_contents.lock().unwrap().contains_key(target) {
+            for entry in fs::read_dir(target)? {
+                let entry = entry?;
+
+                if entry.file_type()?.is_dir() {
+                    fs::remove_dir_all(entry.path())?;
+                } else {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn block_device_exists(&self, path: &str) -> Result<bool, Box<dyn error::Error>> {
+        Ok(!self.missing_devices.lock().unwrap().contains(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_mount_options_into_flags_and_data() {
+        let (flags, data) = split_mount_options(
+            "defaults,noatime,_netdev,compress=zstd,nodev,x-systemd.automount,discard",
+        );
+
+        assert_eq!(
+            flags,
+            sys_mount::MountFlags::NOATIME | sys_mount::MountFlags::NODEV
+        );
+        assert_eq!(data, "compress=zstd,discard");
+    }
+
+    #[tokio::test]
+    async fn reports_failed_command_with_stderr() {
+        let exec = SystemExecutor::new();
+
+        let args = [
+            String::from("-c"),
+            String::from("echo 'no such target' >&2; exit 3"),
+        ];
+
+        let err = exec.output("sh", &args).await.unwrap_err();
+
+        let command_err = err
+            .downcast_ref::<CommandError>()
+            .expect("error is a CommandError");
+
+        assert_eq!(command_err.code, Some(3));
+        assert_eq!(command_err.stderr, "no such target");
+        assert_eq!(
+            err.to_string(),
+            "sh -c 'echo '\\''no such target'\\'' >&2; exit 3' exited with code 3: no such target"
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::error;
+use std::fmt;
+use std::str;
+
+/// A filesystem entry in an fstab file. Fields are kept as written, so spaces in them remain
+/// escaped as `\040`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// The device or remote filesystem to mount, e.g. `PARTUUID=...` or `host:/export`.
+    pub spec: String,
+    /// The mount point.
+    pub file: String,
+    /// The filesystem type.
+    pub vfstype: String,
+    /// The mount options.
+    pub mntops: String,
+    /// Whether the filesystem is dumped by `dump`.
+    pub freq: u32,
+    /// The order in which `fsck` checks the filesystem at boot. Zero disables checking.
+    pub passno: u32,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {}",
+            self.spec, self.file, self.vfstype, self.mntops, self.freq, self.passno
+        )
+    }
+}
+
+impl str::FromStr for Entry {
+    type Err = Box<dyn error::Error>;
+
+    fn from_str(s: &str) -> Result<Entry, Box<dyn error::Error>> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+
+        // The dump frequency and fsck pass number are optional and default to zero.
+        if fields.len() < 4 || fields.len() > 6 {
+            return Err(format!("expected 4 to 6 fields but found {}", fields.len()).into());
+        }
+
+        let number = |i: usize, name: &str| -> Result<u32, Box<dyn error::Error>> {
+            match fields.get(i) {
+                Some(field) => field
+                    .parse()
+                    .map_err(|_| format!("invalid {} '{}'", name, field).into()),
+                None => Ok(0),
+            }
+        };
+
+        Ok(Entry {
+            spec: fields[0].to_string(),
+            file: fields[1].to_string(),
+            vfstype: fields[2].to_string(),
+            mntops: fields[3].to_string(),
+            freq: number(4, "dump frequency")?,
+            passno: number(5, "fsck pass number")?,
+        })
+    }
+}
+
+/// A line of an fstab file.
+#[derive(Clone, Debug, PartialEq)]
+enum Line {
+    /// A blank line or a comment, kept as written.
+    Other(String),
+    /// A filesystem entry. The line as written is kept until the entry is replaced, so that
+    /// unchanged entries keep their formatting.
+    Entry {
+        entry: Entry,
+        written: Option<String>,
+    },
+}
+
+/// The contents of an fstab file. Lines are kept in order, and comments, blank lines and entries
+/// that are not replaced are written back exactly as they were read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fstab {
+    lines: Vec<Line>,
+}
+
+impl Fstab {
+    /// Parses the contents of an fstab file.
+    pub fn parse(contents: &str) -> Result<Fstab, Box<dyn error::Error>> {
+        let mut lines = Vec::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let trimmed = line.trim_start();
+
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                lines.push(Line::Other(line.to_string()));
+
+                continue;
+            }
+
+            let entry: Entry = line
+                .parse()
+                .map_err(|e| format!("invalid fstab entry on line {}: {}", i + 1, e))?;
+
+            lines.push(Line::Entry {
+                entry,
+                written: Some(line.to_string()),
+            });
+        }
+
+        Ok(Fstab { lines })
+    }
+
+    /// Replaces the entry for the given mount point. Returns an error if there is no entry for
+    /// the mount point, or more than one.
+    pub fn replace(
+        &mut self,
+        mount_point: &str,
+        new_entry: Entry,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let mut matching = self.lines.iter_mut().filter_map(|line| match line {
+            Line::Entry { entry, written } if entry.file == mount_point => Some((entry, written)),
+            _ => None,
+        });
+
+        let (entry, written) = matching
+            .next()
+            .ok_or_else(|| format!("no fstab entry for {} found", mount_point))?;
+
+        *entry = new_entry;
+        *written = None;
+
+        if matching.next().is_some() {
+            return Err(format!("more than one fstab entry for {} found", mount_point).into());
+        }
+
+        Ok(())
+    }
+
+    /// Sets an entry, replacing the existing entry for the same mount point or, for entries
+    /// without one such as swap, for the same device. The entry is appended if there is none.
+    pub fn set(&mut self, new_entry: Entry) -> Result<(), Box<dyn error::Error>> {
+        let matches = |entry: &Entry| match new_entry.file.as_str() {
+            "none" => entry.file == "none" && entry.spec == new_entry.spec,
+            file => entry.file == file,
+        };
+
+        let count = self
+            .lines
+            .iter()
+            .filter(|line| matches!(line, Line::Entry { entry, .. } if matches(entry)))
+            .count();
+
+        match count {
+            0 => {
+                self.lines.push(Line::Entry {
+                    entry: new_entry,
+                    written: None,
+                });
+            }
+            1 => {
+                for line in &mut self.lines {
+                    if let Line::Entry { entry, written } = line
+                        && matches(entry)
+                    {
+                        *entry = new_entry.clone();
+                        *written = None;
+                    }
+                }
+            }
+            _ => {
+                return Err(
+                    format!("more than one fstab entry for {} found", new_entry.file).into(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Fstab {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Other(text) => writeln!(f, "{}", text)?,
+                Line::Entry {
+                    written: Some(text),
+                    ..
+                } => writeln!(f, "{}", text)?,
+                Line::Entry {
+                    entry,
+                    written: None,
+                } => writeln!(f, "{}", entry)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RASPIOS_FSTAB: &str =
+        "proc            /proc           proc    defaults          0       0
+PARTUUID=abcd1234-01  /boot/firmware  vfat    defaults          0       2
+PARTUUID=abcd1234-02  /               ext4    defaults,noatime  0       1
+# a swapfile is not a swap partition, no line here
+#   use  dphys-swapfile swap[on|off]  for that
+";
+
+    fn entry(spec: &str, file: &str, vfstype: &str, mntops: &str, passno: u32) -> Entry {
+        Entry {
+            spec: spec.to_string(),
+            file: file.to_string(),
+            vfstype: vfstype.to_string(),
+            mntops: mntops.to_string(),
+            freq: 0,
+            passno,
+        }
+    }
+
+    #[test]
+    fn round_trips_unchanged() {
+        let fstab = Fstab::parse(RASPIOS_FSTAB).unwrap();
+
+        assert_eq!(fstab.to_string(), RASPIOS_FSTAB);
+    }
+
+    #[test]
+    fn parses_entries() {
+        assert_eq!(
+            "PARTUUID=abcd1234-02  /  ext4  defaults,noatime  0  1"
+                .parse::<Entry>()
+                .unwrap(),
+            entry("PARTUUID=abcd1234-02", "/", "ext4", "defaults,noatime", 1)
+        );
+
+        // The dump frequency and fsck pass number default to zero.
+        assert_eq!(
+            "proc /proc proc defaults".parse::<Entry>().unwrap(),
+            entry("proc", "/proc", "proc", "defaults", 0)
+        );
+    }
+
+    #[test]
+    fn replaces_entries_by_mount_point() {
+        let mut fstab = Fstab::parse(RASPIOS_FSTAB).unwrap();
+
+        fstab
+            .replace(
+                "/",
+                entry("PARTUUID=5e3da7a1-01", "/", "ext4", "_netdev,noatime", 1),
+            )
+            .unwrap();
+        fstab
+            .replace(
+                "/boot/firmware",
+                entry("10.0.0.3:/tftp/mac", "/boot/firmware", "nfs", "defaults", 0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            fstab.to_string(),
+            "proc            /proc           proc    defaults          0       0
+10.0.0.3:/tftp/mac /boot/firmware nfs defaults 0 0
+PARTUUID=5e3da7a1-01 / ext4 _netdev,noatime 0 1
+# a swapfile is not a swap partition, no line here
+#   use  dphys-swapfile swap[on|off]  for that
+"
+        );
+    }
+
+    #[test]
+    fn rejects_missing_and_duplicate_entries() {
+        let mut fstab = Fstab::parse("proc /proc proc defaults 0 0\n").unwrap();
+
+        assert_eq!(
+            fstab
+                .replace("/", entry("LABEL=root", "/", "ext4", "defaults", 1))
+                .unwrap_err()
+                .to_string(),
+            "no fstab entry for / found"
+        );
+
+        let mut fstab =
+            Fstab::parse("/dev/sda1 / ext4 defaults\n/dev/sdb1 / ext4 defaults\n").unwrap();
+
+        assert_eq!(
+            fstab
+                .replace("/", entry("LABEL=root", "/", "ext4", "defaults", 1))
+                .unwrap_err()
+                .to_string(),
+            "more than one fstab entry for / found"
+        );
+    }
+
+    #[test]
+    fn sets_entries() {
+        let mut fstab = Fstab::parse(RASPIOS_FSTAB).unwrap();
+
+        for _ in 0..2 {
+            fstab
+                .set(entry("PARTUUID=5e3da7a1-02", "/var", "ext4", "_netdev", 2))
+                .unwrap();
+            fstab
+                .set(entry("PARTUUID=5e3da7a1-03", "none", "swap", "sw", 0))
+                .unwrap();
+        }
+
+        // An existing entry for the mount point is replaced in place.
+        fstab
+            .set(entry("proc", "/proc", "proc", "defaults,hidepid=2", 0))
+            .unwrap();
+
+        assert_eq!(
+            fstab.to_string(),
+            "proc /proc proc defaults,hidepid=2 0 0
+PARTUUID=abcd1234-01  /boot/firmware  vfat    defaults          0       2
+PARTUUID=abcd1234-02  /               ext4    defaults,noatime  0       1
+# a swapfile is not a swap partition, no line here
+#   use  dphys-swapfile swap[on|off]  for that
+PARTUUID=5e3da7a1-02 /var ext4 _netdev 0 2
+PARTUUID=5e3da7a1-03 none swap sw 0 0
+"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        assert_eq!(
+            Fstab::parse("# root\n/dev/sda1 /\n")
+                .unwrap_err()
+                .to_string(),
+            "invalid fstab entry on line 2: expected 4 to 6 fields but found 2"
+        );
+        assert_eq!(
+            Fstab::parse("/dev/sda1 / ext4 defaults 0 x\n")
+                .unwrap_err()
+                .to_string(),
+            "invalid fstab entry on line 1: invalid fsck pass number 'x'"
+        );
+    }
+}
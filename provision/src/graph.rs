@@ -2,14 +2,16 @@ use std::collections;
 use std::fmt;
 
 use futures::future;
-use thiserror;
+use tokio::sync;
 use tokio::sync::broadcast;
+use tokio::time;
 
 use crate::config;
+use crate::report;
 use crate::steps;
 
 /// StepError represents an error executing a step in a graph.
-#[derive(Clone, thiserror::Error, Debug)]
+#[derive(Clone, thiserror::Error, Debug, serde::Serialize)]
 pub struct StepError {
     step_name: String,
     msg: String,
@@ -21,10 +23,101 @@ impl fmt::Display for StepError {
     }
 }
 
+/// GraphError represents a problem with the structure of a graph.
+#[derive(Clone, thiserror::Error, Debug, PartialEq)]
+pub enum GraphError {
+    #[error("node {0} does not exist in the graph")]
+    NodeNotFound(usize),
+    #[error("step {0} cannot depend on itself")]
+    SelfEdge(String),
+    #[error("adding an edge would create a dependency cycle: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+    #[error("steps cannot be reached from {until}: {}", .unreachable.join(", "))]
+    Unreachable {
+        until: String,
+        unreachable: Vec<String>,
+    },
+}
+
+/// The actions a step would take if run.
+pub struct PlannedStep {
+    /// The name of the step.
+    pub name: String,
+    /// Descriptions of the actions the step would take, in order.
+    pub actions: Vec<String>,
+}
+
 #[derive(Clone, Debug)]
 struct VisitResult {
     node_idx: usize,
     result: Result<(), StepError>,
+    status: report::Status,
+    attempts: u32,
+    timing: Option<report::Timing>,
+}
+
+/// Whether a run performs every step or only the steps whose desired state does not already hold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunMode {
+    /// Every step runs.
+    Full,
+    /// Each step is checked before it runs, and is not run if it is already satisfied.
+    Reconcile,
+}
+
+/// Interrupts runs of a graph, e.g. when the process is asked to stop. Steps that are running are
+/// cancelled and steps that have not started are not started, but every step that started is still
+/// cleaned up. Clones share the same state.
+#[derive(Clone)]
+pub struct Interrupt {
+    tx: std::sync::Arc<sync::watch::Sender<bool>>,
+}
+
+impl Default for Interrupt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interrupt {
+    /// Creates a new Interrupt that has not been triggered.
+    pub fn new() -> Interrupt {
+        let (tx, _) = sync::watch::channel(false);
+
+        Interrupt {
+            tx: std::sync::Arc::new(tx),
+        }
+    }
+
+    /// Interrupts every run using this Interrupt or one of its clones.
+    pub fn interrupt(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Returns whether runs have been interrupted.
+    pub fn is_interrupted(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Waits until runs are interrupted.
+    async fn interrupted(&self) {
+        let mut rx = self.tx.subscribe();
+
+        // The sender lives as long as self, so the channel cannot close while waiting.
+        let _ = rx.wait_for(|interrupted| *interrupted).await;
+    }
+}
+
+/// Whether a walk runs steps or cleans them up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum WalkMode {
+    /// Steps wait for concurrency permits, are retried according to their policies, and are
+    /// skipped if a dependency fails.
+    Run,
+    /// Like `Run`, but steps that are already satisfied are not run.
+    Reconcile,
+    /// Steps are cleaned up once each regardless of whether their dependencies' cleanups failed.
+    Cleanup,
 }
 
 /// A graph of instance provisioning steps.
@@ -32,6 +125,14 @@ pub struct StepGraph {
     nodes: Vec<Box<dyn steps::Step>>,
     edges_fwd: Vec<Vec<usize>>,
     edges_rev: Vec<Vec<usize>>,
+    limits: collections::HashMap<usize, sync::Semaphore>,
+    policies: collections::HashMap<usize, steps::StepPolicy>,
+}
+
+impl Default for StepGraph {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl StepGraph {
@@ -40,11 +141,15 @@ impl StepGraph {
         let nodes = Vec::new();
         let edges_fwd = Vec::new();
         let edges_rev = Vec::new();
+        let limits = collections::HashMap::new();
+        let policies = collections::HashMap::new();
 
         StepGraph {
             nodes,
             edges_fwd,
             edges_rev,
+            limits,
+            policies,
         }
     }
 
@@ -53,42 +158,243 @@ impl StepGraph {
         let len = self.nodes.len();
         let b: Box<dyn steps::Step> = Box::new(step);
 
-        self.nodes.push(b.into());
+        self.nodes.push(b);
         self.edges_fwd.push(Vec::new());
         self.edges_rev.push(Vec::new());
 
         len
     }
 
-    /// Adds an edge from one node to another node. Panics if either node does not exist
-    /// in the graph.
-    pub fn add_edge(&mut self, from: usize, to: usize) {
-        let edges_fwd = self.edges_fwd.get_mut(from).expect("edges not found");
-        let edges_rev = self.edges_rev.get_mut(to).expect("edges not found");
+    fn check_node(&self, node: usize) -> Result<(), GraphError> {
+        match self.nodes.get(node) {
+            Some(_) => Ok(()),
+            None => Err(GraphError::NodeNotFound(node)),
+        }
+    }
+
+    /// Adds an edge from one node to another node, meaning `from` depends on `to`. Returns an
+    /// error if either node does not exist, or if the edge would create a cycle.
+    pub fn add_edge(&mut self, from: usize, to: usize) -> Result<(), GraphError> {
+        self.check_node(from)?;
+        self.check_node(to)?;
+
+        if from == to {
+            return Err(GraphError::SelfEdge(self.nodes[from].name()));
+        }
+
+        if let Some(path) = self.path(to, from) {
+            let mut cycle = vec![self.nodes[from].name()];
+
+            cycle.extend(path.iter().map(|n| self.nodes[*n].name()));
+
+            return Err(GraphError::Cycle(cycle));
+        }
+
+        self.edges_fwd[from].push(to);
+        self.edges_rev[to].push(from);
+
+        Ok(())
+    }
+
+    /// Returns a path of nodes following forward edges from `from` to `to`, if there is one.
+    fn path(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        let mut parents: collections::HashMap<usize, usize> = collections::HashMap::new();
+        let mut queue = collections::VecDeque::from([from]);
+
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = vec![to];
+
+                while let Some(parent) = parents.get(path.last().expect("path is not empty")) {
+                    path.push(*parent);
+                }
+
+                path.reverse();
+
+                return Some(path);
+            }
+
+            for next in &self.edges_fwd[node] {
+                if *next != from && !parents.contains_key(next) {
+                    parents.insert(*next, node);
+                    queue.push_back(*next);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Checks that `until` exists and that every node in the graph can reach it, so that no step
+    /// is silently left out of a walk.
+    pub fn validate(&self, until: usize) -> Result<(), GraphError> {
+        self.check_node(until)?;
+
+        let node_set = self.node_set(until);
+
+        let unreachable: Vec<String> = (0..self.nodes.len())
+            .filter(|n| !node_set.contains(n))
+            .map(|n| self.nodes[n].name())
+            .collect();
+
+        if !unreachable.is_empty() {
+            return Err(GraphError::Unreachable {
+                until: self.nodes[until].name(),
+                unreachable,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Limits the number of concurrent runs of a node across all walks of the graph. Returns an
+    /// error if the node does not exist in the graph.
+    pub fn limit_concurrency(&mut self, node: usize, permits: usize) -> Result<(), GraphError> {
+        self.check_node(node)?;
+
+        self.limits.insert(node, sync::Semaphore::new(permits));
+
+        Ok(())
+    }
+
+    /// Overrides the timeout and retry policy of a node. Returns an error if the node does not
+    /// exist in the graph.
+    pub fn set_policy(&mut self, node: usize, policy: steps::StepPolicy) -> Result<(), GraphError> {
+        self.check_node(node)?;
+
+        self.policies.insert(node, policy);
+
+        Ok(())
+    }
+
+    fn policy(&self, node: usize) -> steps::StepPolicy {
+        match self.policies.get(&node) {
+            Some(policy) => *policy,
+            None => self.nodes[node].policy(),
+        }
+    }
+
+    /// Applies the concurrency limits and policy overrides in the given step configs to the nodes
+    /// with matching names.
+    pub fn apply_step_configs(
+        &mut self,
+        step_configs: &collections::HashMap<String, config::StepConfig>,
+    ) {
+        for node in 0..self.nodes.len() {
+            let step_config = match step_configs.get(&self.nodes[node].name()) {
+                Some(step_config) => step_config,
+                None => continue,
+            };
+
+            if let Some(permits) = step_config.max_concurrent {
+                self.limits
+                    .insert(node, sync::Semaphore::new(permits.max(1)));
+            }
+
+            let policy = self.policy(node).overridden(step_config);
+
+            self.policies.insert(node, policy);
+        }
+    }
+
+    /// Renders the graph in Graphviz DOT format. Edges point from a step to the steps it
+    /// depends on.
+    pub fn dot(&self) -> String {
+        let mut out = String::from("digraph steps {\n");
+
+        for (idx, step) in self.nodes.iter().enumerate() {
+            out.push_str(&format!("    {} [label={:?}];\n", idx, step.name()));
+        }
+
+        for (from, edges) in self.edges_fwd.iter().enumerate() {
+            for to in edges {
+                out.push_str(&format!("    {} -> {};\n", from, to));
+            }
+        }
+
+        out.push_str("}\n");
+
+        out
+    }
+
+    /// Describes the actions of all steps that can reach `until` without running them. Steps are
+    /// returned in an order that respects their dependencies.
+    pub fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+        until: usize,
+    ) -> Result<Vec<PlannedStep>, StepError> {
+        self.ordered_nodes(until)
+            .iter()
+            .map(|n| {
+                let step = self.nodes.get(*n).expect("node not found");
+
+                let step_name = step.name();
+
+                match step.plan(workspace_spec, instance_spec) {
+                    Ok(actions) => Ok(PlannedStep {
+                        name: step_name,
+                        actions,
+                    }),
+                    Err(e) => Err(StepError {
+                        step_name,
+                        msg: e.to_string(),
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    fn ordered_nodes(&self, until: usize) -> Vec<usize> {
+        let node_set = self.node_set(until);
+
+        let mut ordered: Vec<usize> = Vec::with_capacity(node_set.len());
 
-        self.nodes.get(to).expect("node not found");
+        while ordered.len() < node_set.len() {
+            let next = (0..self.nodes.len()).find(|n| {
+                node_set.contains(n)
+                    && !ordered.contains(n)
+                    && self.edges_fwd[*n].iter().all(|d| ordered.contains(d))
+            });
+
+            match next {
+                Some(n) => ordered.push(n),
+                None => break,
+            }
+        }
 
-        edges_fwd.push(to);
-        edges_rev.push(from);
+        ordered
     }
 
-    fn build_node_set(&self, node: usize, set: &mut collections::HashSet<usize>) {
-        set.insert(node);
+    /// Returns the set of nodes that `until` depends on, directly or indirectly, including itself.
+    fn node_set(&self, until: usize) -> collections::HashSet<usize> {
+        let mut set = collections::HashSet::new();
+        let mut stack = vec![until];
 
-        let edges_fwd = self.edges_fwd.get(node).expect("invalid index");
+        while let Some(node) = stack.pop() {
+            if set.insert(node) {
+                let edges_fwd = self.edges_fwd.get(node).expect("invalid index");
 
-        edges_fwd.iter().for_each(|n| self.build_node_set(*n, set));
+                stack.extend(edges_fwd.iter().copied());
+            }
+        }
+
+        set
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn visit(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
         node_idx: usize,
+        mode: WalkMode,
+        interrupt: &Interrupt,
         mut dependencies: Vec<broadcast::Receiver<VisitResult>>,
         out: broadcast::Sender<VisitResult>,
         visit_fn: impl AsyncFn(
-            &Box<dyn steps::Step>,
+            &dyn steps::Step,
             &config::WorkspaceConfig,
             &config::InstanceConfig,
         ) -> Result<(), Box<dyn std::error::Error>>,
@@ -97,51 +403,242 @@ impl StepGraph {
 
         let step_name = step.name();
 
-        println!("{}: starting", step_name);
+        println!("{}: {}: starting", instance_spec.id, step_name);
 
-        println!("{}: waiting for dependencies", step_name);
+        println!(
+            "{}: {}: waiting for dependencies",
+            instance_spec.id, step_name
+        );
 
         let dependencies_results =
             future::join_all(dependencies.iter_mut().map(async |d| d.recv().await));
 
         for res in dependencies_results.await {
-            println!("{}: dependency finished", step_name);
+            println!("{}: {}: dependency finished", instance_spec.id, step_name);
 
             let v = res.unwrap();
 
+            if mode == WalkMode::Cleanup {
+                continue;
+            }
+
             if let Err(e) = &v.result {
                 println!(
-                    "{}: received dependency error {}, returning early",
-                    step_name, e
+                    "{}: {}: received dependency error {}, returning early",
+                    instance_spec.id, step_name, e
                 );
 
+                let status = match v.status {
+                    report::Status::Cancelled => report::Status::Cancelled,
+                    _ => report::Status::Skipped,
+                };
+
+                let v = VisitResult {
+                    node_idx,
+                    result: v.result,
+                    status,
+                    attempts: 0,
+                    timing: None,
+                };
+
                 out.send(v).unwrap();
 
                 return;
             }
         }
 
-        let result = match visit_fn(step, workspace_spec, instance_spec).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(StepError {
-                step_name,
-                msg: e.to_string(),
-            }),
+        let cancelled = |attempts: u32| {
+            println!("{}: {}: interrupted", instance_spec.id, step_name);
+
+            VisitResult {
+                node_idx,
+                result: Err(StepError {
+                    step_name: step_name.clone(),
+                    msg: String::from("interrupted"),
+                }),
+                status: report::Status::Cancelled,
+                attempts,
+                timing: None,
+            }
+        };
+
+        if interrupt.is_interrupted() {
+            out.send(cancelled(0)).unwrap();
+
+            return;
+        }
+
+        let policy = self.policy(node_idx);
+
+        if mode == WalkMode::Reconcile {
+            let started_at = chrono::Utc::now();
+
+            let check = step.satisfied(workspace_spec, instance_spec);
+
+            let satisfied = match policy.timeout {
+                Some(timeout) => match time::timeout(timeout, check).await {
+                    Ok(result) => result,
+                    Err(_) => Err(format!("timed out after {:?}", timeout).into()),
+                },
+                None => check.await,
+            };
+
+            let (result, status) = match satisfied {
+                Ok(true) => {
+                    println!(
+                        "{}: {}: already satisfied, not running",
+                        instance_spec.id, step_name
+                    );
+
+                    (Ok(()), report::Status::Satisfied)
+                }
+                Ok(false) => (Ok(()), report::Status::Succeeded),
+                Err(e) => (
+                    Err(StepError {
+                        step_name: step_name.clone(),
+                        msg: format!("error checking whether the step is satisfied: {}", e),
+                    }),
+                    report::Status::Failed,
+                ),
+            };
+
+            if status != report::Status::Succeeded {
+                let v = VisitResult {
+                    node_idx,
+                    result,
+                    status,
+                    attempts: 0,
+                    timing: Some(report::Timing::since(started_at)),
+                };
+
+                out.send(v).unwrap();
+
+                return;
+            }
+        }
+
+        let limit = self
+            .limits
+            .get(&node_idx)
+            .filter(|_| mode != WalkMode::Cleanup);
+
+        let _permit = match limit {
+            Some(semaphore) => {
+                println!(
+                    "{}: {}: waiting for a concurrency permit",
+                    instance_spec.id, step_name
+                );
+
+                tokio::select! {
+                    permit = semaphore.acquire() => Some(permit.expect("semaphore closed")),
+                    _ = interrupt.interrupted() => {
+                        out.send(cancelled(0)).unwrap();
+
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let max_attempts = match mode {
+            WalkMode::Run | WalkMode::Reconcile => policy.max_attempts.max(1),
+            WalkMode::Cleanup => 1,
+        };
+
+        let started_at = chrono::Utc::now();
+
+        let mut attempts = 0;
+        let mut retry_delay = policy.retry_delay;
+
+        let result = loop {
+            attempts += 1;
+
+            let attempt = async {
+                let attempt = visit_fn(step.as_ref(), workspace_spec, instance_spec);
+
+                match policy.timeout {
+                    Some(timeout) => match time::timeout(timeout, attempt).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!("timed out after {:?}", timeout).into()),
+                    },
+                    None => attempt.await,
+                }
+            };
+
+            // Dropping an attempt cancels it, killing any command it is waiting on.
+            let attempt_result = tokio::select! {
+                result = attempt => result,
+                _ = interrupt.interrupted() => {
+                    let mut v = cancelled(attempts);
+
+                    v.timing = Some(report::Timing::since(started_at));
+
+                    out.send(v).unwrap();
+
+                    return;
+                }
+            };
+
+            match attempt_result {
+                Ok(_) => break Ok(()),
+                Err(e) if attempts < max_attempts => {
+                    println!(
+                        "{}: {}: attempt {} of {} failed: {}, retrying in {:?}",
+                        instance_spec.id, step_name, attempts, max_attempts, e, retry_delay
+                    );
+
+                    tokio::select! {
+                        _ = time::sleep(retry_delay) => {}
+                        _ = interrupt.interrupted() => {
+                            let mut v = cancelled(attempts);
+
+                            v.timing = Some(report::Timing::since(started_at));
+
+                            out.send(v).unwrap();
+
+                            return;
+                        }
+                    }
+
+                    retry_delay = (retry_delay * 2).min(policy.max_retry_delay);
+                }
+                Err(e) => {
+                    break Err(StepError {
+                        step_name,
+                        msg: e.to_string(),
+                    });
+                }
+            }
         };
 
-        let v = VisitResult { node_idx, result };
+        let status = match result {
+            Ok(_) => report::Status::Succeeded,
+            Err(_) => report::Status::Failed,
+        };
+
+        let v = VisitResult {
+            node_idx,
+            result,
+            status,
+            attempts,
+            timing: Some(report::Timing::since(started_at)),
+        };
 
         out.send(v).unwrap();
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn walk(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
         node_set: &collections::HashSet<usize>,
+        mode: WalkMode,
+        interrupt: &Interrupt,
         neighbor_fn: impl Fn(usize) -> Vec<usize>,
         visit_fn: impl AsyncFn(
-            &Box<dyn steps::Step>,
+            &dyn steps::Step,
             &config::WorkspaceConfig,
             &config::InstanceConfig,
         ) -> Result<(), Box<dyn std::error::Error>>,
@@ -173,9 +670,11 @@ impl StepGraph {
                 .collect();
 
             self.visit(
-                &workspace_spec,
-                &instance_spec,
+                workspace_spec,
+                instance_spec,
                 *node,
+                mode,
+                interrupt,
                 dependencies_recv,
                 result_sender,
                 &visit_fn,
@@ -202,16 +701,61 @@ impl StepGraph {
         results
     }
 
-    /// Executes a walk through the graph over all points that can reach `until`.
+    /// Cleans up the given nodes, each after the nodes that depend on it.
+    async fn cleanup_walk(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+        node_set: &collections::HashSet<usize>,
+    ) -> collections::HashMap<usize, VisitResult> {
+        let cleanup_neighbor_fn = |n: usize| -> Vec<usize> {
+            self.edges_rev
+                .get(n)
+                .expect("edges not found")
+                .iter()
+                .filter(|n| node_set.contains(n))
+                .copied()
+                .collect()
+        };
+
+        let cleanup_visit_fn =
+            async |s: &dyn steps::Step,
+                   wspec: &config::WorkspaceConfig,
+                   ispec: &config::InstanceConfig| {
+                s.cleanup(wspec, ispec).await;
+
+                Ok(())
+            };
+
+        // Cleanup is never interrupted, since interrupting it would leave behind exactly what it
+        // is tearing down.
+        self.walk(
+            workspace_spec,
+            instance_spec,
+            node_set,
+            WalkMode::Cleanup,
+            &Interrupt::new(),
+            cleanup_neighbor_fn,
+            cleanup_visit_fn,
+        )
+        .await
+    }
+
+    /// Executes a walk through the graph over all points that can reach `until`, then cleans up
+    /// every step that ran. In reconcile mode, steps that are already satisfied are neither run nor
+    /// cleaned up. If the run is interrupted, steps that have not started are cancelled and every
+    /// step that started is cleaned up. Returns a report of each step's outcome.
     pub async fn run(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
         until: usize,
-    ) -> Result<(), StepError> {
-        let mut node_set = &mut collections::HashSet::new();
+        mode: RunMode,
+        interrupt: &Interrupt,
+    ) -> report::InstanceReport {
+        let started_at = chrono::Utc::now();
 
-        self.build_node_set(until, &mut node_set);
+        let node_set = &self.node_set(until);
 
         let run_neighbor_fn = |n: usize| -> Vec<usize> {
             self.edges_fwd
@@ -219,20 +763,25 @@ impl StepGraph {
                 .expect("edges not found")
                 .iter()
                 .filter(|n| node_set.contains(n))
-                .map(|n| *n)
+                .copied()
                 .collect()
         };
 
         let run_visit_fn =
-            async |s: &Box<dyn steps::Step>,
+            async |s: &dyn steps::Step,
                    wspec: &config::WorkspaceConfig,
                    ispec: &config::InstanceConfig| { s.run(wspec, ispec).await };
 
         let run_results = self
             .walk(
-                &workspace_spec,
-                &instance_spec,
+                workspace_spec,
+                instance_spec,
                 node_set,
+                match mode {
+                    RunMode::Full => WalkMode::Run,
+                    RunMode::Reconcile => WalkMode::Reconcile,
+                },
+                interrupt,
                 run_neighbor_fn,
                 run_visit_fn,
             )
@@ -244,42 +793,642 @@ impl StepGraph {
             .result
             .clone();
 
-        println!("provisioning finished, beginning cleanup");
+        println!(
+            "{}: provisioning finished, beginning cleanup",
+            instance_spec.id
+        );
 
         let visited_node_set = &mut collections::HashSet::new();
 
         for v in run_results.values() {
-            visited_node_set.insert(v.node_idx);
+            let started = match v.status {
+                report::Status::Succeeded | report::Status::Failed => true,
+                report::Status::Cancelled => v.attempts > 0,
+                report::Status::Skipped | report::Status::Satisfied => false,
+            };
+
+            if started {
+                visited_node_set.insert(v.node_idx);
+            }
         }
 
-        let cleanup_neighbor_fn = |n: usize| -> Vec<usize> {
-            self.edges_rev
-                .get(n)
-                .expect("edges not found")
-                .iter()
-                .filter(|n| visited_node_set.contains(n))
-                .map(|n| *n)
-                .collect()
+        let cleanup_results = self
+            .cleanup_walk(workspace_spec, instance_spec, visited_node_set)
+            .await;
+
+        let steps = self
+            .ordered_nodes(until)
+            .iter()
+            .map(|n| {
+                let v = run_results.get(n).expect("result not found");
+
+                let error = match (&v.result, v.status) {
+                    (Err(e), report::Status::Skipped) => {
+                        Some(format!("skipped because {} failed", e.step_name))
+                    }
+                    (Err(e), _) => Some(e.msg.clone()),
+                    (Ok(_), _) => None,
+                };
+
+                let cleanup = cleanup_results.get(n);
+
+                report::StepReport {
+                    name: self.nodes[*n].name(),
+                    status: v.status,
+                    attempts: v.attempts,
+                    run: v.timing.clone(),
+                    cleanup: cleanup.and_then(|c| c.timing.clone()),
+                    error,
+                    cleanup_error: cleanup.and_then(|c| c.result.clone().err()).map(|e| e.msg),
+                }
+            })
+            .collect();
+
+        let status = match run_result {
+            Ok(_) => report::Status::Succeeded,
+            Err(_) if interrupt.is_interrupted() => report::Status::Cancelled,
+            Err(_) => report::Status::Failed,
         };
 
-        let cleanup_visit_fn =
-            async |s: &Box<dyn steps::Step>,
-                   wspec: &config::WorkspaceConfig,
-                   ispec: &config::InstanceConfig| {
-                s.cleanup(wspec, ispec).await;
+        report::InstanceReport {
+            id: instance_spec.id.clone(),
+            status,
+            run: report::Timing::since(started_at),
+            error: run_result.err(),
+            steps,
+        }
+    }
 
-                Ok(())
-            };
+    /// Cleans up every step that can reach `until` without running any of them, tearing down what
+    /// an interrupted or crashed run may have left behind. Returns a report of each step's cleanup.
+    pub async fn cleanup(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+        until: usize,
+    ) -> report::InstanceReport {
+        let started_at = chrono::Utc::now();
 
-        self.walk(
-            &workspace_spec,
-            &instance_spec,
-            visited_node_set,
-            cleanup_neighbor_fn,
-            cleanup_visit_fn,
-        )
-        .await;
+        let cleanup_results = self
+            .cleanup_walk(workspace_spec, instance_spec, &self.node_set(until))
+            .await;
+
+        let ordered_results: Vec<&VisitResult> = self
+            .ordered_nodes(until)
+            .iter()
+            .map(|n| cleanup_results.get(n).expect("result not found"))
+            .collect();
+
+        let steps = ordered_results
+            .iter()
+            .map(|c| report::StepReport {
+                name: self.nodes[c.node_idx].name(),
+                status: c.status,
+                attempts: 0,
+                run: None,
+                cleanup: c.timing.clone(),
+                error: None,
+                cleanup_error: c.result.clone().err().map(|e| e.msg),
+            })
+            .collect();
+
+        let error = ordered_results.iter().find_map(|c| c.result.clone().err());
+
+        let status = match error {
+            None => report::Status::Succeeded,
+            Some(_) => report::Status::Failed,
+        };
+
+        report::InstanceReport {
+            id: instance_spec.id.clone(),
+            status,
+            run: report::Timing::since(started_at),
+            error,
+            steps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::error;
+    use std::path;
+    use std::sync::atomic;
+
+    use async_trait::async_trait;
+
+    /// Fails a given number of times before succeeding, taking `delay` for each attempt.
+    struct FlakyStep {
+        failures: atomic::AtomicU32,
+        delay: time::Duration,
+        policy: steps::StepPolicy,
+    }
+
+    #[async_trait]
+    impl steps::Step for FlakyStep {
+        fn name(&self) -> String {
+            String::from("flaky")
+        }
+
+        fn policy(&self) -> steps::StepPolicy {
+            self.policy
+        }
+
+        fn plan(
+            &self,
+            _workspace_spec: &config::WorkspaceConfig,
+            _instance_spec: &config::InstanceConfig,
+        ) -> Result<Vec<String>, Box<dyn error::Error>> {
+            Ok(Vec::new())
+        }
+
+        async fn run(
+            &self,
+            _workspace_spec: &config::WorkspaceConfig,
+            _instance_spec: &config::InstanceConfig,
+        ) -> Result<(), Box<dyn error::Error>> {
+            time::sleep(self.delay).await;
+
+            let remaining = self.failures.load(atomic::Ordering::SeqCst);
+
+            if remaining > 0 {
+                self.failures.store(remaining - 1, atomic::Ordering::SeqCst);
+
+                return Err("transient failure".into());
+            }
+
+            Ok(())
+        }
+
+        async fn cleanup(
+            &self,
+            _workspace_spec: &config::WorkspaceConfig,
+            _instance_spec: &config::InstanceConfig,
+        ) {
+        }
+    }
+
+    /// Records how often it runs, and reports a fixed answer when checked.
+    struct CheckedStep {
+        name: &'static str,
+        satisfied: Result<bool, &'static str>,
+        runs: atomic::AtomicU32,
+    }
+
+    #[async_trait]
+    impl steps::Step for CheckedStep {
+        fn name(&self) -> String {
+            String::from(self.name)
+        }
+
+        fn plan(
+            &self,
+            _workspace_spec: &config::WorkspaceConfig,
+            _instance_spec: &config::InstanceConfig,
+        ) -> Result<Vec<String>, Box<dyn error::Error>> {
+            Ok(Vec::new())
+        }
+
+        async fn satisfied(
+            &self,
+            _workspace_spec: &config::WorkspaceConfig,
+            _instance_spec: &config::InstanceConfig,
+        ) -> Result<bool, Box<dyn error::Error>> {
+            Ok(self.satisfied?)
+        }
+
+        async fn run(
+            &self,
+            _workspace_spec: &config::WorkspaceConfig,
+            _instance_spec: &config::InstanceConfig,
+        ) -> Result<(), Box<dyn error::Error>> {
+            self.runs.fetch_add(1, atomic::Ordering::SeqCst);
+
+            Ok(())
+        }
+
+        async fn cleanup(
+            &self,
+            _workspace_spec: &config::WorkspaceConfig,
+            _instance_spec: &config::InstanceConfig,
+        ) {
+        }
+    }
+
+    fn checked(name: &'static str, satisfied: Result<bool, &'static str>) -> CheckedStep {
+        CheckedStep {
+            name,
+            satisfied,
+            runs: atomic::AtomicU32::new(0),
+        }
+    }
+
+    fn flaky(failures: u32, delay_ms: u64, max_attempts: u32) -> FlakyStep {
+        FlakyStep {
+            failures: atomic::AtomicU32::new(failures),
+            delay: time::Duration::from_millis(delay_ms),
+            policy: steps::StepPolicy {
+                timeout: Some(time::Duration::from_millis(200)),
+                max_attempts,
+                retry_delay: time::Duration::from_millis(1),
+                max_retry_delay: time::Duration::from_millis(2),
+            },
+        }
+    }
+
+    fn workspace_spec() -> config::WorkspaceConfig {
+        config::fixtures::workspace_spec(path::Path::new("/tmp/provision-test"))
+    }
+
+    fn instance_spec() -> config::InstanceConfig {
+        config::fixtures::instance_spec("node1", "dc-a6-32-00-00-01")
+    }
+
+    /// Builds a diamond: d depends on b and c, which both depend on a.
+    fn diamond(b_msg: &'static str) -> (StepGraph, [usize; 4]) {
+        let mut graph = StepGraph::new();
+
+        let a = graph.add_node(steps::EchoStep { msg: "a" });
+        let b = graph.add_node(steps::EchoStep { msg: b_msg });
+        let c = graph.add_node(steps::EchoStep { msg: "c" });
+        let d = graph.add_node(steps::EchoStep { msg: "d" });
+
+        graph.add_edge(b, a).unwrap();
+        graph.add_edge(c, a).unwrap();
+        graph.add_edge(d, b).unwrap();
+        graph.add_edge(d, c).unwrap();
+
+        (graph, [a, b, c, d])
+    }
 
-        run_result
+    #[test]
+    fn rejects_missing_nodes() {
+        let (mut graph, [a, ..]) = diamond("b");
+
+        assert_eq!(graph.add_edge(a, 4), Err(GraphError::NodeNotFound(4)));
+        assert_eq!(graph.add_edge(7, a), Err(GraphError::NodeNotFound(7)));
+        assert_eq!(
+            graph.limit_concurrency(9, 1),
+            Err(GraphError::NodeNotFound(9))
+        );
+        assert_eq!(graph.validate(5), Err(GraphError::NodeNotFound(5)));
+    }
+
+    #[test]
+    fn rejects_self_edges() {
+        let (mut graph, [_, b, ..]) = diamond("b");
+
+        assert_eq!(
+            graph.add_edge(b, b),
+            Err(GraphError::SelfEdge(String::from("echo b")))
+        );
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let (mut graph, [a, _, c, d]) = diamond("b");
+
+        assert_eq!(
+            graph.add_edge(a, d),
+            Err(GraphError::Cycle(vec![
+                String::from("echo a"),
+                String::from("echo d"),
+                String::from("echo b"),
+                String::from("echo a"),
+            ]))
+        );
+        assert_eq!(
+            graph.add_edge(a, c),
+            Err(GraphError::Cycle(vec![
+                String::from("echo a"),
+                String::from("echo c"),
+                String::from("echo a"),
+            ]))
+        );
+
+        // The rejected edges must not have been added.
+        assert_eq!(graph.validate(d), Ok(()));
+        assert!(graph.edges_fwd[a].is_empty());
+    }
+
+    #[test]
+    fn rejects_unreachable_nodes() {
+        let (mut graph, [_, b, _, d]) = diamond("b");
+
+        let e = graph.add_node(steps::EchoStep { msg: "e" });
+
+        assert_eq!(
+            graph.validate(d),
+            Err(GraphError::Unreachable {
+                until: String::from("echo d"),
+                unreachable: vec![String::from("echo e")],
+            })
+        );
+        assert_eq!(
+            graph.validate(b),
+            Err(GraphError::Unreachable {
+                until: String::from("echo b"),
+                unreachable: vec![
+                    String::from("echo c"),
+                    String::from("echo d"),
+                    String::from("echo e"),
+                ],
+            })
+        );
+
+        graph.add_edge(e, d).unwrap();
+
+        assert_eq!(graph.validate(e), Ok(()));
+    }
+
+    #[test]
+    fn operation_graphs_are_valid() {
+        for operation in [
+            crate::Operation::Provision,
+            crate::Operation::Verify,
+            crate::Operation::Deprovision,
+        ] {
+            let exec = std::sync::Arc::new(crate::exec::FakeExecutor::new());
+
+            assert!(operation.graph(exec).is_ok(), "{:?} graph", operation);
+        }
+    }
+
+    #[test]
+    fn plans_in_dependency_order() {
+        let (graph, [.., d]) = diamond("b");
+
+        let names: Vec<String> = graph
+            .plan(&workspace_spec(), &instance_spec(), d)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+
+        assert_eq!(names, ["echo a", "echo b", "echo c", "echo d"]);
+    }
+
+    #[tokio::test]
+    async fn runs_valid_graph() {
+        let (graph, [.., d]) = diamond("b");
+
+        let report = graph
+            .run(
+                &workspace_spec(),
+                &instance_spec(),
+                d,
+                RunMode::Full,
+                &Interrupt::new(),
+            )
+            .await;
+
+        assert_eq!(report.status, report::Status::Succeeded);
+        assert!(report.error.is_none());
+        assert!(
+            report
+                .steps
+                .iter()
+                .all(|s| s.status == report::Status::Succeeded && s.cleanup.is_some())
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_steps_after_failure() {
+        let (graph, [.., d]) = diamond("err");
+
+        let report = graph
+            .run(
+                &workspace_spec(),
+                &instance_spec(),
+                d,
+                RunMode::Full,
+                &Interrupt::new(),
+            )
+            .await;
+
+        let statuses: Vec<(String, report::Status, bool)> = report
+            .steps
+            .iter()
+            .map(|s| (s.name.clone(), s.status, s.cleanup.is_some()))
+            .collect();
+
+        assert_eq!(report.status, report::Status::Failed);
+        assert_eq!(
+            statuses,
+            [
+                (String::from("echo a"), report::Status::Succeeded, true),
+                (String::from("echo err"), report::Status::Failed, true),
+                (String::from("echo c"), report::Status::Succeeded, true),
+                (String::from("echo d"), report::Status::Skipped, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupted_run_cleans_up_started_steps() {
+        let mut graph = StepGraph::new();
+
+        let a = graph.add_node(steps::EchoStep { msg: "a" });
+        let hung = graph.add_node(flaky(0, 10_000, 1));
+        let c = graph.add_node(steps::EchoStep { msg: "c" });
+
+        graph.add_edge(hung, a).unwrap();
+        graph.add_edge(c, hung).unwrap();
+
+        let workspace_spec = workspace_spec();
+        let instance_spec = instance_spec();
+        let interrupt = Interrupt::new();
+
+        let (report, _) = tokio::join!(
+            graph.run(
+                &workspace_spec,
+                &instance_spec,
+                c,
+                RunMode::Full,
+                &interrupt
+            ),
+            async {
+                time::sleep(time::Duration::from_millis(20)).await;
+
+                interrupt.interrupt();
+            }
+        );
+
+        let statuses: Vec<(String, report::Status, u32, bool)> = report
+            .steps
+            .iter()
+            .map(|s| (s.name.clone(), s.status, s.attempts, s.cleanup.is_some()))
+            .collect();
+
+        assert_eq!(report.status, report::Status::Cancelled);
+        assert_eq!(
+            report.error.unwrap().to_string(),
+            "error running flaky: interrupted"
+        );
+        assert_eq!(
+            statuses,
+            [
+                (String::from("echo a"), report::Status::Succeeded, 1, true),
+                (String::from("flaky"), report::Status::Cancelled, 1, true),
+                (String::from("echo c"), report::Status::Cancelled, 0, false),
+            ]
+        );
+    }
+
+    async fn run_flaky(step: FlakyStep) -> report::StepReport {
+        let mut graph = StepGraph::new();
+
+        let flaky = graph.add_node(step);
+
+        let mut report = graph
+            .run(
+                &workspace_spec(),
+                &instance_spec(),
+                flaky,
+                RunMode::Full,
+                &Interrupt::new(),
+            )
+            .await;
+
+        report.steps.remove(0)
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let step = run_flaky(flaky(2, 0, 3)).await;
+
+        assert_eq!(step.status, report::Status::Succeeded);
+        assert_eq!(step.attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let step = run_flaky(flaky(5, 0, 2)).await;
+
+        assert_eq!(step.status, report::Status::Failed);
+        assert_eq!(step.attempts, 2);
+        assert_eq!(step.error.as_deref(), Some("transient failure"));
+    }
+
+    #[tokio::test]
+    async fn times_out_hung_steps() {
+        let step = run_flaky(flaky(0, 10_000, 2)).await;
+
+        assert_eq!(step.status, report::Status::Failed);
+        assert_eq!(step.attempts, 2);
+        assert_eq!(step.error.as_deref(), Some("timed out after 200ms"));
+    }
+
+    #[tokio::test]
+    async fn reconcile_skips_satisfied_steps() {
+        let mut graph = StepGraph::new();
+
+        let a = graph.add_node(checked("a", Ok(true)));
+        let b = graph.add_node(checked("b", Ok(false)));
+
+        graph.add_edge(b, a).unwrap();
+
+        let report = graph
+            .run(
+                &workspace_spec(),
+                &instance_spec(),
+                b,
+                RunMode::Reconcile,
+                &Interrupt::new(),
+            )
+            .await;
+
+        let statuses: Vec<(String, report::Status, u32, bool)> = report
+            .steps
+            .iter()
+            .map(|s| (s.name.clone(), s.status, s.attempts, s.cleanup.is_some()))
+            .collect();
+
+        assert_eq!(report.status, report::Status::Succeeded);
+        assert_eq!(
+            statuses,
+            [
+                (String::from("a"), report::Status::Satisfied, 0, false),
+                (String::from("b"), report::Status::Succeeded, 1, true),
+            ]
+        );
+
+        // A full run ignores whether steps are satisfied.
+        let report = graph
+            .run(
+                &workspace_spec(),
+                &instance_spec(),
+                b,
+                RunMode::Full,
+                &Interrupt::new(),
+            )
+            .await;
+
+        assert!(
+            report
+                .steps
+                .iter()
+                .all(|s| s.status == report::Status::Succeeded)
+        );
+    }
+
+    #[tokio::test]
+    async fn reconcile_fails_steps_that_cannot_be_checked() {
+        let mut graph = StepGraph::new();
+
+        let a = graph.add_node(checked("a", Err("device busy")));
+        let b = graph.add_node(checked("b", Ok(false)));
+
+        graph.add_edge(b, a).unwrap();
+
+        let report = graph
+            .run(
+                &workspace_spec(),
+                &instance_spec(),
+                b,
+                RunMode::Reconcile,
+                &Interrupt::new(),
+            )
+            .await;
+
+        assert_eq!(report.status, report::Status::Failed);
+        assert_eq!(report.steps[0].status, report::Status::Failed);
+        assert_eq!(
+            report.steps[0].error.as_deref(),
+            Some("error checking whether the step is satisfied: device busy")
+        );
+        assert_eq!(report.steps[1].status, report::Status::Skipped);
+    }
+
+    #[test]
+    fn step_configs_override_policies() {
+        let mut graph = StepGraph::new();
+
+        let flaky = graph.add_node(flaky(0, 0, 3));
+        let echo = graph.add_node(steps::EchoStep { msg: "a" });
+
+        let step_configs = collections::HashMap::from([(
+            String::from("flaky"),
+            config::StepConfig {
+                timeout_secs: Some(5),
+                max_attempts: Some(1),
+                ..config::StepConfig::default()
+            },
+        )]);
+
+        graph.apply_step_configs(&step_configs);
+
+        assert_eq!(
+            graph.policy(flaky),
+            steps::StepPolicy {
+                timeout: Some(time::Duration::from_secs(5)),
+                max_attempts: 1,
+                retry_delay: time::Duration::from_millis(1),
+                max_retry_delay: time::Duration::from_millis(2),
+            }
+        );
+        assert_eq!(graph.policy(echo), steps::StepPolicy::default());
     }
 }
@@ -0,0 +1,660 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+
+use crate::config;
+
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+const MBR_PARTITION_TYPE_EMPTY: u8 = 0x00;
+const MBR_PARTITION_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: u32 = 92;
+const GPT_HEADER_CRC_OFFSET: usize = 16;
+const GPT_MAX_PARTITION_ENTRIES: u32 = 1024;
+const GPT_MIN_PARTITION_ENTRY_SIZE: u32 = 128;
+
+/// The sector sizes to try when looking for a GPT header. Images are almost always written with
+/// 512-byte sectors, but 4K-native images put the header at byte 4096 instead.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+const FAT_BOOT_SIGNATURE_OFFSET: usize = 510;
+const FAT16_FS_TYPE_OFFSET: usize = 54;
+const FAT32_FS_TYPE_OFFSET: usize = 82;
+
+const EXT_SUPERBLOCK_OFFSET: usize = 1024;
+const EXT_MAGIC_OFFSET: usize = EXT_SUPERBLOCK_OFFSET + 56;
+const EXT_MAGIC: [u8; 2] = [0x53, 0xef];
+
+/// The kind of partition table found in an image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TableKind {
+    Mbr,
+    Gpt,
+}
+
+impl fmt::Display for TableKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TableKind::Mbr => write!(f, "MBR"),
+            TableKind::Gpt => write!(f, "GPT"),
+        }
+    }
+}
+
+/// The kind of filesystem found at the start of a partition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilesystemKind {
+    Fat,
+    Ext,
+    Unknown,
+}
+
+impl fmt::Display for FilesystemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilesystemKind::Fat => write!(f, "FAT"),
+            FilesystemKind::Ext => write!(f, "ext2/3/4"),
+            FilesystemKind::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A partition found in an image.
+#[derive(Clone, Debug)]
+pub struct Partition {
+    /// The 1-based number of the partition in the partition table.
+    pub number: usize,
+    /// The offset of the partition from the start of the image in bytes.
+    pub offset: u64,
+    /// The size of the partition in bytes.
+    pub size: u64,
+    /// The filesystem found at the start of the partition.
+    pub filesystem: FilesystemKind,
+}
+
+/// The partitions of a Raspberry Pi OS image that are copied to an instance.
+#[derive(Clone, Debug)]
+pub struct ImageLayout {
+    /// The kind of partition table the image uses.
+    pub table: TableKind,
+    /// The offset of the FAT boot partition in bytes.
+    pub boot_offset: u64,
+    /// The offset of the ext4 root partition in bytes.
+    pub rootfs_offset: u64,
+}
+
+fn read_at(f: &mut fs::File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+
+    f.seek(io::SeekFrom::Start(offset))?;
+    f.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+fn u32_le(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(
+        buf[offset..offset + 4]
+            .try_into()
+            .expect("slice is 4 bytes"),
+    )
+}
+
+fn u64_le(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(
+        buf[offset..offset + 8]
+            .try_into()
+            .expect("slice is 8 bytes"),
+    )
+}
+
+/// Returns the CRC-32 checksum GPT uses for its header and partition entries, the one from
+/// IEEE 802.3.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+/// Returns the offset in bytes of the given sector, or an error if it doesn't fit in a `u64`.
+fn sector_offset(lba: u64, sector_size: u64) -> Result<u64, Box<dyn error::Error>> {
+    lba.checked_mul(sector_size)
+        .ok_or_else(|| format!("sector {} is beyond any image", lba).into())
+}
+
+fn probe_filesystem(f: &mut fs::File, offset: u64) -> io::Result<FilesystemKind> {
+    let buf = match read_at(f, offset, EXT_MAGIC_OFFSET + EXT_MAGIC.len()) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(FilesystemKind::Unknown),
+        Err(e) => return Err(e),
+    };
+
+    if buf[EXT_MAGIC_OFFSET..EXT_MAGIC_OFFSET + 2] == EXT_MAGIC {
+        return Ok(FilesystemKind::Ext);
+    }
+
+    let has_boot_signature =
+        buf[FAT_BOOT_SIGNATURE_OFFSET..FAT_BOOT_SIGNATURE_OFFSET + 2] == MBR_SIGNATURE;
+    let is_fat16 = &buf[FAT16_FS_TYPE_OFFSET..FAT16_FS_TYPE_OFFSET + 3] == b"FAT";
+    let is_fat32 = &buf[FAT32_FS_TYPE_OFFSET..FAT32_FS_TYPE_OFFSET + 5] == b"FAT32";
+
+    if has_boot_signature && (is_fat16 || is_fat32) {
+        return Ok(FilesystemKind::Fat);
+    }
+
+    Ok(FilesystemKind::Unknown)
+}
+
+/// Returns the partitions from a GPT at the given sector size, or `None` if there is no GPT header at
+/// that sector size. Filesystems are not probed.
+fn read_gpt(
+    f: &mut fs::File,
+    sector_size: u64,
+) -> Result<Option<Vec<Partition>>, Box<dyn error::Error>> {
+    // The header is read up to the end of its sector, which it must fit in.
+    let sector = match read_at(f, sector_size, sector_size as usize) {
+        Ok(sector) => sector,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if &sector[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let header_size = u32_le(&sector, 12);
+
+    if header_size < GPT_MIN_HEADER_SIZE || header_size as u64 > sector_size {
+        return Err(format!("invalid GPT header: header size of {} bytes", header_size).into());
+    }
+
+    let mut header = sector[..header_size as usize].to_vec();
+
+    let header_crc = u32_le(&header, GPT_HEADER_CRC_OFFSET);
+
+    // The checksum is computed with its own field zeroed.
+    header[GPT_HEADER_CRC_OFFSET..GPT_HEADER_CRC_OFFSET + 4].fill(0);
+
+    if crc32(&header) != header_crc {
+        return Err("invalid GPT header: checksum mismatch".into());
+    }
+
+    let entries_lba = u64_le(&header, 72);
+    let num_entries = u32_le(&header, 80);
+    let entry_size = u32_le(&header, 84);
+    let entries_crc = u32_le(&header, 88);
+
+    // Entries are 128 bytes times a power of two, and never span sectors.
+    let valid_entry_size = entry_size >= GPT_MIN_PARTITION_ENTRY_SIZE
+        && entry_size.is_power_of_two()
+        && entry_size as u64 <= sector_size;
+
+    if num_entries > GPT_MAX_PARTITION_ENTRIES || !valid_entry_size {
+        return Err(format!(
+            "invalid GPT header: {} entries of {} bytes",
+            num_entries, entry_size
+        )
+        .into());
+    }
+
+    let entry_size = entry_size as usize;
+
+    let entries = read_at(
+        f,
+        sector_offset(entries_lba, sector_size)?,
+        num_entries as usize * entry_size,
+    )?;
+
+    if crc32(&entries) != entries_crc {
+        return Err("invalid GPT partition entries: checksum mismatch".into());
+    }
+
+    let mut partitions = Vec::new();
+
+    for (i, entry) in entries.chunks_exact(entry_size).enumerate() {
+        // An all-zero partition type GUID marks an unused entry.
+        if entry[0..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        let first_lba = u64_le(entry, 32);
+        let last_lba = u64_le(entry, 40);
+
+        if last_lba < first_lba {
+            return Err(format!("invalid GPT entry {}: ends before it starts", i + 1).into());
+        }
+
+        let invalid = || format!("invalid GPT entry {}: beyond any image", i + 1);
+
+        partitions.push(Partition {
+            number: i + 1,
+            offset: sector_offset(first_lba, sector_size).map_err(|_| invalid())?,
+            size: sector_offset(last_lba - first_lba + 1, sector_size).map_err(|_| invalid())?,
+            filesystem: FilesystemKind::Unknown,
+        });
+    }
+
+    Ok(Some(partitions))
+}
+
+/// Reads the partition table of the image at the given path. Both MBR and GPT partition tables are
+/// supported.
+pub fn read_partitions(
+    img_path: &path::Path,
+) -> Result<(TableKind, Vec<Partition>), Box<dyn error::Error>> {
+    let mut f = fs::File::open(img_path)?;
+
+    let mbr = read_at(&mut f, 0, 512)?;
+
+    if mbr[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != MBR_SIGNATURE {
+        return Err("no partition table found: missing MBR boot signature".into());
+    }
+
+    let mbr_entries: Vec<&[u8]> = (0..4)
+        .map(|i| {
+            let entry_offset = MBR_PARTITION_TABLE_OFFSET + i * MBR_PARTITION_ENTRY_SIZE;
+
+            &mbr[entry_offset..entry_offset + MBR_PARTITION_ENTRY_SIZE]
+        })
+        .collect();
+
+    let is_gpt = mbr_entries
+        .iter()
+        .any(|entry| entry[4] == MBR_PARTITION_TYPE_GPT_PROTECTIVE);
+
+    let (table, mut partitions) = if is_gpt {
+        let mut gpt_partitions = None;
+
+        for sector_size in SECTOR_SIZES {
+            gpt_partitions = read_gpt(&mut f, sector_size)?;
+
+            if gpt_partitions.is_some() {
+                break;
+            }
+        }
+
+        let partitions = gpt_partitions.ok_or("protective MBR found but no GPT header at LBA 1")?;
+
+        (TableKind::Gpt, partitions)
+    } else {
+        // MBR partition tables always address 512-byte sectors.
+        let partitions = mbr_entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry[4] != MBR_PARTITION_TYPE_EMPTY)
+            .map(|(i, entry)| Partition {
+                number: i + 1,
+                offset: u32_le(entry, 8) as u64 * 512,
+                size: u32_le(entry, 12) as u64 * 512,
+                filesystem: FilesystemKind::Unknown,
+            })
+            .collect();
+
+        (TableKind::Mbr, partitions)
+    };
+
+    let img_len = f.metadata()?.len();
+
+    for partition in partitions.iter_mut() {
+        let end = partition
+            .offset
+            .checked_add(partition.size)
+            .ok_or_else(|| format!("partition {} ends beyond any image", partition.number))?;
+
+        if end > img_len {
+            return Err(format!(
+                "partition {} ends at byte {} but the image is only {} bytes; is it truncated?",
+                partition.number, end, img_len
+            )
+            .into());
+        }
+
+        partition.filesystem = probe_filesystem(&mut f, partition.offset)?;
+    }
+
+    Ok((table, partitions))
+}
+
+/// Chooses the partition with the given filesystem. If an offset override is given, it must be
+/// the start of a partition with that filesystem; otherwise the first such partition is chosen.
+fn choose_partition(
+    partitions: &[Partition],
+    filesystem: FilesystemKind,
+    override_offset: Option<u64>,
+    override_name: &str,
+) -> Result<u64, Box<dyn error::Error>> {
+    match override_offset {
+        Some(offset) => match partitions.iter().find(|p| p.offset == offset) {
+            Some(p) if p.filesystem == filesystem => Ok(offset),
+            Some(p) => Err(format!(
+                "{} {} is the start of partition {}, which has a {} filesystem instead of {}",
+                override_name, offset, p.number, p.filesystem, filesystem
+            )
+            .into()),
+            None => Err(format!(
+                "{} {} is not the start of any partition; partitions start at {}",
+                override_name,
+                offset,
+                partitions
+                    .iter()
+                    .map(|p| p.offset.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )
+            .into()),
+        },
+        None => partitions
+            .iter()
+            .find(|p| p.filesystem == filesystem)
+            .map(|p| p.offset)
+            .ok_or_else(|| format!("no partition with a {} filesystem found", filesystem).into()),
+    }
+}
+
+/// Determines the boot and root partition offsets of the workspace image from its partition table,
+/// cross-checking any offsets given explicitly in the workspace config.
+pub fn layout(
+    workspace_spec: &config::WorkspaceConfig,
+) -> Result<ImageLayout, Box<dyn error::Error>> {
+    let img_path = path::Path::new(&workspace_spec.img_path);
+
+    let result = read_partitions(img_path).and_then(|(table, partitions)| {
+        let boot_offset = choose_partition(
+            &partitions,
+            FilesystemKind::Fat,
+            workspace_spec.img_boot_offset,
+            "img_boot_offset",
+        )?;
+
+        let rootfs_offset = choose_partition(
+            &partitions,
+            FilesystemKind::Ext,
+            workspace_spec.img_rootfs_offset,
+            "img_rootfs_offset",
+        )?;
+
+        Ok(ImageLayout {
+            table,
+            boot_offset,
+            rootfs_offset,
+        })
+    });
+
+    result.map_err(|e| format!("error reading image {}: {}", workspace_spec.img_path, e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    /// The boot and root partitions of every test image, as offsets and sizes in bytes.
+    const BOOT: (usize, usize) = (MIB, 2 * MIB);
+    const ROOTFS: (usize, usize) = (3 * MIB, 4 * MIB);
+
+    /// Returns an 8MiB image with a FAT boot filesystem and an ext root filesystem, and no
+    /// partition table.
+    fn filesystems() -> Vec<u8> {
+        let mut img = vec![0u8; 8 * MIB];
+
+        let boot = BOOT.0;
+        img[boot + FAT32_FS_TYPE_OFFSET..boot + FAT32_FS_TYPE_OFFSET + 5].copy_from_slice(b"FAT32");
+        img[boot + FAT_BOOT_SIGNATURE_OFFSET..boot + FAT_BOOT_SIGNATURE_OFFSET + 2]
+            .copy_from_slice(&[0x55, 0xaa]);
+
+        let rootfs = ROOTFS.0;
+        img[rootfs + EXT_MAGIC_OFFSET..rootfs + EXT_MAGIC_OFFSET + 2].copy_from_slice(&EXT_MAGIC);
+
+        img
+    }
+
+    /// Writes an MBR partition table entry of the given type.
+    fn write_mbr_entry(img: &mut [u8], i: usize, kind: u8, start: u32, sectors: u32) {
+        let entry = MBR_PARTITION_TABLE_OFFSET + i * MBR_PARTITION_ENTRY_SIZE;
+
+        img[entry + 4] = kind;
+        img[entry + 8..entry + 12].copy_from_slice(&start.to_le_bytes());
+        img[entry + 12..entry + 16].copy_from_slice(&sectors.to_le_bytes());
+        img[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn mbr_image() -> Vec<u8> {
+        let mut img = filesystems();
+
+        for (i, (kind, (offset, size))) in [(0x0c, BOOT), (0x83, ROOTFS)].into_iter().enumerate() {
+            write_mbr_entry(
+                &mut img,
+                i,
+                kind,
+                (offset / 512) as u32,
+                (size / 512) as u32,
+            );
+        }
+
+        img
+    }
+
+    /// Returns an image with a GPT for the given sector size, with its entries at LBA 2.
+    fn gpt_image(sector_size: usize) -> Vec<u8> {
+        let mut img = filesystems();
+
+        write_mbr_entry(&mut img, 0, MBR_PARTITION_TYPE_GPT_PROTECTIVE, 1, u32::MAX);
+
+        let header = sector_size;
+        img[header..header + 8].copy_from_slice(GPT_SIGNATURE);
+        img[header + 12..header + 16].copy_from_slice(&GPT_MIN_HEADER_SIZE.to_le_bytes());
+        img[header + 72..header + 80].copy_from_slice(&2u64.to_le_bytes());
+        img[header + 80..header + 84].copy_from_slice(&128u32.to_le_bytes());
+        img[header + 84..header + 88].copy_from_slice(&128u32.to_le_bytes());
+
+        for (i, (offset, size)) in [BOOT, ROOTFS].into_iter().enumerate() {
+            let entry = 2 * sector_size + i * 128;
+
+            img[entry..entry + 16].fill(i as u8 + 1);
+            img[entry + 32..entry + 40]
+                .copy_from_slice(&((offset / sector_size) as u64).to_le_bytes());
+            img[entry + 40..entry + 48]
+                .copy_from_slice(&(((offset + size) / sector_size - 1) as u64).to_le_bytes());
+        }
+
+        update_gpt_checksums(&mut img, sector_size);
+
+        img
+    }
+
+    /// Recomputes the checksums of the GPT for the given sector size, after the header or entries
+    /// were changed.
+    fn update_gpt_checksums(img: &mut [u8], sector_size: usize) {
+        let header = sector_size;
+        let entries = 2 * sector_size;
+
+        let entries_crc = crc32(&img[entries..entries + 128 * 128]);
+        img[header + 88..header + 92].copy_from_slice(&entries_crc.to_le_bytes());
+
+        img[header + 16..header + 20].fill(0);
+        let header_crc = crc32(&img[header..header + GPT_MIN_HEADER_SIZE as usize]);
+        img[header + 16..header + 20].copy_from_slice(&header_crc.to_le_bytes());
+    }
+
+    /// Writes the image to a temporary workspace, and returns the workspace and its config.
+    fn workspace(img: &[u8]) -> (tempfile::TempDir, config::WorkspaceConfig) {
+        let dir = tempfile::tempdir().unwrap();
+
+        fs::write(dir.path().join("os.img"), img).unwrap();
+
+        let workspace_spec = config::fixtures::workspace_spec(dir.path());
+
+        (dir, workspace_spec)
+    }
+
+    fn assert_test_layout(img: &[u8], table: TableKind) {
+        let (_dir, workspace_spec) = workspace(img);
+
+        let (read_table, partitions) =
+            read_partitions(path::Path::new(&workspace_spec.img_path)).unwrap();
+
+        assert_eq!(read_table, table);
+        assert_eq!(
+            partitions
+                .iter()
+                .map(|p| (p.number, p.offset, p.size, p.filesystem))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, BOOT.0 as u64, BOOT.1 as u64, FilesystemKind::Fat),
+                (2, ROOTFS.0 as u64, ROOTFS.1 as u64, FilesystemKind::Ext),
+            ]
+        );
+
+        let layout = layout(&workspace_spec).unwrap();
+
+        assert_eq!(layout.table, table);
+        assert_eq!(layout.boot_offset, BOOT.0 as u64);
+        assert_eq!(layout.rootfs_offset, ROOTFS.0 as u64);
+    }
+
+    fn layout_err(img: &[u8]) -> String {
+        let (_dir, workspace_spec) = workspace(img);
+
+        layout(&workspace_spec).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn reads_mbr_partitions() {
+        assert_test_layout(&mbr_image(), TableKind::Mbr);
+    }
+
+    #[test]
+    fn reads_gpt_partitions() {
+        for sector_size in SECTOR_SIZES {
+            assert_test_layout(&gpt_image(sector_size as usize), TableKind::Gpt);
+        }
+    }
+
+    #[test]
+    fn rejects_offset_overrides_that_do_not_match() {
+        let (_dir, mut workspace_spec) = workspace(&mbr_image());
+
+        workspace_spec.img_boot_offset = Some(ROOTFS.0 as u64);
+
+        let err = layout(&workspace_spec).unwrap_err().to_string();
+
+        assert!(
+            err.ends_with(&format!(
+                "img_boot_offset {} is the start of partition 2, which has a ext2/3/4 filesystem instead of FAT",
+                ROOTFS.0
+            )),
+            "{}",
+            err
+        );
+
+        workspace_spec.img_boot_offset = None;
+        workspace_spec.img_rootfs_offset = Some(4 * MIB as u64);
+
+        let err = layout(&workspace_spec).unwrap_err().to_string();
+
+        assert!(
+            err.ends_with(&format!(
+                "img_rootfs_offset {} is not the start of any partition; partitions start at {}, {}",
+                4 * MIB,
+                BOOT.0,
+                ROOTFS.0
+            )),
+            "{}",
+            err
+        );
+
+        // Matching overrides are accepted.
+        workspace_spec.img_boot_offset = Some(BOOT.0 as u64);
+        workspace_spec.img_rootfs_offset = Some(ROOTFS.0 as u64);
+
+        assert_eq!(
+            layout(&workspace_spec).unwrap().rootfs_offset,
+            ROOTFS.0 as u64
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        for img in [mbr_image(), gpt_image(512)] {
+            let err = layout_err(&img[..5 * MIB]);
+
+            assert!(
+                err.ends_with(&format!(
+                    "partition 2 ends at byte {} but the image is only {} bytes; is it truncated?",
+                    ROOTFS.0 + ROOTFS.1,
+                    5 * MIB
+                )),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_gpt_headers() {
+        let sector_size = 512;
+        let header = sector_size;
+        let entries = 2 * sector_size;
+
+        // Bytes written over the image, and the error they cause.
+        let cases: [(usize, &[u8], &str); 6] = [
+            (header + 72, &[3], "invalid GPT header: checksum mismatch"),
+            (
+                entries + 32,
+                &[1],
+                "invalid GPT partition entries: checksum mismatch",
+            ),
+            (
+                header + 12,
+                &600u32.to_le_bytes(),
+                "invalid GPT header: header size of 600 bytes",
+            ),
+            (
+                header + 84,
+                &(1u32 << 31).to_le_bytes(),
+                "invalid GPT header: 128 entries of 2147483648 bytes",
+            ),
+            (
+                header + 84,
+                &192u32.to_le_bytes(),
+                "invalid GPT header: 128 entries of 192 bytes",
+            ),
+            (
+                entries + 40,
+                &u64::MAX.to_le_bytes(),
+                "invalid GPT entry 1: beyond any image",
+            ),
+        ];
+
+        for (offset, bytes, expected) in cases {
+            let mut img = gpt_image(sector_size);
+
+            img[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+            // The checksum cases leave the checksums stale; the others are checked past them.
+            if !expected.contains("checksum") {
+                update_gpt_checksums(&mut img, sector_size);
+            }
+
+            let err = layout_err(&img);
+
+            assert!(err.ends_with(expected), "{}: {}", expected, err);
+        }
+    }
+}
@@ -1,59 +1,1339 @@
+use std::sync;
+
+use futures::stream;
+use futures::stream::StreamExt;
+
+mod cmdline;
 pub mod config;
+mod copy;
+pub mod exec;
+mod fstab;
 pub mod graph;
+mod image;
+pub mod lock;
+pub mod report;
 mod steps;
+pub mod validate;
 
-/// Provisions instances as defined by all configs associated with the given workspace.
-pub async fn run(
-    workspace_spec: &config::WorkspaceConfig,
-    instance_specs: &Vec<config::InstanceConfig>,
-) -> Vec<Result<(), graph::StepError>> {
+/// An operation that can be performed on instances. Each operation is defined by its own graph of
+/// steps.
+#[derive(clap::ValueEnum, serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Writes the OS image to an instance and configures it to boot.
+    Provision,
+    /// Checks that an instance is configured as it would be after provisioning.
+    Verify,
+    /// Wipes the boot directory and root filesystem of an instance.
+    Deprovision,
+    /// Tears down the mounts and iSCSI sessions an interrupted or crashed provision left behind,
+    /// without running any steps. Its graph is the provision graph.
+    #[value(skip)]
+    Cleanup,
+}
+
+impl Operation {
+    /// Builds the graph of steps for this operation, with steps performing system operations
+    /// through the given executor. Returns the graph and the ID of the node that signals the
+    /// completion of the operation.
+    pub fn graph(
+        &self,
+        exec: sync::Arc<dyn exec::Executor>,
+    ) -> Result<(graph::StepGraph, usize), graph::GraphError> {
+        match self {
+            Operation::Provision | Operation::Cleanup => provision_graph(exec),
+            Operation::Verify => verify_graph(exec),
+            Operation::Deprovision => deprovision_graph(exec),
+        }
+    }
+}
+
+fn provision_graph(
+    exec: sync::Arc<dyn exec::Executor>,
+) -> Result<(graph::StepGraph, usize), graph::GraphError> {
     let mut graph = graph::StepGraph::new();
 
     let mkdir_step = graph.add_node(steps::MkdirStep {});
 
-    let mount_boot_step = graph.add_node(steps::MountBootStep {});
+    let mount_boot_step = graph.add_node(steps::MountBootStep { exec: exec.clone() });
 
-    let login_iscsi_step = graph.add_node(steps::LoginIscsiStep {});
+    let wipe_boot_step = graph.add_node(steps::WipeBootStep {});
 
-    let prepare_rootfs_step = graph.add_node(steps::PrepareRootfsStep {});
+    let login_iscsi_step = graph.add_node(steps::LoginIscsiStep { exec: exec.clone() });
 
-    let copy_data_step = graph.add_node(steps::CopyDataStep {});
+    let prepare_rootfs_step = graph.add_node(steps::PrepareRootfsStep { exec: exec.clone() });
 
-    let update_cmdline_step = graph.add_node(steps::UpdateCmdlineStep {});
+    let mount_rootfs_step = graph.add_node(steps::MountRootfsStep { exec: exec.clone() });
 
-    let configure_hostname_step = graph.add_node(steps::ConfigureHostnameStep {});
+    let copy_data_step = graph.add_node(steps::CopyDataStep::new(exec.clone()));
+
+    let update_cmdline_step = graph.add_node(steps::UpdateCmdlineStep { exec: exec.clone() });
+
+    let configure_hostname_step =
+        graph.add_node(steps::ConfigureHostnameStep { exec: exec.clone() });
 
     let configure_user_auth_step = graph.add_node(steps::ConfigureUserAuthStep {});
 
     let finish_step = graph.add_node(steps::FinishStep {});
 
-    graph.add_edge(finish_step, update_cmdline_step);
-    graph.add_edge(finish_step, configure_hostname_step);
-    graph.add_edge(finish_step, configure_user_auth_step);
+    graph.add_edge(finish_step, update_cmdline_step)?;
+    graph.add_edge(finish_step, configure_hostname_step)?;
+    graph.add_edge(finish_step, configure_user_auth_step)?;
+
+    graph.add_edge(configure_user_auth_step, copy_data_step)?;
+
+    graph.add_edge(configure_hostname_step, copy_data_step)?;
+
+    graph.add_edge(update_cmdline_step, copy_data_step)?;
+
+    graph.add_edge(copy_data_step, mount_rootfs_step)?;
+    graph.add_edge(copy_data_step, wipe_boot_step)?;
+
+    graph.add_edge(wipe_boot_step, mount_boot_step)?;
+
+    graph.add_edge(mount_boot_step, mkdir_step)?;
+
+    graph.add_edge(mount_rootfs_step, prepare_rootfs_step)?;
+
+    graph.add_edge(prepare_rootfs_step, login_iscsi_step)?;
+    graph.add_edge(prepare_rootfs_step, mkdir_step)?;
+
+    graph.validate(finish_step)?;
+
+    Ok((graph, finish_step))
+}
+
+fn verify_graph(
+    exec: sync::Arc<dyn exec::Executor>,
+) -> Result<(graph::StepGraph, usize), graph::GraphError> {
+    let mut graph = graph::StepGraph::new();
+
+    let mkdir_step = graph.add_node(steps::MkdirStep {});
+
+    let mount_boot_step = graph.add_node(steps::MountBootStep { exec: exec.clone() });
+
+    let login_iscsi_step = graph.add_node(steps::LoginIscsiStep { exec: exec.clone() });
+
+    let mount_rootfs_step = graph.add_node(steps::MountRootfsStep { exec: exec.clone() });
+
+    let verify_step = graph.add_node(steps::VerifyStep {});
+
+    let finish_step = graph.add_node(steps::FinishStep {});
+
+    graph.add_edge(finish_step, verify_step)?;
+
+    graph.add_edge(verify_step, mount_boot_step)?;
+    graph.add_edge(verify_step, mount_rootfs_step)?;
+
+    graph.add_edge(mount_boot_step, mkdir_step)?;
+
+    graph.add_edge(mount_rootfs_step, login_iscsi_step)?;
+    graph.add_edge(mount_rootfs_step, mkdir_step)?;
+
+    graph.validate(finish_step)?;
+
+    Ok((graph, finish_step))
+}
+
+fn deprovision_graph(
+    exec: sync::Arc<dyn exec::Executor>,
+) -> Result<(graph::StepGraph, usize), graph::GraphError> {
+    let mut graph = graph::StepGraph::new();
+
+    let mkdir_step = graph.add_node(steps::MkdirStep {});
+
+    let mount_boot_step = graph.add_node(steps::MountBootStep { exec: exec.clone() });
+
+    let wipe_boot_step = graph.add_node(steps::WipeBootStep {});
+
+    let login_iscsi_step = graph.add_node(steps::LoginIscsiStep { exec: exec.clone() });
+
+    let wipe_rootfs_step = graph.add_node(steps::WipeRootfsStep { exec: exec.clone() });
+
+    let finish_step = graph.add_node(steps::FinishStep {});
+
+    graph.add_edge(finish_step, wipe_boot_step)?;
+    graph.add_edge(finish_step, wipe_rootfs_step)?;
 
-    graph.add_edge(configure_user_auth_step, copy_data_step);
+    graph.add_edge(wipe_boot_step, mount_boot_step)?;
 
-    graph.add_edge(configure_hostname_step, copy_data_step);
+    graph.add_edge(mount_boot_step, mkdir_step)?;
 
-    graph.add_edge(update_cmdline_step, copy_data_step);
+    graph.add_edge(wipe_rootfs_step, login_iscsi_step)?;
 
-    graph.add_edge(copy_data_step, prepare_rootfs_step);
-    graph.add_edge(copy_data_step, mount_boot_step);
+    graph.validate(finish_step)?;
+
+    Ok((graph, finish_step))
+}
+
+/// Performs the given operation on each of the given instances through the given executor. In
+/// reconcile mode, only the steps whose desired state does not already hold are run. Once
+/// interrupted, no further steps start, and instances that have not started are cancelled. Returns
+/// a report of the outcome of each step for each instance, or an error if the operation's graph is
+/// invalid.
+pub async fn run(
+    operation: Operation,
+    mode: graph::RunMode,
+    workspace_spec: &config::WorkspaceConfig,
+    instance_specs: &[config::InstanceConfig],
+    exec: sync::Arc<dyn exec::Executor>,
+    interrupt: &graph::Interrupt,
+) -> Result<report::RunReport, graph::GraphError> {
+    let started_at = chrono::Utc::now();
 
-    graph.add_edge(mount_boot_step, mkdir_step);
+    let (mut graph, finish_step) = operation.graph(exec)?;
 
-    graph.add_edge(prepare_rootfs_step, login_iscsi_step);
-    graph.add_edge(prepare_rootfs_step, mkdir_step);
+    graph.apply_step_configs(&workspace_spec.steps);
 
     // While in theory we can run all provisions concurrently, in practice this swamps the NAS and
-    // causes odd behavior like iSCSI timeouts. Thus, we provision each machine serially instead.
-    let mut results = Vec::with_capacity(instance_specs.len());
+    // causes odd behavior like iSCSI timeouts. Thus, we only run as many instances at once as the
+    // workspace allows, and rely on per-step limits to keep heavy steps like copying data serial.
+    let max_parallel_instances = workspace_spec.max_parallel_instances.max(1);
+
+    let instances = stream::iter(instance_specs)
+        .map(async |spec| match operation {
+            Operation::Cleanup => graph.cleanup(workspace_spec, spec, finish_step).await,
+            _ => {
+                graph
+                    .run(workspace_spec, spec, finish_step, mode, interrupt)
+                    .await
+            }
+        })
+        .buffered(max_parallel_instances)
+        .collect()
+        .await;
+
+    Ok(report::RunReport {
+        operation,
+        run: report::Timing::since(started_at),
+        instances,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path;
+
+    /// Writes a sparse image with an MBR partition table, a FAT boot partition at 1 MiB and an
+    /// ext4 root partition at 3 MiB. Only the signatures the partition detection looks for are
+    /// written.
+    fn write_image(path: &path::Path) {
+        let mut img = vec![0u8; 8 * 1024 * 1024];
+
+        for (i, (kind, start, sectors)) in [(0x0c_u8, 2048_u32, 4096_u32), (0x83, 6144, 8192)]
+            .iter()
+            .enumerate()
+        {
+            let entry = 446 + i * 16;
+
+            img[entry + 4] = *kind;
+            img[entry + 8..entry + 12].copy_from_slice(&start.to_le_bytes());
+            img[entry + 12..entry + 16].copy_from_slice(&sectors.to_le_bytes());
+        }
+
+        img[510..512].copy_from_slice(&[0x55, 0xaa]);
+
+        let boot = 2048 * 512;
+        img[boot + 82..boot + 87].copy_from_slice(b"FAT32");
+        img[boot + 510..boot + 512].copy_from_slice(&[0x55, 0xaa]);
+
+        let rootfs = 6144 * 512;
+        img[rootfs + 1080..rootfs + 1082].copy_from_slice(&[0x53, 0xef]);
+
+        fs::write(path, img).unwrap();
+    }
+
+    struct Fixture {
+        _dir: tempfile::TempDir,
+        root: path::PathBuf,
+        workspace_spec: config::WorkspaceConfig,
+        instance_spec: config::InstanceConfig,
+        exec: sync::Arc<exec::FakeExecutor>,
+    }
+
+    impl Fixture {
+        /// Sets up a workspace in a temporary directory whose image contains a minimal Raspberry
+        /// Pi OS boot and root filesystem, and an executor that mounts them.
+        fn new(steps: serde_json::Value) -> Fixture {
+            let dir = tempfile::tempdir().unwrap();
+            let root = dir.path().to_path_buf();
+
+            write_image(&root.join("os.img"));
 
-    for spec in instance_specs {
-        let result = graph.run(workspace_spec, spec, finish_step).await;
+            let boot = root.join("image/boot");
+            let rootfs = root.join("image/rootfs");
 
-        results.push(result);
+            fs::create_dir_all(&boot).unwrap();
+            fs::create_dir_all(rootfs.join("etc")).unwrap();
+            fs::create_dir_all(rootfs.join("root/.ssh")).unwrap();
+
+            fs::write(
+                boot.join("cmdline.txt"),
+                "console=tty1 root=PARTUUID=abcd1234-02 rootfstype=ext4 fsck.repair=yes ro rootwait\n",
+            )
+            .unwrap();
+            fs::write(
+                rootfs.join("etc/fstab"),
+                "PARTUUID=abcd1234-01  /boot/firmware  vfat    defaults          0       2\nPARTUUID=abcd1234-02  /               ext4    defaults,noatime  0       1\n",
+            )
+            .unwrap();
+            fs::write(rootfs.join("etc/hosts"), "127.0.1.1\traspberrypi\n").unwrap();
+
+            let mut workspace_json = config::fixtures::workspace_json(&root.join("ws"));
+
+            workspace_json["img_path"] = serde_json::json!(root.join("os.img"));
+            workspace_json["kernel_params"] =
+                serde_json::json!(["cgroup_enable=memory", "console=ttyAMA0"]);
+            workspace_json["steps"] = steps;
+
+            let workspace_spec: config::WorkspaceConfig =
+                serde_json::from_value(workspace_json).unwrap();
+
+            let mut instance_json = config::fixtures::instance_json("node1", "dc-a6-32-00-00-01");
+
+            instance_json["kernel_params"] = serde_json::json!(["console=serial0,115200"]);
+
+            let instance_spec: config::InstanceConfig =
+                serde_json::from_value(instance_json).unwrap();
+
+            let exec = sync::Arc::new(exec::FakeExecutor::new());
+
+            let mount = root.join("ws/node1/mount");
+
+            exec.mount_contents(&mount.join("img/boot"), &boot);
+            exec.mount_contents(&mount.join("img/rootfs"), &rootfs);
+
+            exec.respond(
+                "lsblk -n -o NAME /dev/disk/by-path/ip-10.0.0.2:3260-iscsi-iqn.2024-01.test:target-node1-lun-1-part1",
+                "sdb1\n",
+            );
+            exec.respond(
+                "lsblk -n -o PARTUUID /dev/disk/by-path/ip-10.0.0.2:3260-iscsi-iqn.2024-01.test:target-node1-lun-1-part1",
+                "5e3da7a1-01\n",
+            );
+
+            Fixture {
+                _dir: dir,
+                root,
+                workspace_spec,
+                instance_spec,
+                exec,
+            }
+        }
+
+        /// Reaches the instance's target through two portals, whose paths the executor combines
+        /// into a multipath device, and adds multipath boot support to the image.
+        fn use_multipath(&mut self) {
+            self.workspace_spec.iscsi_target_ip = None;
+            self.workspace_spec.iscsi_portals = serde_json::from_value(serde_json::json!([
+                {"name": "nas-a", "host": "10.0.0.2"},
+                {"name": "nas-b", "host": "10.1.0.2"},
+            ]))
+            .unwrap();
+            self.instance_spec.iscsi_portals = vec![String::from("nas-a"), String::from("nas-b")];
+
+            self.exec.respond(
+                &format!("/lib/udev/scsi_id --whitelisted --device {}", DEV),
+                "36001405aabbccdd\n",
+            );
+            self.exec.respond(
+                "lsblk -n -o NAME /dev/disk/by-id/dm-uuid-part1-mpath-36001405aabbccdd",
+                "dm-1\n",
+            );
+
+            let script = self
+                .root
+                .join("image/rootfs/etc/initramfs-tools/scripts/local-top/iscsi-multipath");
+
+            fs::create_dir_all(script.parent().unwrap()).unwrap();
+            fs::write(&script, "#!/bin/sh\n").unwrap();
+        }
+
+        fn path(&self, relative: &str) -> String {
+            self.root.join(relative).to_str().unwrap().to_string()
+        }
+
+        async fn run(&self, operation: Operation) -> report::InstanceReport {
+            self.run_mode(operation, graph::RunMode::Full).await
+        }
+
+        async fn run_mode(
+            &self,
+            operation: Operation,
+            mode: graph::RunMode,
+        ) -> report::InstanceReport {
+            let report = run(
+                operation,
+                mode,
+                &self.workspace_spec,
+                std::slice::from_ref(&self.instance_spec),
+                self.exec.clone(),
+                &graph::Interrupt::new(),
+            )
+            .await
+            .unwrap();
+
+            report.instances.into_iter().next().unwrap()
+        }
+    }
+
+    const DEV: &str =
+        "/dev/disk/by-path/ip-10.0.0.2:3260-iscsi-iqn.2024-01.test:target-node1-lun-1";
+
+    /// Returns what `parted --machine print` prints for a 10GiB LUN with a GPT partition table
+    /// and partitions with the given starts and sizes in MiB. A partition without a size fills the
+    /// rest of the LUN up to the backup GPT, as parted creates it.
+    fn parted_table(partitions: &[(u64, Option<u64>)]) -> String {
+        const MIB: u64 = 1024 * 1024;
+
+        let disk_size = 10 * 1024 * MIB;
+
+        let mut table = format!(
+            "BYT;\n/dev/sdb:{}B:scsi:512:512:gpt:LIO-ORG disk:;\n",
+            disk_size
+        );
+
+        for (i, (start_mib, size_mib)) in partitions.iter().enumerate() {
+            let start = start_mib * MIB;
+            let size = match size_mib {
+                Some(size_mib) => size_mib * MIB,
+                None => disk_size - MIB - start,
+            };
+
+            table.push_str(&format!(
+                "{}:{}B:{}B:{}B:ext4::;\n",
+                i + 1,
+                start,
+                start + size - 1,
+                size
+            ));
+        }
+
+        table
+    }
+
+    #[tokio::test]
+    async fn provisions_instance() {
+        let f = Fixture::new(serde_json::json!({}));
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        assert_eq!(
+            f.exec.calls_starting_with("iscsiadm"),
+            [
+                "iscsiadm --mode session",
+                "iscsiadm --mode discovery --portal 10.0.0.2:3260 --type sendtargets",
+                "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal 10.0.0.2:3260 --login",
+                "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal 10.0.0.2:3260 --logout",
+            ]
+        );
+
+        assert_eq!(
+            f.exec.calls_starting_with("parted"),
+            [
+                format!("parted --script {} mklabel gpt", DEV),
+                format!(
+                    "parted --script --align optimal {} mkpart primary 0% 100%",
+                    DEV
+                ),
+            ]
+        );
+
+        assert_eq!(
+            f.exec.calls_starting_with("mkfs"),
+            [format!("mkfs -t ext4 {}-part1", DEV)]
+        );
+
+        assert_eq!(
+            f.exec.calls_starting_with("lsblk"),
+            [
+                format!("lsblk -n -o NAME {}-part1", DEV),
+                format!("lsblk -n -o PARTUUID {}-part1", DEV),
+            ]
+        );
+
+        assert_eq!(
+            f.exec.calls_starting_with("sed"),
+            [format!(
+                "sed -i -r -e 's/(.*)raspberrypi(.*?)$/\\1node1\\2/g' {}",
+                f.path("ws/node1/mount/instance/rootfs/etc/hosts")
+            )]
+        );
+
+        let mut mount_calls = f.exec.calls_starting_with("mount");
+        mount_calls.sort();
+
+        assert_eq!(
+            mount_calls,
+            [
+                format!(
+                    "mount -o ro,loop,offset=1048576 {} {}",
+                    f.path("os.img"),
+                    f.path("ws/node1/mount/img/boot")
+                ),
+                format!(
+                    "mount -o ro,loop,offset=3145728 {} {}",
+                    f.path("os.img"),
+                    f.path("ws/node1/mount/img/rootfs")
+                ),
+                format!(
+                    "mount -t ext4 -o noatime /dev/sdb1 {}",
+                    f.path("ws/node1/mount/instance/rootfs")
+                ),
+                format!(
+                    "mount -t nfs -o addr=10.0.0.3 :/tftp/dc-a6-32-00-00-01 {}",
+                    f.path("ws/node1/mount/instance/boot")
+                ),
+            ]
+        );
+
+        // Cleaning up copy data unmounts the image partitions again, in case a cancelled copy left
+        // them mounted.
+        assert_eq!(f.exec.calls_starting_with("umount").len(), 6);
+
+        // The image contents and instance configuration end up on the instance's filesystems.
+        let instance = f.root.join("ws/node1/mount/instance");
+
+        // The workspace's and instance's consoles are added to the image's, in that order, so the
+        // instance's becomes /dev/console.
+        assert_eq!(
+            fs::read_to_string(instance.join("boot/cmdline.txt")).unwrap(),
+            "console=tty1 console=ttyAMA0 console=serial0,115200 root=PARTUUID=5e3da7a1-01 rootfstype=ext4 fsck.repair=yes rootwait ip=dhcp ISCSI_INITIATOR=iqn.2024-01.test:node1 ISCSI_TARGET_NAME=iqn.2024-01.test:target-node1 ISCSI_TARGET_IP=10.0.0.2 ISCSI_TARGET_PORT=3260 rw cgroup_enable=memory\n",
+        );
+        assert_eq!(
+            fs::read_to_string(instance.join("rootfs/etc/fstab")).unwrap(),
+            "10.0.0.3:/tftp/dc-a6-32-00-00-01 /boot/firmware nfs defaults,vers=4.1,proto=tcp 0 0\nPARTUUID=5e3da7a1-01 / ext4 _netdev,noatime 0 1\n",
+        );
+        assert_eq!(
+            fs::read_to_string(instance.join("rootfs/etc/hostname")).unwrap(),
+            "node1\n"
+        );
+        assert_eq!(
+            fs::read_to_string(instance.join("rootfs/root/.ssh/authorized_keys")).unwrap(),
+            format!("{}\n", config::fixtures::SSH_KEY)
+        );
+        assert_eq!(
+            fs::read_to_string(instance.join("boot/userconf.txt")).unwrap(),
+            format!("{}\n", config::fixtures::USER_PASSWORD)
+        );
+
+        // The image mounts are emptied again when unmounted.
+        assert!(!f.root.join("ws/node1/mount/img").exists());
+    }
+
+    #[tokio::test]
+    async fn creates_configured_root_filesystem() {
+        let mut f = Fixture::new(serde_json::json!({}));
+
+        f.instance_spec.rootfs = Some(
+            serde_json::from_value(serde_json::json!({
+                "type": "xfs",
+                "label": "node1-root",
+                "mkfs_options": ["-m", "reflink=1"],
+                "mount_options": ["noatime", "logbsize=256k"],
+            }))
+            .unwrap(),
+        );
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        assert_eq!(
+            f.exec.calls_starting_with("mkfs"),
+            [format!(
+                "mkfs -t xfs -f -L node1-root -m reflink=1 {}-part1",
+                DEV
+            )]
+        );
+        assert_eq!(
+            f.exec.calls_starting_with("mount -t xfs"),
+            [format!(
+                "mount -t xfs -o noatime,logbsize=256k /dev/sdb1 {}",
+                f.path("ws/node1/mount/instance/rootfs")
+            )]
+        );
+
+        let instance = f.root.join("ws/node1/mount/instance");
+
+        let fstab = fs::read_to_string(instance.join("rootfs/etc/fstab")).unwrap();
+
+        assert!(
+            fstab.contains("PARTUUID=5e3da7a1-01 / xfs _netdev,noatime,logbsize=256k 0 0\n"),
+            "{}",
+            fstab
+        );
+
+        let cmdline = fs::read_to_string(instance.join("boot/cmdline.txt")).unwrap();
+
+        assert!(cmdline.contains(" rootfstype=xfs "), "{}", cmdline);
+
+        // Reconciling checks for the configured layout, filesystem type and label.
+        f.exec
+            .respond("parted --script --machine", &parted_table(&[(1, None)]));
+        f.exec.respond("lsblk -n -o FSTYPE", "xfs\n");
+        f.exec.respond("lsblk -n -o LABEL", "node1-root\n");
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        assert_eq!(report.steps[4].name, "prepare rootfs");
+        assert_eq!(report.steps[4].status, report::Status::Satisfied);
     }
 
-    results
+    #[tokio::test]
+    async fn creates_configured_partitions() {
+        let mut f = Fixture::new(serde_json::json!({}));
+
+        f.instance_spec.disk = serde_json::from_value(serde_json::json!({
+            "lun": 2,
+            "partitions": [
+                {"mount_point": "/", "size": "8GiB"},
+                {"mount_point": "/var", "size": "4GiB", "type": "xfs"},
+                {"size": "1GiB", "type": "swap", "label": "swap"},
+                {"mount_point": "/srv/data", "type": "btrfs"},
+            ],
+        }))
+        .unwrap();
+
+        let dev = "/dev/disk/by-path/ip-10.0.0.2:3260-iscsi-iqn.2024-01.test:target-node1-lun-2";
+
+        for n in 1..=4 {
+            f.exec.respond(
+                &format!("lsblk -n -o NAME {}-part{}", dev, n),
+                &format!("sdc{}\n", n),
+            );
+            f.exec.respond(
+                &format!("lsblk -n -o PARTUUID {}-part{}", dev, n),
+                &format!("5e3da7a1-0{}\n", n),
+            );
+        }
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        let mkpart = |start: &str, end: &str| {
+            format!(
+                "parted --script --align optimal {} mkpart primary {} {}",
+                dev, start, end
+            )
+        };
+
+        assert_eq!(
+            f.exec.calls_starting_with("parted"),
+            [
+                format!("parted --script {} mklabel gpt", dev),
+                mkpart("0%", "8193MiB"),
+                mkpart("8193MiB", "12289MiB"),
+                mkpart("12289MiB", "13313MiB"),
+                mkpart("13313MiB", "100%"),
+            ]
+        );
+
+        assert_eq!(
+            f.exec.calls_starting_with("mk"),
+            [
+                format!("mkfs -t ext4 {}-part1", dev),
+                format!("mkfs -t xfs -f {}-part2", dev),
+                format!("mkswap -L swap {}-part3", dev),
+                format!("mkfs -t btrfs -f {}-part4", dev),
+            ]
+        );
+
+        // Partitions are mounted inside the root filesystem, and unmounted before it.
+        let rootfs = f.path("ws/node1/mount/instance/rootfs");
+
+        let partition_calls: Vec<String> = f
+            .exec
+            .calls()
+            .into_iter()
+            .filter(|c| c.contains("/dev/sdc") || c.starts_with(&format!("umount {}", rootfs)))
+            .collect();
+
+        assert_eq!(
+            partition_calls,
+            [
+                format!("mount -t ext4 -o noatime /dev/sdc1 {}", rootfs),
+                format!("mount -t xfs -o noatime /dev/sdc2 {}/var", rootfs),
+                format!("mount -t btrfs -o noatime /dev/sdc4 {}/srv/data", rootfs),
+                format!("umount {}/srv/data", rootfs),
+                format!("umount {}/var", rootfs),
+                format!("umount {}", rootfs),
+            ]
+        );
+
+        let instance = f.root.join("ws/node1/mount/instance");
+
+        assert_eq!(
+            fs::read_to_string(instance.join("rootfs/etc/fstab")).unwrap(),
+            "10.0.0.3:/tftp/dc-a6-32-00-00-01 /boot/firmware nfs defaults,vers=4.1,proto=tcp 0 0
+PARTUUID=5e3da7a1-01 / ext4 _netdev,noatime 0 1
+PARTUUID=5e3da7a1-02 /var xfs _netdev,noatime 0 0
+PARTUUID=5e3da7a1-03 none swap sw 0 0
+PARTUUID=5e3da7a1-04 /srv/data btrfs _netdev,noatime 0 0
+",
+        );
+
+        let cmdline = fs::read_to_string(instance.join("boot/cmdline.txt")).unwrap();
+
+        assert!(
+            cmdline.contains(" root=PARTUUID=5e3da7a1-01 rootfstype=ext4 "),
+            "{}",
+            cmdline
+        );
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_chap() {
+        let mut f = Fixture::new(serde_json::json!({}));
+
+        f.instance_spec.chap = Some(config::ChapCredentials {
+            username: String::from("node1"),
+            password: String::from("initiator-secret"),
+        });
+        f.instance_spec.mutual_chap = Some(config::ChapCredentials {
+            username: String::from("nas"),
+            password: String::from("target-secret"),
+        });
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        let node = "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal 10.0.0.2:3260";
+
+        assert_eq!(
+            f.exec.calls_starting_with("iscsiadm"),
+            [
+                String::from("iscsiadm --mode session"),
+                String::from("iscsiadm --mode discovery --portal 10.0.0.2:3260 --type sendtargets"),
+                format!(
+                    "{} --op update --name node.session.auth.authmethod --value CHAP",
+                    node
+                ),
+                format!(
+                    "{} --op update --name node.session.auth.username --value node1",
+                    node
+                ),
+                format!(
+                    "{} --op update --name node.session.auth.password --value initiator-secret",
+                    node
+                ),
+                format!(
+                    "{} --op update --name node.session.auth.username_in --value nas",
+                    node
+                ),
+                format!(
+                    "{} --op update --name node.session.auth.password_in --value target-secret",
+                    node
+                ),
+                format!("{} --login", node),
+                format!("{} --logout", node),
+            ]
+        );
+
+        let cmdline =
+            fs::read_to_string(f.root.join("ws/node1/mount/instance/boot/cmdline.txt")).unwrap();
+
+        assert!(
+            cmdline.contains(" ISCSI_TARGET_IP=10.0.0.2 ISCSI_TARGET_PORT=3260 ISCSI_USERNAME=node1 ISCSI_PASSWORD=initiator-secret ISCSI_IN_USERNAME=nas ISCSI_IN_PASSWORD=target-secret rw "),
+            "{}",
+            cmdline
+        );
+
+        // Passwords are left out of the plan.
+        let (graph, finish_step) = Operation::Provision.graph(f.exec.clone()).unwrap();
+
+        let actions: Vec<String> = graph
+            .plan(&f.workspace_spec, &f.instance_spec, finish_step)
+            .unwrap()
+            .into_iter()
+            .flat_map(|step| step.actions)
+            .collect();
+
+        for password in ["initiator-secret", "target-secret"] {
+            assert!(
+                actions.iter().all(|a| !a.contains(password)),
+                "{:?}",
+                actions
+            );
+        }
+        assert!(
+            actions
+                .iter()
+                .any(|a| a.contains(" ISCSI_PASSWORD=REDACTED "))
+        );
+        assert!(actions.iter().any(|a| {
+            a.ends_with("--op update --name node.session.auth.password_in --value REDACTED")
+        }));
+
+        // And out of the config shown by show-config.
+        let shown = serde_json::to_string(&f.instance_spec).unwrap();
+
+        for password in ["initiator-secret", "target-secret"] {
+            assert!(!shown.contains(password), "{}", shown);
+        }
+
+        assert!(shown.contains(r#""password":"REDACTED""#), "{}", shown);
+    }
+
+    #[tokio::test]
+    async fn uses_configured_portal() {
+        let mut f = Fixture::new(serde_json::json!({}));
+
+        f.workspace_spec.iscsi_target_ip = None;
+        f.workspace_spec.iscsi_portals = serde_json::from_value(serde_json::json!([
+            {"name": "nas1", "host": "10.0.0.2"},
+            {"name": "nas2", "host": "fd00::2", "port": 3261},
+        ]))
+        .unwrap();
+        f.instance_spec.iscsi_portal = Some(String::from("nas2"));
+
+        let dev = "/dev/disk/by-path/ip-fd00::2:3261-iscsi-iqn.2024-01.test:target-node1-lun-1";
+
+        f.exec
+            .respond(&format!("lsblk -n -o NAME {}-part1", dev), "sdb1\n");
+        f.exec.respond(
+            &format!("lsblk -n -o PARTUUID {}-part1", dev),
+            "5e3da7a1-01\n",
+        );
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        assert_eq!(
+            f.exec.calls_starting_with("iscsiadm"),
+            [
+                "iscsiadm --mode session",
+                "iscsiadm --mode discovery --portal [fd00::2]:3261 --type sendtargets",
+                "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal [fd00::2]:3261 --login",
+                "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal [fd00::2]:3261 --logout",
+            ]
+        );
+        assert_eq!(
+            f.exec.calls_starting_with("mkfs"),
+            [format!("mkfs -t ext4 {}-part1", dev)]
+        );
+
+        let cmdline =
+            fs::read_to_string(f.root.join("ws/node1/mount/instance/boot/cmdline.txt")).unwrap();
+
+        assert!(
+            cmdline.contains(" ISCSI_TARGET_IP=fd00::2 ISCSI_TARGET_PORT=3261 "),
+            "{}",
+            cmdline
+        );
+
+        let report = f.run(Operation::Verify).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+    }
+
+    #[tokio::test]
+    async fn provisions_multipath_instance() {
+        let mut f = Fixture::new(serde_json::json!({}));
+
+        f.use_multipath();
+
+        let dm_dev = "/dev/disk/by-id/dm-uuid-mpath-36001405aabbccdd";
+        let dm_part = "/dev/disk/by-id/dm-uuid-part1-mpath-36001405aabbccdd";
+
+        f.exec.respond(
+            &format!("lsblk -n -o PARTUUID {}", dm_part),
+            "5e6f7a8b-01\n",
+        );
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        let node_a = "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal 10.0.0.2:3260";
+        let node_b = "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal 10.1.0.2:3260";
+
+        // The multipath device is removed before logging out of its paths.
+        let iscsi_calls: Vec<String> = f
+            .exec
+            .calls()
+            .into_iter()
+            .filter(|c| c.starts_with("iscsiadm") || c.starts_with("multipath"))
+            .collect();
+
+        assert_eq!(
+            iscsi_calls,
+            [
+                String::from("iscsiadm --mode session"),
+                String::from("iscsiadm --mode discovery --portal 10.0.0.2:3260 --type sendtargets"),
+                format!("{} --login", node_a),
+                String::from("iscsiadm --mode discovery --portal 10.1.0.2:3260 --type sendtargets"),
+                format!("{} --login", node_b),
+                format!("multipath -f {}", dm_dev),
+                format!("{} --logout", node_a),
+                format!("{} --logout", node_b),
+            ]
+        );
+
+        assert_eq!(
+            f.exec.calls_starting_with("parted"),
+            [
+                format!("parted --script {} mklabel gpt", dm_dev),
+                format!(
+                    "parted --script --align optimal {} mkpart primary 0% 100%",
+                    dm_dev
+                ),
+            ]
+        );
+        assert_eq!(
+            f.exec.calls_starting_with("mkfs"),
+            [format!("mkfs -t ext4 {}", dm_part)]
+        );
+        assert_eq!(
+            f.exec.calls_starting_with("mount -t ext4"),
+            [format!(
+                "mount -t ext4 -o noatime /dev/dm-1 {}",
+                f.path("ws/node1/mount/instance/rootfs")
+            )]
+        );
+
+        // The instance's initramfs logs in through every portal, and finds its partitions by
+        // PARTUUID, which resolves to the partitions on the multipath device.
+        let instance = f.root.join("ws/node1/mount/instance");
+
+        let cmdline = fs::read_to_string(instance.join("boot/cmdline.txt")).unwrap();
+
+        assert_eq!(
+            cmdline.trim_end(),
+            "console=tty1 console=ttyAMA0 console=serial0,115200 root=PARTUUID=5e6f7a8b-01 \
+             rootfstype=ext4 fsck.repair=yes \
+             rootwait ip=dhcp ISCSI_INITIATOR=iqn.2024-01.test:node1 \
+             ISCSI_TARGET_NAME=iqn.2024-01.test:target-node1 ISCSI_TARGET_IP=10.0.0.2 \
+             ISCSI_TARGET_PORT=3260 ISCSI_TARGET_PORTALS=10.0.0.2:3260,10.1.0.2:3260 rw \
+             cgroup_enable=memory"
+        );
+
+        let fstab = fs::read_to_string(instance.join("rootfs/etc/fstab")).unwrap();
+
+        assert!(
+            fstab.contains("PARTUUID=5e6f7a8b-01 / ext4 _netdev,noatime 0 1\n"),
+            "{}",
+            fstab
+        );
+        assert!(!fstab.contains("dm-uuid"), "{}", fstab);
+    }
+
+    #[tokio::test]
+    async fn skips_portals_with_existing_sessions() {
+        let multipath_fixture = || {
+            let mut f = Fixture::new(serde_json::json!({}));
+
+            f.use_multipath();
+
+            f
+        };
+
+        let logins = |f: &Fixture| -> Vec<String> {
+            f.exec
+                .calls_starting_with("iscsiadm --mode node")
+                .into_iter()
+                .filter(|c| c.ends_with("--login"))
+                .collect()
+        };
+
+        // An earlier attempt logged in through the first portal before failing on the second.
+        let f = multipath_fixture();
+
+        f.exec.respond(
+            "iscsiadm --mode session",
+            "tcp: [1] 10.0.0.2:3260,1 iqn.2024-01.test:target-node1 (non-flash)\n\
+             tcp: [2] 10.1.0.2:3260,1 iqn.2024-01.test:target-node2 (non-flash)\n",
+        );
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(
+            logins(&f),
+            [
+                "iscsiadm --mode node --targetname iqn.2024-01.test:target-node1 --portal 10.1.0.2:3260 --login"
+            ]
+        );
+
+        // iscsiadm exits with an error when there are no sessions at all.
+        let f = multipath_fixture();
+
+        f.exec.exit(
+            "iscsiadm --mode session",
+            21,
+            "iscsiadm: No active sessions.",
+        );
+
+        let report = f.run(Operation::Provision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(logins(&f).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn requires_multipath_boot_support() {
+        let mut f = Fixture::new(serde_json::json!({}));
+
+        f.use_multipath();
+
+        fs::remove_file(
+            f.root
+                .join("image/rootfs/etc/initramfs-tools/scripts/local-top/iscsi-multipath"),
+        )
+        .unwrap();
+
+        let report = f.run(Operation::Provision).await;
+
+        let error = report.error.expect("provisioning should fail");
+
+        assert!(
+            error.to_string().contains(
+                "the image has no /etc/initramfs-tools/scripts/local-top/iscsi-multipath"
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_failed_login_and_cleans_up() {
+        let f = Fixture::new(serde_json::json!({"login iSCSI": {"max_attempts": 1}}));
+
+        f.exec.fail(
+            "iscsiadm --mode node",
+            "iscsiadm: initiator reported error (24)",
+        );
+
+        let report = f.run(Operation::Provision).await;
+
+        let error = report.error.expect("provisioning should fail");
+
+        assert_eq!(
+            error.to_string(),
+            "error running login iSCSI: iscsiadm: initiator reported error (24)"
+        );
+
+        let status = |name: &str| {
+            report
+                .steps
+                .iter()
+                .find(|s| s.name == name)
+                .map(|s| s.status)
+                .unwrap()
+        };
+
+        assert_eq!(status("mount boot"), report::Status::Succeeded);
+        assert_eq!(status("login iSCSI"), report::Status::Failed);
+        assert_eq!(status("prepare rootfs"), report::Status::Skipped);
+        assert_eq!(status("finish"), report::Status::Skipped);
+
+        assert!(f.exec.calls_starting_with("parted").is_empty());
+        assert!(f.exec.calls_starting_with("mkfs").is_empty());
+
+        // The NFS mount that succeeded is unmounted, and the failed login is logged out.
+        assert_eq!(
+            f.exec.calls_starting_with("umount"),
+            [format!("umount {}", f.path("ws/node1/mount/instance/boot"))]
+        );
+        assert_eq!(f.exec.calls_starting_with("iscsiadm").len(), 4);
+    }
+
+    #[tokio::test]
+    async fn fails_when_device_does_not_appear() {
+        let f = Fixture::new(serde_json::json!({"login iSCSI": {"max_attempts": 1}}));
+
+        f.exec.missing_device(DEV);
+
+        let mut workspace_spec = f.workspace_spec;
+        workspace_spec.device_wait.timeout_secs = 0;
+
+        let report = run(
+            Operation::Provision,
+            graph::RunMode::Full,
+            &workspace_spec,
+            std::slice::from_ref(&f.instance_spec),
+            f.exec.clone(),
+            &graph::Interrupt::new(),
+        )
+        .await
+        .unwrap();
+
+        let error = report.instances[0].error.clone().unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "error running login iSCSI: timed out after 0s waiting for device {} to appear",
+                DEV
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_provisioned_instance() {
+        let f = Fixture::new(serde_json::json!({}));
+
+        f.run(Operation::Provision).await;
+
+        let report = f.run(Operation::Verify).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        fs::remove_file(f.root.join("ws/node1/mount/instance/rootfs/etc/hostname")).unwrap();
+
+        let report = f.run(Operation::Verify).await;
+
+        assert!(
+            report
+                .error
+                .unwrap()
+                .to_string()
+                .contains("etc/hostname: No such file or directory")
+        );
+    }
+
+    #[tokio::test]
+    async fn reconciles_configured_hostname() {
+        let f = Fixture::new(serde_json::json!({}));
+
+        f.exec
+            .respond("parted --script --machine", &parted_table(&[(1, None)]));
+
+        f.run(Operation::Provision).await;
+
+        let hosts_path = f.root.join("ws/node1/mount/instance/rootfs/etc/hosts");
+
+        let hostname_status = |report: &report::InstanceReport| {
+            report
+                .steps
+                .iter()
+                .find(|s| s.name == "configure hostname")
+                .unwrap()
+                .status
+        };
+
+        // A mention of the image's hostname elsewhere doesn't matter once the instance's is set.
+        fs::write(
+            &hosts_path,
+            "127.0.0.1\tlocalhost\n# was raspberrypi\n127.0.1.1\tnode1\n",
+        )
+        .unwrap();
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        assert_eq!(hostname_status(&report), report::Status::Satisfied);
+
+        // A hostname that merely contains the instance's ID is not the instance's hostname.
+        fs::write(&hosts_path, "127.0.1.1\tnode10\n").unwrap();
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        assert_eq!(hostname_status(&report), report::Status::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn reconciles_provisioned_instance() {
+        let f = Fixture::new(serde_json::json!({}));
+
+        f.exec
+            .respond("parted --script --machine", &parted_table(&[(1, None)]));
+        f.exec.respond("lsblk -n -o FSTYPE", "ext4\n");
+
+        f.run(Operation::Provision).await;
+
+        let calls = f.exec.calls().len();
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+
+        let statuses: Vec<(&str, report::Status)> = report
+            .steps
+            .iter()
+            .map(|s| (s.name.as_str(), s.status))
+            .collect();
+
+        // The fake executor doesn't apply the /etc/hosts edit, so the hostname is configured again.
+        assert_eq!(
+            statuses,
+            [
+                ("mkdir", report::Status::Succeeded),
+                ("mount boot", report::Status::Succeeded),
+                ("wipe boot", report::Status::Satisfied),
+                ("login iSCSI", report::Status::Succeeded),
+                ("prepare rootfs", report::Status::Satisfied),
+                ("mount rootfs", report::Status::Succeeded),
+                ("copy data", report::Status::Satisfied),
+                ("update command line", report::Status::Satisfied),
+                ("configure hostname", report::Status::Succeeded),
+                ("configure auth", report::Status::Satisfied),
+                ("finish", report::Status::Succeeded),
+            ]
+        );
+
+        let reconcile_calls = &f.exec.calls()[calls..];
+
+        assert!(
+            !reconcile_calls
+                .iter()
+                .any(|c| c.starts_with("parted --script /")
+                    || c.starts_with("parted --script --align")
+                    || c.starts_with("mkfs")
+                    || c.contains("loop")),
+            "{:?}",
+            reconcile_calls
+        );
+
+        // Replacing the image means copying it again.
+        let img = fs::OpenOptions::new()
+            .append(true)
+            .open(f.root.join("os.img"))
+            .unwrap();
+
+        img.set_len(9 * 1024 * 1024).unwrap();
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        let status = |name: &str| report.steps.iter().find(|s| s.name == name).unwrap().status;
+
+        assert_eq!(status("wipe boot"), report::Status::Succeeded);
+        assert_eq!(status("copy data"), report::Status::Succeeded);
+        assert_eq!(status("update command line"), report::Status::Succeeded);
+        assert_eq!(status("prepare rootfs"), report::Status::Satisfied);
+    }
+
+    #[tokio::test]
+    async fn reconciles_partition_layout_changes() {
+        let mut f = Fixture::new(serde_json::json!({}));
+
+        f.instance_spec.disk = serde_json::from_value(serde_json::json!({
+            "partitions": [
+                {"mount_point": "/", "size": "4GiB"},
+                {"mount_point": "/var"},
+            ],
+        }))
+        .unwrap();
+
+        f.exec.respond(
+            "parted --script --machine",
+            &parted_table(&[(1, Some(4096)), (4097, None)]),
+        );
+        f.exec.respond("lsblk -n -o FSTYPE", "ext4\n");
+
+        let prepare_rootfs_status = |report: &report::InstanceReport| {
+            report
+                .steps
+                .iter()
+                .find(|s| s.name == "prepare rootfs")
+                .unwrap()
+                .status
+        };
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(prepare_rootfs_status(&report), report::Status::Satisfied);
+        assert!(f.exec.calls_starting_with("parted --script /").is_empty());
+
+        // Only the size of the root partition changes.
+        f.instance_spec.disk.partitions[0].size = Some(String::from("6GiB"));
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(prepare_rootfs_status(&report), report::Status::Succeeded);
+        assert_eq!(
+            f.exec.calls_starting_with("parted --script --align"),
+            [
+                format!(
+                    "parted --script --align optimal {} mkpart primary 0% 6145MiB",
+                    DEV
+                ),
+                format!(
+                    "parted --script --align optimal {} mkpart primary 6145MiB 100%",
+                    DEV
+                ),
+            ]
+        );
+
+        // Partitions left over from an earlier layout are not part of the configured one.
+        f.instance_spec.disk.partitions.pop();
+        f.instance_spec.disk.partitions[0].size = None;
+
+        let report = f
+            .run_mode(Operation::Provision, graph::RunMode::Reconcile)
+            .await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(prepare_rootfs_status(&report), report::Status::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn cleans_up_without_running_steps() {
+        let f = Fixture::new(serde_json::json!({}));
+
+        let report = f.run(Operation::Cleanup).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert!(
+            report
+                .steps
+                .iter()
+                .all(|s| s.run.is_none() && s.cleanup.is_some())
+        );
+
+        assert!(f.exec.calls_starting_with("mount").is_empty());
+        assert!(f.exec.calls_starting_with("parted").is_empty());
+
+        let iscsi_calls = f.exec.calls_starting_with("iscsiadm");
+
+        assert_eq!(iscsi_calls.len(), 1);
+        assert!(iscsi_calls[0].ends_with("--logout"), "{}", iscsi_calls[0]);
+
+        let umount_calls = f.exec.calls_starting_with("umount");
+
+        assert!(umount_calls.contains(&format!(
+            "umount {}",
+            f.path("ws/node1/mount/instance/boot")
+        )));
+        assert!(umount_calls.contains(&format!(
+            "umount {}",
+            f.path("ws/node1/mount/instance/rootfs")
+        )));
+    }
+
+    #[tokio::test]
+    async fn deprovisions_instance() {
+        let f = Fixture::new(serde_json::json!({}));
+
+        let report = f.run(Operation::Deprovision).await;
+
+        assert!(report.error.is_none(), "{:?}", report.error);
+        assert_eq!(
+            f.exec.calls_starting_with("wipefs"),
+            [format!("wipefs --all {}", DEV)]
+        );
+    }
 }
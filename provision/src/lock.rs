@@ -0,0 +1,363 @@
+use std::error;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::process;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::config;
+
+/// The name of the workspace lock file, inside the workspace path. It starts with a dot so that it
+/// cannot collide with an instance's lock file.
+const WORKSPACE_LOCK_FILE: &str = ".provision.lock";
+
+/// Where the kernel exposes a random ID that changes on every boot.
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// The process holding a lock, as recorded in its lock file.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Holder {
+    pid: u32,
+    started_at: DateTime<Utc>,
+    /// The ID of the boot the holder ran in. Unset in locks taken by older versions.
+    #[serde(default)]
+    boot_id: Option<String>,
+    /// When the holder started, in clock ticks since boot, which tells it apart from a later
+    /// process given the same PID. Unset in locks taken by older versions.
+    #[serde(default)]
+    start_ticks: Option<u64>,
+}
+
+impl Holder {
+    /// Returns the holder for this process.
+    fn current() -> Holder {
+        Holder {
+            pid: process::id(),
+            started_at: Utc::now(),
+            boot_id: boot_id().ok(),
+            start_ticks: process_start_ticks(process::id()).ok(),
+        }
+    }
+
+    /// Returns whether the holder is still running on this host. Processes on other hosts sharing
+    /// the workspace cannot be checked, and look like they are not running. Neither does a process
+    /// that was given the holder's PID after the holder exited, e.g. after a reboot.
+    fn is_running(&self) -> bool {
+        if let Some(holder_boot_id) = &self.boot_id
+            && boot_id().is_ok_and(|id| id != *holder_boot_id)
+        {
+            return false;
+        }
+
+        // Signal 0 checks whether the process exists without signalling it. EPERM means it exists
+        // but belongs to another user.
+        let res = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+
+        if res != 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EPERM) {
+            return false;
+        }
+
+        match (self.start_ticks, process_start_ticks(self.pid)) {
+            (Some(holder_ticks), Ok(ticks)) => ticks == holder_ticks,
+            // The process exited since it was signalled.
+            (Some(_), Err(e)) if e.kind() == io::ErrorKind::NotFound => false,
+            _ => true,
+        }
+    }
+}
+
+/// Returns the ID of the current boot.
+fn boot_id() -> io::Result<String> {
+    Ok(fs::read_to_string(BOOT_ID_PATH)?.trim().to_string())
+}
+
+/// Returns when the process with the given PID started, in clock ticks since boot, from the 22nd
+/// field of `/proc/<pid>/stat`.
+fn process_start_ticks(pid: u32) -> io::Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "unexpected /proc stat format");
+
+    // The second field is the command name in parentheses, which may itself contain spaces and
+    // parentheses, so fields are counted from the last closing parenthesis, after which the
+    // third field starts.
+    let (_, fields) = stat.rsplit_once(')').ok_or_else(invalid)?;
+
+    fields
+        .split_whitespace()
+        .nth(22 - 3)
+        .and_then(|ticks| ticks.parse().ok())
+        .ok_or_else(invalid)
+}
+
+/// An advisory lock, held by creating a file that records the holder's PID and start time. The
+/// file is removed when the lock is dropped. Only runs of this binary respect the lock.
+#[derive(Debug)]
+pub struct LockFile {
+    path: path::PathBuf,
+}
+
+impl LockFile {
+    /// Takes the lock at the given path. If another process holds it, returns an error identifying
+    /// the holder, unless `break_stale` is set and the lock is stale, in which case the existing
+    /// lock is removed and taken over. A lock is stale if its holder is no longer running on this
+    /// host, or if its holder cannot be read. A lock held by a running process is never broken.
+    pub fn acquire(
+        path: &path::Path,
+        break_stale: bool,
+    ) -> Result<LockFile, Box<dyn error::Error>> {
+        match LockFile::create(path) {
+            Ok(lock) => return Ok(lock),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(format!("error creating {}: {}", path.display(), e).into()),
+        }
+
+        let holder = fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Holder>(&contents).ok());
+
+        let description = match &holder {
+            Some(holder) if holder.is_running() => {
+                return Err(format!(
+                    "{} is locked by process {} (running since {}); wait for it to finish",
+                    path.display(),
+                    holder.pid,
+                    holder.started_at
+                )
+                .into());
+            }
+            Some(holder) => format!(
+                "process {} (started at {}, no longer running on this host)",
+                holder.pid, holder.started_at
+            ),
+            None => String::from("an unknown process"),
+        };
+
+        if !break_stale {
+            return Err(format!(
+                "{} is locked by {}; if no other run is in progress, pass --break-stale-locks to take it over",
+                path.display(),
+                description
+            )
+            .into());
+        }
+
+        println!("breaking lock {} held by {}", path.display(), description);
+
+        fs::remove_file(path)?;
+
+        // If another run took the lock in the meantime, it keeps it.
+        LockFile::create(path)
+            .map_err(|e| format!("error creating {}: {}", path.display(), e).into())
+    }
+
+    fn create(path: &path::Path) -> Result<LockFile, io::Error> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        // From here on, the lock is ours, and dropping it removes the file even if writing the
+        // holder fails.
+        let lock = LockFile {
+            path: path.to_path_buf(),
+        };
+
+        let holder = Holder::current();
+
+        file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+
+        Ok(lock)
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            println!("error removing lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Takes the workspace lock and the lock of each of the given instances, so that no other run
+/// operates on the workspace until the returned locks are dropped. Either all of the locks are
+/// taken or none are. The instance locks also keep other tools that respect them off the
+/// instances' directories.
+pub fn lock_run(
+    workspace_spec: &config::WorkspaceConfig,
+    instance_specs: &[config::InstanceConfig],
+    break_stale: bool,
+) -> Result<Vec<LockFile>, Box<dyn error::Error>> {
+    let workspace_pb = path::PathBuf::from(&workspace_spec.path);
+
+    fs::create_dir_all(&workspace_pb)?;
+
+    let workspace_lock = LockFile::acquire(&workspace_pb.join(WORKSPACE_LOCK_FILE), break_stale)?;
+
+    let mut locks = instance_specs
+        .iter()
+        .map(|spec| LockFile::acquire(&workspace_pb.join(format!("{}.lock", spec.id)), break_stale))
+        .collect::<Result<Vec<LockFile>, Box<dyn error::Error>>>()?;
+
+    // Locks are dropped in order, so the workspace lock goes last and is released last.
+    locks.push(workspace_lock);
+
+    Ok(locks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_lock_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node1.lock");
+
+        let lock = LockFile::acquire(&path, false).unwrap();
+
+        let holder: Holder = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(holder.pid, process::id());
+        assert!(holder.is_running());
+
+        // A lock held by a running process cannot be broken.
+        for break_stale in [false, true] {
+            let err = LockFile::acquire(&path, break_stale)
+                .unwrap_err()
+                .to_string();
+
+            assert!(
+                err.starts_with(&format!(
+                    "{} is locked by process {} (running since",
+                    path.display(),
+                    process::id()
+                )),
+                "{}",
+                err
+            );
+        }
+
+        drop(lock);
+
+        assert!(!path.exists());
+        assert!(LockFile::acquire(&path, false).is_ok());
+    }
+
+    #[test]
+    fn breaks_stale_locks_only_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node1.lock");
+
+        // A lock left behind by a run that was killed.
+        fs::write(
+            &path,
+            r#"{"pid": 4194305, "started_at": "2024-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+
+        let err = LockFile::acquire(&path, false).unwrap_err().to_string();
+
+        assert!(
+            err.contains("process 4194305 (started at 2024-01-01 00:00:00 UTC, no longer running"),
+            "{}",
+            err
+        );
+
+        let lock = LockFile::acquire(&path, true).unwrap();
+
+        let holder: Holder = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(holder.pid, process::id());
+
+        drop(lock);
+    }
+
+    #[test]
+    fn breaks_locks_whose_pid_was_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node1.lock");
+
+        let current = Holder::current();
+
+        assert!(current.boot_id.is_some());
+        assert!(current.start_ticks.is_some());
+
+        // Locks left behind by a process with this process's PID, in an earlier boot, and earlier
+        // in this boot.
+        let stale_holders = [
+            Holder {
+                boot_id: Some(String::from("00000000-0000-0000-0000-000000000000")),
+                ..Holder::current()
+            },
+            Holder {
+                start_ticks: current.start_ticks.map(|ticks| ticks + 1),
+                ..Holder::current()
+            },
+        ];
+
+        for holder in stale_holders {
+            assert!(!holder.is_running(), "{:?}", holder);
+
+            fs::write(&path, serde_json::to_string(&holder).unwrap()).unwrap();
+
+            let err = LockFile::acquire(&path, false).unwrap_err().to_string();
+
+            assert!(err.contains("no longer running on this host"), "{}", err);
+
+            drop(LockFile::acquire(&path, true).unwrap());
+        }
+    }
+
+    #[test]
+    fn locks_workspace_and_instances_all_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let workspace_spec = config::fixtures::workspace_spec(&dir.path().join("ws"));
+
+        let instance_spec = |id: &str| config::fixtures::instance_spec(id, "dc-a6-32-00-00-01");
+
+        let locks = lock_run(&workspace_spec, &[instance_spec("node1")], false).unwrap();
+
+        assert!(dir.path().join("ws").join(WORKSPACE_LOCK_FILE).exists());
+        assert!(dir.path().join("ws/node1.lock").exists());
+
+        // Another run on the workspace is refused, even on other instances.
+        let err = lock_run(&workspace_spec, &[instance_spec("node2")], false).unwrap_err();
+
+        assert!(
+            err.to_string().contains(".provision.lock is locked by"),
+            "{}",
+            err
+        );
+        assert!(!dir.path().join("ws/node2.lock").exists());
+
+        drop(locks);
+
+        assert!(!dir.path().join("ws").join(WORKSPACE_LOCK_FILE).exists());
+        assert!(!dir.path().join("ws/node1.lock").exists());
+
+        // If an instance is locked, none of the other locks are kept.
+        let node1_lock = LockFile::acquire(&dir.path().join("ws/node1.lock"), false).unwrap();
+
+        let err = lock_run(
+            &workspace_spec,
+            &[instance_spec("node2"), instance_spec("node1")],
+            false,
+        )
+        .unwrap_err();
+
+        assert!(
+            err.to_string().contains("node1.lock is locked by"),
+            "{}",
+            err
+        );
+        assert!(!dir.path().join("ws").join(WORKSPACE_LOCK_FILE).exists());
+        assert!(!dir.path().join("ws/node2.lock").exists());
+
+        drop(node1_lock);
+    }
+}
@@ -1,39 +1,225 @@
 use std::env;
 use std::error;
-use std::iter;
+use std::process;
+use std::sync;
 
-use tokio;
+use tokio::signal::unix;
 
-use provision;
 use provision::config;
+use provision::exec;
+use provision::graph;
+use provision::lock;
+use provision::validate;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn error::Error>> {
-    let args: Vec<String> = env::args().collect();
+fn system_executor() -> sync::Arc<dyn exec::Executor> {
+    sync::Arc::new(exec::SystemExecutor::new())
+}
+
+fn load_specs(
+    cfg: &config::Config,
+) -> Result<(config::WorkspaceConfig, Vec<config::InstanceConfig>), Box<dyn error::Error>> {
+    let (mut workspace_spec, instance_specs) =
+        validate::load_configs(&cfg.workspace_config_path, &cfg.instances_config_dir)?;
+
+    if let Some(max_parallel_instances) = cfg.max_parallel_instances {
+        workspace_spec.max_parallel_instances = max_parallel_instances;
+    }
+
+    let instance_specs = config::select_instances(instance_specs, &cfg.selection)?;
+
+    Ok((workspace_spec, instance_specs))
+}
+
+fn list(cfg: &config::Config) -> Result<(), Box<dyn error::Error>> {
+    let (_, instance_specs) = load_specs(cfg)?;
+
+    for spec in instance_specs {
+        let labels: Vec<String> = spec
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+
+        println!(
+            "{}\t{}\t{}\t{}",
+            spec.id,
+            spec.mac_addr,
+            spec.iscsi_target_iqn,
+            labels.join(",")
+        );
+    }
+
+    Ok(())
+}
+
+fn show_config(cfg: &config::Config, id: &str) -> Result<(), Box<dyn error::Error>> {
+    let (_, instance_specs) =
+        validate::load_configs(&cfg.workspace_config_path, &cfg.instances_config_dir)?;
 
-    let cfg = config::Config::build(&args)?;
+    let instance_spec = instance_specs
+        .iter()
+        .find(|spec| spec.id == id)
+        .ok_or_else(|| format!("no instance config found with ID {}", id))?;
 
-    let workspace_spec = config::load_workspace_config(&cfg.workspace_config_path)?;
+    println!("{}", serde_json::to_string_pretty(instance_spec)?);
 
-    let instance_specs = config::load_instance_configs(&cfg.instances_config_dir)?;
+    Ok(())
+}
+
+fn plan(
+    cfg: &config::Config,
+    operation: provision::Operation,
+) -> Result<(), Box<dyn error::Error>> {
+    let (workspace_spec, instance_specs) = load_specs(cfg)?;
+
+    let (graph, finish_step) = operation.graph(system_executor())?;
+
+    for spec in instance_specs {
+        println!("{}:", spec.id);
+
+        let planned_steps = graph.plan(&workspace_spec, &spec, finish_step)?;
 
-    let results = provision::run(&workspace_spec, &instance_specs).await;
+        for (i, planned_step) in planned_steps.iter().enumerate() {
+            println!("  {}. {}", i + 1, planned_step.name);
+
+            for action in &planned_step.actions {
+                println!("       {}", action);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Interrupts runs when the process receives SIGINT or SIGTERM, so that every step that started is
+/// cleaned up before exiting. A second signal exits immediately without cleaning up.
+fn handle_signals(interrupt: graph::Interrupt) -> Result<(), Box<dyn error::Error>> {
+    let mut sigint = unix::signal(unix::SignalKind::interrupt())?;
+    let mut sigterm = unix::signal(unix::SignalKind::terminate())?;
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigint.recv() => {}
+            _ = sigterm.recv() => {}
+        }
+
+        println!("interrupted, cleaning up; interrupt again to exit immediately");
+
+        interrupt.interrupt();
+
+        tokio::select! {
+            _ = sigint.recv() => {}
+            _ = sigterm.recv() => {}
+        }
+
+        println!("interrupted again, exiting without cleaning up");
+
+        process::exit(130);
+    });
+
+    Ok(())
+}
+
+async fn run(
+    cfg: &config::Config,
+    operation: provision::Operation,
+    mode: graph::RunMode,
+) -> Result<(), Box<dyn error::Error>> {
+    let (workspace_spec, instance_specs) = load_specs(cfg)?;
+
+    // Held until the run finishes, including cleanup.
+    let _locks = lock::lock_run(&workspace_spec, &instance_specs, cfg.break_stale_locks)?;
+
+    let interrupt = graph::Interrupt::new();
+
+    handle_signals(interrupt.clone())?;
+
+    let report = provision::run(
+        operation,
+        mode,
+        &workspace_spec,
+        &instance_specs,
+        system_executor(),
+        &interrupt,
+    )
+    .await?;
 
     let mut failed = false;
 
-    for (spec, result) in iter::zip(instance_specs, results) {
-        match result {
-            Ok(_) => println!("{}: ok", spec.id),
-            Err(e) => {
-                println!("{}: {}", spec.id, e);
+    for instance in &report.instances {
+        match &instance.error {
+            None => println!("{}: ok", instance.id),
+            Some(e) => {
+                println!("{}: {}", instance.id, e);
                 failed = true;
             }
         }
     }
 
-    if !failed {
+    if let Some(report_path) = &cfg.report_path {
+        report.write_to_path(report_path)?;
+
+        println!("wrote report to {}", report_path.display());
+    }
+
+    if interrupt.is_interrupted() {
+        Err("interrupted".into())
+    } else if !failed {
         Ok(())
     } else {
-        Err("some instances failed to provision".into())
+        Err("some instances failed".into())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn error::Error>> {
+    let args: Vec<String> = env::args().collect();
+
+    let cfg = config::Config::build(&args).unwrap_or_else(|e| e.exit());
+
+    match &cfg.command {
+        config::Command::Provision { reconcile } => {
+            let mode = match reconcile {
+                true => graph::RunMode::Reconcile,
+                false => graph::RunMode::Full,
+            };
+
+            run(&cfg, provision::Operation::Provision, mode).await
+        }
+        config::Command::Verify => {
+            run(&cfg, provision::Operation::Verify, graph::RunMode::Full).await
+        }
+        config::Command::Deprovision => {
+            run(
+                &cfg,
+                provision::Operation::Deprovision,
+                graph::RunMode::Full,
+            )
+            .await
+        }
+        config::Command::Cleanup => {
+            run(&cfg, provision::Operation::Cleanup, graph::RunMode::Full).await
+        }
+        config::Command::Plan { operation } => plan(&cfg, *operation),
+        config::Command::List => list(&cfg),
+        config::Command::Validate => {
+            let (_, instance_specs) =
+                validate::load_configs(&cfg.workspace_config_path, &cfg.instances_config_dir)?;
+
+            match instance_specs.len() {
+                1 => println!("workspace config and 1 instance config are valid"),
+                n => println!("workspace config and {} instance configs are valid", n),
+            }
+
+            Ok(())
+        }
+        config::Command::ShowConfig { id } => show_config(&cfg, id),
+        config::Command::Graph { operation } => {
+            let (graph, _) = operation.graph(system_executor())?;
+
+            print!("{}", graph.dot());
+
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,110 @@
+use std::error;
+use std::fs;
+use std::path;
+
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::graph;
+
+/// The outcome of a step or an instance.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The step ran and succeeded, or every step of the instance succeeded.
+    Succeeded,
+    /// The step ran and failed, or a step of the instance failed.
+    Failed,
+    /// The step did not run because one of its dependencies failed.
+    Skipped,
+    /// The step did not run because the state it would produce already held.
+    Satisfied,
+    /// The step was stopped, or never started, because the run was interrupted. An interrupted
+    /// instance is cancelled rather than failed.
+    Cancelled,
+}
+
+/// When something started and finished.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Timing {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_secs: f64,
+}
+
+impl Timing {
+    /// Creates a timing from the given start time to now.
+    pub fn since(started_at: DateTime<Utc>) -> Timing {
+        let finished_at = Utc::now();
+
+        let duration_secs = (finished_at - started_at)
+            .to_std()
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        Timing {
+            started_at,
+            finished_at,
+            duration_secs,
+        }
+    }
+}
+
+/// The outcome of a single step for an instance.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct StepReport {
+    /// The name of the step.
+    pub name: String,
+    /// Whether the step succeeded, failed or was skipped.
+    pub status: Status,
+    /// The number of times the step was attempted. Zero for skipped and satisfied steps, and for
+    /// steps that were cancelled before they started.
+    pub attempts: u32,
+    /// When the step ran, or was checked if it was already satisfied. Not set for skipped steps.
+    pub run: Option<Timing>,
+    /// When the step was cleaned up. Steps are cleaned up after the whole walk finishes whether or
+    /// not they succeeded; skipped and satisfied steps, and steps cancelled before they started,
+    /// are not cleaned up.
+    pub cleanup: Option<Timing>,
+    /// Why the step failed or was skipped.
+    pub error: Option<String>,
+    /// Why the step's cleanup failed, e.g. because it timed out.
+    pub cleanup_error: Option<String>,
+}
+
+/// The outcome of an operation on a single instance.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct InstanceReport {
+    /// The ID of the instance.
+    pub id: String,
+    /// Whether every step succeeded.
+    pub status: Status,
+    /// When the instance's walk started and finished, including cleanup.
+    pub run: Timing,
+    /// The first step error, if any.
+    pub error: Option<graph::StepError>,
+    /// The outcome of each step, in dependency order.
+    pub steps: Vec<StepReport>,
+}
+
+/// The outcome of an operation on all selected instances.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct RunReport {
+    /// The operation that was performed.
+    pub operation: crate::Operation,
+    /// When the operation started and finished.
+    pub run: Timing,
+    /// The outcome for each instance, in the order the instances were selected.
+    pub instances: Vec<InstanceReport>,
+}
+
+impl RunReport {
+    /// Writes the report as JSON to the given path.
+    pub fn write_to_path(&self, path: &path::Path) -> Result<(), Box<dyn error::Error>> {
+        let json = serde_json::to_string_pretty(self)?;
+
+        fs::write(path, json + "\n")?;
+
+        Ok(())
+    }
+}
@@ -1,16 +1,24 @@
+use std::collections;
 use std::error;
+use std::fmt;
 use std::fs;
+use std::io;
 use std::io::prelude::*;
 use std::path;
-use std::process;
-use tokio::process as t_process;
+use std::sync;
+use std::sync::atomic;
+use tokio::sync as t_sync;
+use tokio::task;
 use tokio::time;
 
 use async_trait::async_trait;
-use sys_mount;
-use tokio;
 
+use crate::cmdline;
 use crate::config;
+use crate::copy;
+use crate::exec;
+use crate::fstab;
+use crate::image;
 
 const MOUNT_DIR: &str = "mount";
 const IMG_MOUNT_DIR: &str = "img";
@@ -18,12 +26,108 @@ const INSTANCE_MOUNT_DIR: &str = "instance";
 const ROOTFS_MOUNT_DIR: &str = "rootfs";
 const BOOT_MOUNT_DIR: &str = "boot";
 
+/// Where the instance mounts its root filesystem and its boot directory.
+const ROOT_MOUNT_POINT: &str = "/";
+const BOOT_MOUNT_POINT: &str = "/boot/firmware";
+
+/// The file recording which image a partition was copied from, at the root of the copy.
+const IMAGE_STAMP_FILE: &str = ".provision-image";
+
+/// The exit code iscsiadm uses when there is nothing to list, e.g. no sessions.
+const ISCSI_ERR_NO_OBJS_FOUND: i32 = 21;
+
+/// The kernel parameters that pass CHAP credentials to the initramfs.
+const CHAP_PARAMS: [&str; 4] = [
+    "ISCSI_USERNAME",
+    "ISCSI_PASSWORD",
+    "ISCSI_IN_USERNAME",
+    "ISCSI_IN_PASSWORD",
+];
+
+/// The kernel parameter that lists every portal of a multipath target for the initramfs.
+const PORTALS_PARAM: &str = "ISCSI_TARGET_PORTALS";
+
+/// The initramfs script, installed in the image by the packer template, that logs into the portals
+/// in `ISCSI_TARGET_PORTALS` at boot, relative to the root filesystem.
+const MULTIPATH_BOOT_SCRIPT: &str = "etc/initramfs-tools/scripts/local-top/iscsi-multipath";
+
+/// How often to report progress while copying image data.
+const COPY_PROGRESS_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/// How a step is run: how long each attempt may take, and how often it is retried if it fails.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepPolicy {
+    /// The maximum time a single attempt, or the step's cleanup, may take. Unlimited if unset.
+    pub timeout: Option<time::Duration>,
+    /// The maximum number of times the step is attempted.
+    pub max_attempts: u32,
+    /// The delay before the first retry. Doubles after each failed attempt.
+    pub retry_delay: time::Duration,
+    /// The maximum delay between retries.
+    pub max_retry_delay: time::Duration,
+}
+
+impl Default for StepPolicy {
+    fn default() -> Self {
+        StepPolicy {
+            timeout: None,
+            max_attempts: 1,
+            retry_delay: time::Duration::from_secs(1),
+            max_retry_delay: time::Duration::from_secs(30),
+        }
+    }
+}
+
+impl StepPolicy {
+    /// Returns this policy with any values set in the given step config taking precedence.
+    pub fn overridden(&self, step_config: &config::StepConfig) -> StepPolicy {
+        StepPolicy {
+            timeout: step_config
+                .timeout_secs
+                .map(time::Duration::from_secs)
+                .or(self.timeout),
+            max_attempts: step_config.max_attempts.unwrap_or(self.max_attempts),
+            retry_delay: step_config
+                .retry_delay_ms
+                .map(time::Duration::from_millis)
+                .unwrap_or(self.retry_delay),
+            max_retry_delay: step_config
+                .max_retry_delay_ms
+                .map(time::Duration::from_millis)
+                .unwrap_or(self.max_retry_delay),
+        }
+    }
+}
+
 /// Represents a single step in provisioning an instance.
 #[async_trait]
-pub trait Step {
+pub trait Step: Send + Sync {
     /// Returns the name of the step.
     fn name(&self) -> String;
 
+    /// Returns the timeout and retry policy for the step. Steps run once with no timeout unless
+    /// they override this or the workspace config overrides it.
+    fn policy(&self) -> StepPolicy {
+        StepPolicy::default()
+    }
+
+    /// Describes the actions the step would take if run, without taking them.
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>>;
+
+    /// Returns whether the state the step would produce already holds, so that reconciling can
+    /// skip running it. Steps that cannot tell always run.
+    async fn satisfied(
+        &self,
+        _workspace_spec: &config::WorkspaceConfig,
+        _instance_spec: &config::InstanceConfig,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        Ok(false)
+    }
+
     /// Runs the step's provisioning logic.
     async fn run(
         &self,
@@ -36,29 +140,87 @@ pub trait Step {
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> ();
+    );
 }
 
-fn output_or_err(output: process::Output) -> Result<String, Box<dyn error::Error>> {
-    if !output.status.success() {
-        let stdout = String::from_utf8(output.stdout)?;
-        let stderr = String::from_utf8(output.stderr)?;
+/// An external command run by a step. Steps build commands through this type so that the
+/// commands they describe when planning are exactly the commands they run.
+struct StepCommand {
+    program: &'static str,
+    args: Vec<String>,
+}
 
-        println!(
-            "error running command.
-stderr: '{}'
-stdout: '{}'",
-            stderr, stdout,
-        );
+impl StepCommand {
+    fn new(program: &'static str, args: &[&str]) -> StepCommand {
+        StepCommand {
+            program,
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    /// Runs the command, returning its stdout or an error if it did not exit successfully.
+    async fn output(&self, exec: &dyn exec::Executor) -> Result<String, Box<dyn error::Error>> {
+        exec.output(self.program, &self.args).await
+    }
+
+    /// Describes the command for use in a plan.
+    fn describe(&self) -> String {
+        format!("run: {}", self)
+    }
+}
+
+impl fmt::Display for StepCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", exec::command_line(self.program, &self.args))
+    }
+}
+
+/// Waits for the block device at the given path to appear, e.g. once udev has created its
+/// `/dev/disk/by-path` link. Returns an error if the device does not appear before the configured
+/// timeout.
+async fn wait_for_device(
+    exec: &dyn exec::Executor,
+    path: &str,
+    wait_config: &config::DeviceWaitConfig,
+) -> Result<(), Box<dyn error::Error>> {
+    let timeout = time::Duration::from_secs(wait_config.timeout_secs);
+    let max_interval = time::Duration::from_millis(wait_config.max_interval_ms);
+
+    let start = time::Instant::now();
+    let mut interval = time::Duration::from_millis(wait_config.initial_interval_ms);
+
+    println!("waiting for {} to appear", path);
+
+    loop {
+        if exec.block_device_exists(path)? {
+            println!("{} appeared after {:?}", path, start.elapsed());
+
+            return Ok(());
+        }
 
-        Err(output.status.to_string().into())
-    } else {
-        let res = String::from_utf8(output.stdout)?;
+        let elapsed = start.elapsed();
 
-        Ok(res)
+        if elapsed >= timeout {
+            return Err(format!(
+                "timed out after {}s waiting for device {} to appear",
+                wait_config.timeout_secs, path
+            )
+            .into());
+        }
+
+        time::sleep(interval.min(timeout - elapsed)).await;
+
+        interval = (interval * 2).min(max_interval);
     }
 }
 
+fn describe_wait_for_device(path: &str, wait_config: &config::DeviceWaitConfig) -> String {
+    format!(
+        "wait up to {}s for device {} to appear",
+        wait_config.timeout_secs, path
+    )
+}
+
 fn write_to_path(path: &[&str], contents: String) -> Result<(), Box<dyn error::Error>> {
     let pathbuf: path::PathBuf = path.iter().collect();
 
@@ -71,68 +233,125 @@ fn write_to_path(path: &[&str], contents: String) -> Result<(), Box<dyn error::E
     Ok(())
 }
 
+/// Returns the path of the given components relative to the instance's workspace directory.
+fn instance_path(
+    workspace_spec: &config::WorkspaceConfig,
+    instance_spec: &config::InstanceConfig,
+    components: &[&str],
+) -> path::PathBuf {
+    let mut pb: path::PathBuf = [&workspace_spec.path, &instance_spec.id].iter().collect();
+
+    pb.extend(components);
+
+    pb
+}
+
+fn path_str(pb: &path::Path) -> Result<&str, Box<dyn error::Error>> {
+    Ok(pb.to_str().ok_or("invalid path")?)
+}
+
+/// Returns whether an unmount failed because nothing was mounted at the target.
+fn is_not_mounted(e: &(dyn error::Error + 'static)) -> bool {
+    e.downcast_ref::<io::Error>().and_then(|e| e.raw_os_error()) == Some(libc::EINVAL)
+}
+
+/// Returns the contents of the file at the given path, or `None` if it does not exist.
+fn read_if_exists(pb: &path::Path) -> Result<Option<String>, Box<dyn error::Error>> {
+    match fs::read_to_string(pb) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("error reading {}: {}", pb.display(), e).into()),
+    }
+}
+
+/// Identifies the image partition at the given offset by the image's path, size and modification
+/// time, so that a copy of it can be recognized without mounting the image.
+fn image_stamp(
+    workspace_spec: &config::WorkspaceConfig,
+    offset: u64,
+) -> Result<String, Box<dyn error::Error>> {
+    let metadata = fs::metadata(&workspace_spec.img_path)?;
+
+    let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+
+    Ok(format!(
+        "{} {} {} {}\n",
+        workspace_spec.img_path,
+        metadata.len(),
+        modified.as_nanos(),
+        offset
+    ))
+}
+
+/// Returns whether the directory at the given path holds a complete copy of the image partition at
+/// the given offset.
+fn holds_image_copy(
+    workspace_spec: &config::WorkspaceConfig,
+    offset: u64,
+    pb: &path::Path,
+) -> Result<bool, Box<dyn error::Error>> {
+    let stamp = read_if_exists(&pb.join(IMAGE_STAMP_FILE))?;
+
+    Ok(stamp == Some(image_stamp(workspace_spec, offset)?))
+}
+
 /// Creates requisite directories for instance provisioning.
 pub struct MkdirStep {}
 
+impl MkdirStep {
+    fn mount_dirs(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> [path::PathBuf; 4] {
+        [
+            instance_path(
+                workspace_spec,
+                instance_spec,
+                &[MOUNT_DIR, IMG_MOUNT_DIR, ROOTFS_MOUNT_DIR],
+            ),
+            instance_path(
+                workspace_spec,
+                instance_spec,
+                &[MOUNT_DIR, IMG_MOUNT_DIR, BOOT_MOUNT_DIR],
+            ),
+            instance_path(
+                workspace_spec,
+                instance_spec,
+                &[MOUNT_DIR, INSTANCE_MOUNT_DIR, ROOTFS_MOUNT_DIR],
+            ),
+            instance_path(
+                workspace_spec,
+                instance_spec,
+                &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR],
+            ),
+        ]
+    }
+}
+
 #[async_trait]
 impl Step for MkdirStep {
     fn name(&self) -> String {
         String::from("mkdir")
     }
 
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        self.mount_dirs(workspace_spec, instance_spec)
+            .iter()
+            .map(|pb| Ok(format!("create directory {}", path_str(pb)?)))
+            .collect()
+    }
+
     async fn run(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
     ) -> Result<(), Box<dyn error::Error>> {
-        let img_rootfs_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            IMG_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let img_boot_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            IMG_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let instance_rootfs_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let instance_boot_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let all_pbs = [
-            img_rootfs_mount_pb,
-            img_boot_mount_pb,
-            instance_rootfs_mount_pb,
-            instance_boot_mount_pb,
-        ];
-
-        for pb in all_pbs {
+        for pb in self.mount_dirs(workspace_spec, instance_spec) {
             fs::create_dir_all(pb.as_path())?;
         }
 
@@ -143,81 +362,24 @@ impl Step for MkdirStep {
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> () {
-        let img_rootfs_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            IMG_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let img_boot_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            IMG_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let instance_rootfs_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let instance_boot_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let img_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            IMG_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
+    ) {
+        let img_mount_pb =
+            instance_path(workspace_spec, instance_spec, &[MOUNT_DIR, IMG_MOUNT_DIR]);
+
+        let instance_mount_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR],
+        );
 
-        let instance_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
+        let mount_pb = instance_path(workspace_spec, instance_spec, &[MOUNT_DIR]);
 
-        let mount_pb: path::PathBuf = [&workspace_spec.path, &instance_spec.id, MOUNT_DIR]
-            .iter()
-            .collect();
+        let instance_pb = instance_path(workspace_spec, instance_spec, &[]);
 
-        let instance_pb: path::PathBuf = [&workspace_spec.path, &instance_spec.id].iter().collect();
-
-        let all_pbs = [
-            img_rootfs_mount_pb,
-            img_boot_mount_pb,
-            instance_rootfs_mount_pb,
-            instance_boot_mount_pb,
-            img_mount_pb,
-            instance_mount_pb,
-            mount_pb,
-            instance_pb,
-        ];
+        let all_pbs = self
+            .mount_dirs(workspace_spec, instance_spec)
+            .into_iter()
+            .chain([img_mount_pb, instance_mount_pb, mount_pb, instance_pb]);
 
         for pb in all_pbs {
             let path = pb.as_path();
@@ -232,108 +394,1169 @@ impl Step for MkdirStep {
     }
 }
 
+/// A setting in an iSCSI node record, set with `iscsiadm --op update`.
+struct NodeSetting {
+    name: &'static str,
+    value: String,
+    /// Whether the value is left out of plans.
+    secret: bool,
+}
+
+impl NodeSetting {
+    fn new(name: &'static str, value: &str, secret: bool) -> NodeSetting {
+        NodeSetting {
+            name,
+            value: value.to_string(),
+            secret,
+        }
+    }
+}
+
 /// Logs into the workspace iSCSI portal and instance iSCSI target.
-pub struct LoginIscsiStep {}
+pub struct LoginIscsiStep {
+    pub exec: sync::Arc<dyn exec::Executor>,
+}
 
-#[async_trait]
-impl Step for LoginIscsiStep {
-    fn name(&self) -> String {
-        String::from("login iSCSI")
+impl LoginIscsiStep {
+    fn session_command(&self) -> StepCommand {
+        StepCommand::new("iscsiadm", &["--mode", "session"])
     }
 
-    async fn run(
+    /// Returns the addresses of the portals that already have a session to the instance's target,
+    /// e.g. from an earlier attempt that failed on another portal. Logging in again through them
+    /// would fail.
+    async fn logged_in_portals(
         &self,
-        workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> Result<(), Box<dyn error::Error>> {
-        println!(
-            "logging into {} to access target {}",
-            workspace_spec.iscsi_target_ip, instance_spec.iscsi_target_iqn
-        );
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let stdout = match self.session_command().output(self.exec.as_ref()).await {
+            Ok(stdout) => stdout,
+            Err(e)
+                if e.downcast_ref::<exec::CommandError>()
+                    .is_some_and(|e| e.code == Some(ISCSI_ERR_NO_OBJS_FOUND)) =>
+            {
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        // Each session is listed as e.g. `tcp: [1] 10.0.0.2:3260,1 iqn.2024-01.com.nas:node1
+        // (non-flash)`.
+        let mut addresses = Vec::new();
+
+        for line in stdout.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            if fields.len() < 4 || fields[3] != instance_spec.iscsi_target_iqn {
+                continue;
+            }
+
+            if let Some((address, _)) = fields[2].rsplit_once(',') {
+                addresses.push(address.to_string());
+            }
+        }
 
-        let discover_output = t_process::Command::new("iscsiadm")
-            .args([
+        Ok(addresses)
+    }
+
+    fn discover_command(&self, portal: &config::PortalConfig) -> StepCommand {
+        StepCommand::new(
+            "iscsiadm",
+            &[
                 "--mode",
                 "discovery",
                 "--portal",
-                &workspace_spec.iscsi_target_ip,
+                &portal.address(),
                 "--type",
                 "sendtargets",
-            ])
-            .output()
-            .await?;
+            ],
+        )
+    }
 
-        output_or_err(discover_output)?;
+    fn node_command(
+        &self,
+        portal: &config::PortalConfig,
+        instance_spec: &config::InstanceConfig,
+        action: &[&str],
+    ) -> StepCommand {
+        let address = portal.address();
+
+        let mut args = vec![
+            "--mode",
+            "node",
+            "--targetname",
+            &instance_spec.iscsi_target_iqn,
+            "--portal",
+            &address,
+        ];
 
-        let login_output = t_process::Command::new("iscsiadm")
-            .args([
-                "--mode",
-                "node",
-                "--targetname",
-                &instance_spec.iscsi_target_iqn,
-                "--portal",
-                &workspace_spec.iscsi_target_ip,
-                "--login",
-            ])
-            .output()
-            .await?;
+        args.extend_from_slice(action);
 
-        output_or_err(login_output)?;
+        StepCommand::new("iscsiadm", &args)
+    }
 
-        println!("sleeping for 5 seconds because iscsiadm is racy");
+    fn update_command(
+        &self,
+        portal: &config::PortalConfig,
+        instance_spec: &config::InstanceConfig,
+        name: &str,
+        value: &str,
+    ) -> StepCommand {
+        self.node_command(
+            portal,
+            instance_spec,
+            &["--op", "update", "--name", name, "--value", value],
+        )
+    }
 
-        time::sleep(time::Duration::from_millis(5_000)).await;
+    /// Returns the node settings that configure CHAP authentication. Returns no settings if the
+    /// instance does not use CHAP.
+    fn chap_settings(
+        &self,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<NodeSetting>, Box<dyn error::Error>> {
+        instance_spec.validate_chap()?;
 
-        Ok(())
+        let chap = match &instance_spec.chap {
+            Some(chap) => chap,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut settings = vec![
+            NodeSetting::new("node.session.auth.authmethod", "CHAP", false),
+            NodeSetting::new("node.session.auth.username", &chap.username, false),
+            NodeSetting::new("node.session.auth.password", &chap.password, true),
+        ];
+
+        if let Some(mutual_chap) = &instance_spec.mutual_chap {
+            settings.push(NodeSetting::new(
+                "node.session.auth.username_in",
+                &mutual_chap.username,
+                false,
+            ));
+            settings.push(NodeSetting::new(
+                "node.session.auth.password_in",
+                &mutual_chap.password,
+                true,
+            ));
+        }
+
+        Ok(settings)
     }
 
-    async fn cleanup(
+    /// Discovers the instance's target through the given portal and logs into it.
+    async fn login(
         &self,
-        workspace_spec: &config::WorkspaceConfig,
+        portal: &config::PortalConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> () {
+        chap_settings: &[NodeSetting],
+    ) -> Result<(), Box<dyn error::Error>> {
         println!(
-            "logging out of {} and target {}",
-            workspace_spec.iscsi_target_ip, instance_spec.iscsi_target_iqn
+            "logging into {} to access target {}",
+            portal.address(),
+            instance_spec.iscsi_target_iqn
         );
 
-        let output_result = t_process::Command::new("iscsiadm")
-            .args([
-                "--mode",
-                "node",
-                "--targetname",
-                &instance_spec.iscsi_target_iqn,
-                "--portal",
-                &workspace_spec.iscsi_target_ip,
-                "--logout",
-            ])
-            .output()
-            .await;
+        self.discover_command(portal)
+            .output(self.exec.as_ref())
+            .await?;
 
-        let output = match output_result {
-            Ok(o) => o,
-            Err(e) => {
-                println!("error logging out of target: {}", e);
-                return ();
-            }
-        };
+        // Discovery creates the node record, so its credentials can only be set afterwards.
+        if !chap_settings.is_empty() {
+            println!(
+                "configuring CHAP authentication for target {}",
+                instance_spec.iscsi_target_iqn
+            );
+        }
 
-        match output_or_err(output) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("error logging out of target: {}", e);
+        for setting in chap_settings {
+            self.update_command(portal, instance_spec, setting.name, &setting.value)
+                .output(self.exec.as_ref())
+                .await?;
+        }
+
+        self.node_command(portal, instance_spec, &["--login"])
+            .output(self.exec.as_ref())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Step for LoginIscsiStep {
+    fn name(&self) -> String {
+        String::from("login iSCSI")
+    }
+
+    fn policy(&self) -> StepPolicy {
+        // Logins fail transiently when the target is busy, and hang if it is unreachable.
+        StepPolicy {
+            timeout: Some(time::Duration::from_secs(120)),
+            max_attempts: 3,
+            ..StepPolicy::default()
+        }
+    }
+
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let chap_settings = self.chap_settings(instance_spec)?;
+
+        let mut actions = vec![format!(
+            "{}, skipping portals that already have a session to {}",
+            self.session_command().describe(),
+            instance_spec.iscsi_target_iqn
+        )];
+
+        for portal in instance_spec.iscsi_portals(workspace_spec)? {
+            actions.push(self.discover_command(&portal).describe());
+
+            // Secrets are left out of the plan, since it is printed.
+            for setting in &chap_settings {
+                let value = match setting.secret {
+                    true => config::SECRET_PLACEHOLDER,
+                    false => &setting.value,
+                };
+
+                actions.push(
+                    self.update_command(&portal, instance_spec, setting.name, value)
+                        .describe(),
+                );
             }
-        };
+
+            actions.push(
+                self.node_command(&portal, instance_spec, &["--login"])
+                    .describe(),
+            );
+            actions.push(describe_wait_for_device(
+                &iscsi_path_dev_path(&portal, instance_spec),
+                &workspace_spec.device_wait,
+            ));
+        }
+
+        if instance_spec.multipath() {
+            let portal = instance_spec.iscsi_portal(workspace_spec)?;
+
+            actions.push(scsi_id_command(&iscsi_path_dev_path(&portal, instance_spec)).describe());
+            actions.push(describe_wait_for_device(
+                &planned_lun_device(workspace_spec, instance_spec)?.path(),
+                &workspace_spec.device_wait,
+            ));
+        }
+
+        Ok(actions)
+    }
+
+    async fn run(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let portals = instance_spec.iscsi_portals(workspace_spec)?;
+
+        let chap_settings = self.chap_settings(instance_spec)?;
+
+        let logged_in_portals = self.logged_in_portals(instance_spec).await?;
+
+        for portal in &portals {
+            if logged_in_portals.contains(&portal.address()) {
+                println!(
+                    "already logged into {} for target {}",
+                    portal.address(),
+                    instance_spec.iscsi_target_iqn
+                );
+            } else {
+                self.login(portal, instance_spec, &chap_settings).await?;
+            }
+
+            // Logging in returns before the kernel has attached the target's LUNs, so wait for the
+            // device to show up before letting dependent steps use it.
+            wait_for_device(
+                self.exec.as_ref(),
+                &iscsi_path_dev_path(portal, instance_spec),
+                &workspace_spec.device_wait,
+            )
+            .await?;
+        }
+
+        if instance_spec.multipath() {
+            let lun_device = lun_device(self.exec.as_ref(), workspace_spec, instance_spec).await?;
+
+            // multipathd assembles the multipath device once it sees a second path to the LUN.
+            wait_for_device(
+                self.exec.as_ref(),
+                &lun_device.path(),
+                &workspace_spec.device_wait,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) {
+        let portals = match instance_spec.iscsi_portals(workspace_spec) {
+            Ok(portals) => portals,
+            Err(e) => {
+                println!("error finding iSCSI portals: {}", e);
+
+                return;
+            }
+        };
+
+        // A multipath device left behind without paths can queue I/O indefinitely, so it is
+        // removed before its paths are.
+        if instance_spec.multipath() {
+            let lun_device =
+                match lun_device(self.exec.as_ref(), workspace_spec, instance_spec).await {
+                    Ok(lun_device) => Some(lun_device),
+                    Err(e) => {
+                        println!("error finding multipath device: {}", e);
+
+                        None
+                    }
+                };
+
+            if let Some(lun_device) = lun_device {
+                println!("removing multipath device {}", lun_device.path());
+
+                match StepCommand::new("multipath", &["-f", &lun_device.path()])
+                    .output(self.exec.as_ref())
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        println!("error removing multipath device: {}", e);
+                    }
+                };
+            }
+        }
+
+        for portal in &portals {
+            println!(
+                "logging out of {} and target {}",
+                portal.address(),
+                instance_spec.iscsi_target_iqn
+            );
+
+            match self
+                .node_command(portal, instance_spec, &["--logout"])
+                .output(self.exec.as_ref())
+                .await
+            {
+                Ok(_) => {}
+                Err(e) => {
+                    println!("error logging out of target: {}", e);
+                }
+            };
+        }
+    }
+}
+
+/// Returns the path udev gives the instance's iSCSI LUN when logged in through the given portal.
+fn iscsi_path_dev_path(
+    portal: &config::PortalConfig,
+    instance_spec: &config::InstanceConfig,
+) -> String {
+    // udev names the device by the portal's address without brackets, even for IPv6.
+    format!(
+        "/dev/disk/by-path/ip-{}:{}-iscsi-{}-lun-{}",
+        portal.host, portal.port, instance_spec.iscsi_target_iqn, instance_spec.disk.lun,
+    )
+}
+
+fn scsi_id_command(path: &str) -> StepCommand {
+    StepCommand::new("/lib/udev/scsi_id", &["--whitelisted", "--device", path])
+}
+
+/// The block device of the instance's iSCSI LUN.
+enum LunDevice {
+    /// The device logged in through a single portal, by its path.
+    Path(String),
+    /// The multipath device combining the devices logged in through each portal, by the LUN's
+    /// WWID.
+    Multipath(String),
+}
+
+impl LunDevice {
+    /// Returns the path of the whole device.
+    fn path(&self) -> String {
+        match self {
+            LunDevice::Path(path) => path.clone(),
+            LunDevice::Multipath(wwid) => format!("/dev/disk/by-id/dm-uuid-mpath-{}", wwid),
+        }
+    }
+
+    /// Returns the path of the partition with the given number.
+    fn partition_path(&self, number: usize) -> String {
+        match self {
+            LunDevice::Path(path) => format!("{}-part{}", path, number),
+            LunDevice::Multipath(wwid) => {
+                format!("/dev/disk/by-id/dm-uuid-part{}-mpath-{}", number, wwid)
+            }
+        }
+    }
+}
+
+/// Returns the instance's LUN device for use in plans, where the WWID of a multipath LUN is only
+/// known once logged in. The plan shows how the WWID is looked up.
+fn planned_lun_device(
+    workspace_spec: &config::WorkspaceConfig,
+    instance_spec: &config::InstanceConfig,
+) -> Result<LunDevice, Box<dyn error::Error>> {
+    let portal = instance_spec.iscsi_portal(workspace_spec)?;
+
+    let path = iscsi_path_dev_path(&portal, instance_spec);
+
+    match instance_spec.multipath() {
+        true => Ok(LunDevice::Multipath(String::from("<WWID>"))),
+        false => Ok(LunDevice::Path(path)),
+    }
+}
+
+/// Returns the instance's LUN device. The WWID of a multipath LUN is looked up through the device
+/// logged in through the first portal.
+async fn lun_device(
+    exec: &dyn exec::Executor,
+    workspace_spec: &config::WorkspaceConfig,
+    instance_spec: &config::InstanceConfig,
+) -> Result<LunDevice, Box<dyn error::Error>> {
+    let portal = instance_spec.iscsi_portal(workspace_spec)?;
+
+    let path = iscsi_path_dev_path(&portal, instance_spec);
+
+    if !instance_spec.multipath() {
+        return Ok(LunDevice::Path(path));
+    }
+
+    let scsi_id_stdout = scsi_id_command(&path).output(exec).await?;
+    let wwid = scsi_id_stdout.trim();
+
+    if wwid.is_empty() {
+        return Err(format!("no WWID found for {}", path).into());
+    }
+
+    Ok(LunDevice::Multipath(wwid.to_string()))
+}
+
+/// A partition on the instance's iSCSI LUN.
+struct DiskPartition {
+    /// The 1-based number of the partition, in the order the partitions are configured.
+    number: usize,
+    /// The path of the partition's device.
+    path: String,
+    config: config::PartitionConfig,
+}
+
+impl DiskPartition {
+    /// Returns where the partition is mounted during provisioning, or `None` for swap.
+    fn mount_path(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Option<path::PathBuf> {
+        let mount_point = self.config.mount_point.as_ref()?;
+
+        let mut pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, ROOTFS_MOUNT_DIR],
+        );
+
+        let relative = mount_point.trim_start_matches('/');
+
+        if !relative.is_empty() {
+            pb.push(relative);
+        }
+
+        Some(pb)
+    }
+
+    fn is_root(&self) -> bool {
+        self.config.mount_point.as_deref() == Some(ROOT_MOUNT_POINT)
+    }
+}
+
+/// Returns the partitions configured for the instance's iSCSI LUN, in order.
+fn disk_partitions(
+    lun_device: &LunDevice,
+    workspace_spec: &config::WorkspaceConfig,
+    instance_spec: &config::InstanceConfig,
+) -> Result<Vec<DiskPartition>, Box<dyn error::Error>> {
+    let partitions = instance_spec
+        .partitions(workspace_spec)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, config)| DiskPartition {
+            number: i + 1,
+            path: lun_device.partition_path(i + 1),
+            config,
+        })
+        .collect();
+
+    Ok(partitions)
+}
+
+/// Returns the partitions that are mounted, in the order they must be mounted so that each mount
+/// point is inside the partition mounted before it, e.g. `/` before `/var`.
+fn mounted_partitions(partitions: &[DiskPartition]) -> Vec<&DiskPartition> {
+    let mut mounted: Vec<&DiskPartition> = partitions
+        .iter()
+        .filter(|p| p.config.mount_point.is_some())
+        .collect();
+
+    mounted.sort_by_key(|p| {
+        path::Path::new(p.config.mount_point.as_deref().unwrap_or_default())
+            .components()
+            .count()
+    });
+
+    mounted
+}
+
+const MIB: u64 = 1024 * 1024;
+
+/// Where a partition starts on the LUN and how big it is, in MiB. A partition without a size fills
+/// the rest of the LUN.
+struct Extent {
+    start_mib: u64,
+    size_mib: Option<u64>,
+}
+
+/// A partition as parted prints it, with its start and size in bytes.
+struct TablePartition {
+    number: usize,
+    start: u64,
+    size: u64,
+}
+
+/// A disk's partition table as parted prints it.
+struct PartitionTable {
+    /// The size of the disk in bytes.
+    disk_size: u64,
+    /// The type of the partition table, e.g. `gpt` or `msdos`.
+    label: String,
+    /// The partitions in order of their numbers.
+    partitions: Vec<TablePartition>,
+}
+
+impl PartitionTable {
+    /// Parses the output of `parted --machine <disk> unit B print`, e.g.
+    ///
+    /// ```text
+    /// BYT;
+    /// /dev/sdb:10737418240B:scsi:512:512:gpt:LIO-ORG disk:;
+    /// 1:1048576B:8590983167B:8589934592B:ext4::;
+    /// ```
+    fn parse(output: &str) -> Result<PartitionTable, Box<dyn error::Error>> {
+        let bytes = |field: &str| -> Result<u64, Box<dyn error::Error>> {
+            let n = field
+                .strip_suffix('B')
+                .ok_or_else(|| format!("'{}' is not a size in bytes", field))?;
+
+            Ok(n.parse()?)
+        };
+
+        let mut lines = output
+            .lines()
+            .map(|line| line.trim_end().trim_end_matches(';'))
+            .filter(|line| !line.is_empty() && *line != "BYT");
+
+        let disk: Vec<&str> = lines.next().ok_or("no disk found")?.split(':').collect();
+
+        if disk.len() < 6 {
+            return Err(format!("unexpected disk line '{}'", disk.join(":")).into());
+        }
+
+        let mut partitions = Vec::new();
+
+        for line in lines {
+            let fields: Vec<&str> = line.split(':').collect();
+
+            if fields.len() < 4 {
+                return Err(format!("unexpected partition line '{}'", line).into());
+            }
+
+            partitions.push(TablePartition {
+                number: fields[0].parse()?,
+                start: bytes(fields[1])?,
+                size: bytes(fields[3])?,
+            });
+        }
+
+        Ok(PartitionTable {
+            disk_size: bytes(disk[1])?,
+            label: disk[5].to_string(),
+            partitions,
+        })
+    }
+}
+
+/// Formats the iSCSI target device and creates the configured partitions and filesystems on it.
+pub struct PrepareRootfsStep {
+    pub exec: sync::Arc<dyn exec::Executor>,
+}
+
+impl PrepareRootfsStep {
+    fn mklabel_command(&self, iscsi_dev_path: &str) -> StepCommand {
+        StepCommand::new("parted", &["--script", iscsi_dev_path, "mklabel", "gpt"])
+    }
+
+    fn mkpart_command(&self, iscsi_dev_path: &str, start: &str, end: &str) -> StepCommand {
+        StepCommand::new(
+            "parted",
+            &[
+                "--script",
+                "--align",
+                "optimal",
+                iscsi_dev_path,
+                "mkpart",
+                "primary",
+                start,
+                end,
+            ],
+        )
+    }
+
+    fn print_command(&self, iscsi_dev_path: &str) -> StepCommand {
+        StepCommand::new(
+            "parted",
+            &[
+                "--script",
+                "--machine",
+                iscsi_dev_path,
+                "unit",
+                "B",
+                "print",
+            ],
+        )
+    }
+
+    /// Returns the start and size of each partition in MiB. Partitions are laid out back to back
+    /// from the first aligned offset, and the last partition without a size fills the rest of the
+    /// LUN.
+    fn partition_extents(
+        &self,
+        partitions: &[DiskPartition],
+    ) -> Result<Vec<Extent>, Box<dyn error::Error>> {
+        // parted aligns 0% to the first optimally aligned sector, which is 1MiB in practice.
+        let mut start_mib = 1;
+        let mut extents = Vec::with_capacity(partitions.len());
+
+        for partition in partitions {
+            let size_mib = partition.config.size_mib()?;
+
+            extents.push(Extent {
+                start_mib,
+                size_mib,
+            });
+
+            start_mib += size_mib.unwrap_or_default();
+        }
+
+        Ok(extents)
+    }
+
+    /// Returns the start and end of each partition in the units parted expects.
+    fn partition_bounds(
+        &self,
+        partitions: &[DiskPartition],
+    ) -> Result<Vec<(String, String)>, Box<dyn error::Error>> {
+        let bounds = partitions
+            .iter()
+            .zip(self.partition_extents(partitions)?)
+            .map(
+                |(
+                    partition,
+                    Extent {
+                        start_mib,
+                        size_mib,
+                    },
+                )| {
+                    let start = match partition.number {
+                        1 => String::from("0%"),
+                        _ => format!("{}MiB", start_mib),
+                    };
+
+                    let end = match size_mib {
+                        Some(size_mib) => format!("{}MiB", start_mib + size_mib),
+                        None => String::from("100%"),
+                    };
+
+                    (start, end)
+                },
+            )
+            .collect();
+
+        Ok(bounds)
+    }
+
+    /// Returns whether the LUN has a GPT partition table with exactly the configured partitions,
+    /// each at its configured start and with its configured size.
+    async fn layout_satisfied(
+        &self,
+        iscsi_dev_path: &str,
+        partitions: &[DiskPartition],
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let stdout = match self
+            .print_command(iscsi_dev_path)
+            .output(self.exec.as_ref())
+            .await
+        {
+            Ok(stdout) => stdout,
+            // parted fails to print disks without a partition table it recognizes.
+            Err(e) if e.downcast_ref::<exec::CommandError>().is_some() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        let table = PartitionTable::parse(&stdout).map_err(|e| {
+            format!(
+                "error reading the partition table of {}: {}",
+                iscsi_dev_path, e
+            )
+        })?;
+
+        if table.label != "gpt" || table.partitions.len() != partitions.len() {
+            return Ok(false);
+        }
+
+        let extents = self.partition_extents(partitions)?;
+
+        for (
+            i,
+            (
+                Extent {
+                    start_mib,
+                    size_mib,
+                },
+                actual,
+            ),
+        ) in extents.iter().zip(&table.partitions).enumerate()
+        {
+            if actual.number != i + 1 || actual.start != start_mib * MIB {
+                return Ok(false);
+            }
+
+            let size_matches = match size_mib {
+                Some(size_mib) => actual.size == size_mib * MIB,
+                // A partition filling the rest of the LUN ends before the backup GPT at the end of
+                // the disk, aligned down to the 1MiB grain.
+                None => actual.start + actual.size + 2 * MIB >= table.disk_size,
+            };
+
+            if !size_matches {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn mkfs_command(&self, fs: &config::FilesystemConfig, iscsi_part_path: &str) -> StepCommand {
+        let fstype = fs.fstype.to_string();
+
+        let mut args = Vec::new();
+
+        if fs.fstype != config::FilesystemType::Swap {
+            args.extend(["-t", fstype.as_str()]);
+        }
+
+        // mkfs.ext4 and mkswap only ask before overwriting an existing filesystem when run
+        // interactively, but the others refuse to unless forced.
+        let needs_force = matches!(
+            fs.fstype,
+            config::FilesystemType::Xfs
+                | config::FilesystemType::Btrfs
+                | config::FilesystemType::F2fs
+        );
+
+        if needs_force {
+            args.push("-f");
+        }
+
+        if let Some(label) = &fs.label {
+            let label_flag = match fs.fstype {
+                config::FilesystemType::F2fs => "-l",
+                _ => "-L",
+            };
+
+            args.push(label_flag);
+            args.push(label);
+        }
+
+        args.extend(fs.mkfs_options.iter().map(|o| o.as_str()));
+        args.push(iscsi_part_path);
+
+        match fs.fstype {
+            config::FilesystemType::Swap => StepCommand::new("mkswap", &args),
+            _ => StepCommand::new("mkfs", &args),
+        }
+    }
+
+    fn lsblk_command(&self, column: &str, iscsi_part_path: &str) -> StepCommand {
+        StepCommand::new("lsblk", &["-n", "-o", column, iscsi_part_path])
+    }
+
+    /// Returns whether the partition exists and has the configured filesystem and label.
+    async fn partition_satisfied(
+        &self,
+        partition: &DiskPartition,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        if !self.exec.block_device_exists(&partition.path)? {
+            return Ok(false);
+        }
+
+        let fs = &partition.config.filesystem;
+
+        let fstype = self
+            .lsblk_command("FSTYPE", &partition.path)
+            .output(self.exec.as_ref())
+            .await?;
+
+        if fstype.trim() != fs.fstype.to_string() {
+            return Ok(false);
+        }
+
+        match &fs.label {
+            Some(label) => {
+                let current_label = self
+                    .lsblk_command("LABEL", &partition.path)
+                    .output(self.exec.as_ref())
+                    .await?;
+
+                Ok(current_label.trim_end() == label)
+            }
+            None => Ok(true),
+        }
+    }
+}
+
+#[async_trait]
+impl Step for PrepareRootfsStep {
+    fn name(&self) -> String {
+        String::from("prepare rootfs")
+    }
+
+    fn policy(&self) -> StepPolicy {
+        StepPolicy {
+            timeout: Some(time::Duration::from_secs(600)),
+            ..StepPolicy::default()
+        }
+    }
+
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let iscsi_dev_path = planned_lun_device(workspace_spec, instance_spec)?.path();
+
+        let partitions = disk_partitions(
+            &planned_lun_device(workspace_spec, instance_spec)?,
+            workspace_spec,
+            instance_spec,
+        )?;
+
+        let mut actions = vec![self.mklabel_command(&iscsi_dev_path).describe()];
+
+        for (start, end) in self.partition_bounds(&partitions)? {
+            actions.push(
+                self.mkpart_command(&iscsi_dev_path, &start, &end)
+                    .describe(),
+            );
+        }
+
+        for partition in &partitions {
+            actions.push(describe_wait_for_device(
+                &partition.path,
+                &workspace_spec.device_wait,
+            ));
+            actions.push(
+                self.mkfs_command(&partition.config.filesystem, &partition.path)
+                    .describe(),
+            );
+        }
+
+        Ok(actions)
+    }
+
+    async fn satisfied(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let lun_device = lun_device(self.exec.as_ref(), workspace_spec, instance_spec).await?;
+
+        let partitions = disk_partitions(&lun_device, workspace_spec, instance_spec)?;
+
+        if !self
+            .layout_satisfied(&lun_device.path(), &partitions)
+            .await?
+        {
+            return Ok(false);
+        }
+
+        for partition in &partitions {
+            if !self.partition_satisfied(partition).await? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn run(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let lun_device = lun_device(self.exec.as_ref(), workspace_spec, instance_spec).await?;
+
+        let iscsi_dev_path = lun_device.path();
+
+        let partitions = disk_partitions(&lun_device, workspace_spec, instance_spec)?;
+
+        println!("making GPT partition table on {}", iscsi_dev_path);
+
+        self.mklabel_command(&iscsi_dev_path)
+            .output(self.exec.as_ref())
+            .await?;
+
+        let bounds = self.partition_bounds(&partitions)?;
+
+        for (partition, (start, end)) in partitions.iter().zip(bounds) {
+            println!(
+                "making {} from {} to {} on {}",
+                partition.config.describe(),
+                start,
+                end,
+                iscsi_dev_path
+            );
+
+            self.mkpart_command(&iscsi_dev_path, &start, &end)
+                .output(self.exec.as_ref())
+                .await?;
+        }
+
+        for partition in &partitions {
+            wait_for_device(
+                self.exec.as_ref(),
+                &partition.path,
+                &workspace_spec.device_wait,
+            )
+            .await?;
+
+            println!(
+                "creating {} filesystem at {}",
+                partition.config.filesystem.fstype, partition.path
+            );
+
+            self.mkfs_command(&partition.config.filesystem, &partition.path)
+                .output(self.exec.as_ref())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup(
+        &self,
+        _workspace_spec: &config::WorkspaceConfig,
+        _instance_spec: &config::InstanceConfig,
+    ) {
+    }
+}
+
+/// Mounts the root filesystem on the iSCSI target device, along with any other partitions
+/// configured for the instance at their mount points inside it.
+pub struct MountRootfsStep {
+    pub exec: sync::Arc<dyn exec::Executor>,
+}
+
+impl MountRootfsStep {
+    fn lsblk_command(&self, iscsi_part_path: &str) -> StepCommand {
+        StepCommand::new("lsblk", &["-n", "-o", "NAME", iscsi_part_path])
+    }
+}
+
+#[async_trait]
+impl Step for MountRootfsStep {
+    fn name(&self) -> String {
+        String::from("mount rootfs")
+    }
+
+    fn policy(&self) -> StepPolicy {
+        StepPolicy {
+            timeout: Some(time::Duration::from_secs(60)),
+            ..StepPolicy::default()
+        }
+    }
+
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let partitions = disk_partitions(
+            &planned_lun_device(workspace_spec, instance_spec)?,
+            workspace_spec,
+            instance_spec,
+        )?;
+
+        let mut actions = Vec::new();
+
+        for partition in mounted_partitions(&partitions) {
+            let mount_path_pb = partition
+                .mount_path(workspace_spec, instance_spec)
+                .expect("mounted partitions have a mount point");
+
+            actions.push(self.lsblk_command(&partition.path).describe());
+            actions.push(format!(
+                "mount the device backing {} at {} with options '{}'",
+                partition.path,
+                path_str(&mount_path_pb)?,
+                partition.config.filesystem.mount_options.join(",")
+            ));
+        }
+
+        Ok(actions)
+    }
+
+    async fn run(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let lun_device = lun_device(self.exec.as_ref(), workspace_spec, instance_spec).await?;
+
+        let partitions = disk_partitions(&lun_device, workspace_spec, instance_spec)?;
+
+        for partition in mounted_partitions(&partitions) {
+            let mount_path_pb = partition
+                .mount_path(workspace_spec, instance_spec)
+                .expect("mounted partitions have a mount point");
+
+            let mount_path = mount_path_pb
+                .to_str()
+                .ok_or(String::from("invalid mount path"))?;
+
+            println!("finding device for {}", partition.path);
+
+            let part_out = self
+                .lsblk_command(&partition.path)
+                .output(self.exec.as_ref())
+                .await?;
+
+            let part_name = part_out.trim_end();
+
+            let dev_part_path = format!("/dev/{}", part_name);
+
+            // Mount points other than the root are created inside the partitions mounted before
+            // them, which may be freshly formatted.
+            if !partition.is_root() {
+                fs::create_dir_all(&mount_path_pb)?;
+            }
+
+            println!("mounting {} at {}", dev_part_path, mount_path);
+
+            // The partition is mounted with the options it boots with, so that it is populated
+            // the same way and invalid options fail now rather than at boot.
+            let mount_options = &partition.config.filesystem.mount_options;
+
+            self.exec
+                .mount(&exec::Mount {
+                    source: dev_part_path,
+                    target: mount_path_pb.clone(),
+                    fstype: Some(partition.config.filesystem.fstype.to_string()),
+                    data: match mount_options.is_empty() {
+                        true => None,
+                        false => Some(mount_options.join(",")),
+                    },
+                    ..exec::Mount::default()
+                })
+                .map_err(|e| {
+                    format!(
+                        "error mounting {} with options '{}': {}",
+                        partition.path,
+                        mount_options.join(","),
+                        e
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) {
+        let partitions = match planned_lun_device(workspace_spec, instance_spec)
+            .and_then(|lun_device| disk_partitions(&lun_device, workspace_spec, instance_spec))
+        {
+            Ok(partitions) => partitions,
+            Err(e) => {
+                println!("error reading partitions: {}", e);
+
+                return;
+            }
+        };
+
+        for partition in mounted_partitions(&partitions).iter().rev() {
+            let mount_path_pb = partition
+                .mount_path(workspace_spec, instance_spec)
+                .expect("mounted partitions have a mount point");
+
+            match self.exec.unmount(&mount_path_pb) {
+                Ok(_) => {}
+                Err(e) => {
+                    println!("error unmounting {}: {}", mount_path_pb.display(), e);
+                }
+            };
+        }
     }
 }
 
-/// Formats the iSCSI target device and creates an ext4 filesystem on the device.
-pub struct PrepareRootfsStep {}
+/// Wipes all filesystem and partition table signatures from the iSCSI target device.
+pub struct WipeRootfsStep {
+    pub exec: sync::Arc<dyn exec::Executor>,
+}
+
+impl WipeRootfsStep {
+    fn wipefs_command(&self, iscsi_dev_path: &str) -> StepCommand {
+        StepCommand::new("wipefs", &["--all", iscsi_dev_path])
+    }
+}
 
 #[async_trait]
-impl Step for PrepareRootfsStep {
+impl Step for WipeRootfsStep {
     fn name(&self) -> String {
-        String::from("prepare rootfs")
+        String::from("wipe rootfs")
+    }
+
+    fn policy(&self) -> StepPolicy {
+        StepPolicy {
+            timeout: Some(time::Duration::from_secs(60)),
+            ..StepPolicy::default()
+        }
+    }
+
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let iscsi_dev_path = planned_lun_device(workspace_spec, instance_spec)?.path();
+
+        Ok(vec![self.wipefs_command(&iscsi_dev_path).describe()])
     }
 
     async fn run(
@@ -341,86 +1564,111 @@ impl Step for PrepareRootfsStep {
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
     ) -> Result<(), Box<dyn error::Error>> {
-        let iscsi_dev_path = format!(
-            "/dev/disk/by-path/ip-{}:3260-iscsi-{}-lun-1",
-            workspace_spec.iscsi_target_ip, instance_spec.iscsi_target_iqn,
-        );
+        let lun_device = lun_device(self.exec.as_ref(), workspace_spec, instance_spec).await?;
 
-        let iscsi_part_path = format!("{}-part1", iscsi_dev_path);
+        let iscsi_dev_path = lun_device.path();
 
-        println!("making GPT partition table on {}", iscsi_dev_path);
+        println!("wiping signatures from {}", iscsi_dev_path);
 
-        let mklabel_output = t_process::Command::new("parted")
-            .args(["--script", &iscsi_dev_path, "mklabel", "gpt"])
-            .output()
+        self.wipefs_command(&iscsi_dev_path)
+            .output(self.exec.as_ref())
             .await?;
 
-        output_or_err(mklabel_output)?;
+        Ok(())
+    }
 
-        println!("making partition on {}", iscsi_dev_path);
+    async fn cleanup(
+        &self,
+        _workspace_spec: &config::WorkspaceConfig,
+        _instance_spec: &config::InstanceConfig,
+    ) {
+    }
+}
 
-        let mkpart_output = t_process::Command::new("parted")
-            .args([
-                "--script",
-                "--align",
-                "optimal",
-                &iscsi_dev_path,
-                "mkpart",
-                "primary",
-                "ext4",
-                "0%",
-                "100%",
-            ])
-            .output()
-            .await?;
+/// Returns the path of the instance's `/boot/firmware` directory on the NFS server.
+fn nfs_boot_path(
+    workspace_spec: &config::WorkspaceConfig,
+    instance_spec: &config::InstanceConfig,
+) -> path::PathBuf {
+    [&workspace_spec.nfs_tftp_dir, &instance_spec.mac_addr]
+        .iter()
+        .collect()
+}
 
-        output_or_err(mkpart_output)?;
+/// Mounts the Raspberry Pi `/boot/firmware` directory at the workspace NFS server.
+pub struct MountBootStep {
+    pub exec: sync::Arc<dyn exec::Executor>,
+}
 
-        let mount_path_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
+#[async_trait]
+impl Step for MountBootStep {
+    fn name(&self) -> String {
+        String::from("mount boot")
+    }
 
-        let mount_path = mount_path_pb
-            .to_str()
-            .ok_or(String::from("invalid mount path"))?;
+    fn policy(&self) -> StepPolicy {
+        // NFS mounts hang rather than fail when the server is unreachable.
+        StepPolicy {
+            timeout: Some(time::Duration::from_secs(60)),
+            max_attempts: 3,
+            ..StepPolicy::default()
+        }
+    }
 
-        println!("sleeping for 5 seconds because iscsiadm is racy");
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let nfs_path_pb = nfs_boot_path(workspace_spec, instance_spec);
 
-        println!("formatting disk at {}", iscsi_part_path);
+        let mount_path_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR],
+        );
 
-        time::sleep(time::Duration::from_millis(5_000)).await;
+        Ok(vec![format!(
+            "mount NFS export {}:{} at {}",
+            workspace_spec.nfs_server_ip,
+            path_str(&nfs_path_pb)?,
+            path_str(&mount_path_pb)?
+        )])
+    }
 
-        let mkfs_output = t_process::Command::new("mkfs")
-            .args(["-t", "ext4", &iscsi_part_path])
-            .output()
-            .await?;
+    async fn run(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let nfs_path_pb = nfs_boot_path(workspace_spec, instance_spec);
 
-        output_or_err(mkfs_output)?;
+        let nfs_path = nfs_path_pb.to_str().ok_or("invalid path")?;
 
-        println!("finding device for {}", iscsi_part_path);
+        // The mount syscall for NFS is a little funky. This bit of code inspired by a StackOverflow post
+        // seems to work.
 
-        let lsblk_output = t_process::Command::new("lsblk")
-            .args(["-n", "-o", "NAME", &iscsi_part_path])
-            .output()
-            .await?;
+        let nfs_mount_src = format!(":{}", nfs_path);
 
-        let part_out = output_or_err(lsblk_output)?;
+        let nfs_mount_addr_option = format!("addr={}", workspace_spec.nfs_server_ip);
 
-        let part_name = part_out.trim_end();
+        let mount_path_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR],
+        );
 
-        let dev_part_path = format!("/dev/{}", part_name);
+        let mount_path = mount_path_pb.to_str().ok_or("invalid path")?;
 
-        println!("mounting {} at {}", &dev_part_path, mount_path);
+        println!("mounting {} at {}", nfs_mount_src, mount_path);
 
-        // The mount here should persist indefinitely instead of being auto-unmounted
-        // on drop
-        sys_mount::Mount::builder().mount(&dev_part_path, mount_path)?;
+        self.exec.mount(&exec::Mount {
+            source: nfs_mount_src,
+            target: mount_path_pb.clone(),
+            fstype: Some(String::from("nfs")),
+            data: Some(nfs_mount_addr_option),
+            ..exec::Mount::default()
+        })?;
 
         Ok(())
     }
@@ -429,42 +1677,26 @@ impl Step for PrepareRootfsStep {
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> () {
-        let mount_path_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let mount_path_result = mount_path_pb
-            .to_str()
-            .ok_or(String::from("invalid mount path"));
-
-        let mount_path = match mount_path_result {
-            Ok(p) => p,
-            Err(e) => {
-                println!("error constructing mount path: {}", e);
-                return ();
-            }
-        };
+    ) {
+        let mount_path_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR],
+        );
 
-        match sys_mount::unmount(mount_path, sys_mount::UnmountFlags::DETACH) {
+        match self.exec.unmount(&mount_path_pb) {
             Ok(_) => {}
             Err(e) => {
-                println!("error unmounting {}: {}", mount_path, e);
+                println!("error unmounting {}: {}", mount_path_pb.display(), e);
             }
         };
     }
 }
 
-/// Mounts the Raspberry Pi `/boot/firmware` directory at the workspace NFS server.
-pub struct MountBootStep {}
+/// Removes the contents of the instance's `/boot/firmware` directory on the NFS server.
+pub struct WipeBootStep {}
 
-impl MountBootStep {
+impl WipeBootStep {
     fn remove_dir_contents(&self, path: &path::Path) -> Result<(), Box<dyn error::Error>> {
         for entry_result in fs::read_dir(path)? {
             let entry = entry_result?;
@@ -483,9 +1715,48 @@ impl MountBootStep {
 }
 
 #[async_trait]
-impl Step for MountBootStep {
+impl Step for WipeBootStep {
     fn name(&self) -> String {
-        String::from("mount boot")
+        String::from("wipe boot")
+    }
+
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let nfs_path_pb = nfs_boot_path(workspace_spec, instance_spec);
+
+        let mount_path_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR],
+        );
+
+        Ok(vec![format!(
+            "remove all contents of {} (NFS export {}:{})",
+            path_str(&mount_path_pb)?,
+            workspace_spec.nfs_server_ip,
+            path_str(&nfs_path_pb)?,
+        )])
+    }
+
+    /// The boot directory only needs wiping before a fresh copy, so there is nothing to do if it
+    /// already holds a copy of the image's boot partition.
+    async fn satisfied(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let layout = image::layout(workspace_spec)?;
+
+        let mount_path_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR],
+        );
+
+        holds_image_copy(workspace_spec, layout.boot_offset, &mount_path_pb)
     }
 
     async fn run(
@@ -493,86 +1764,300 @@ impl Step for MountBootStep {
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
     ) -> Result<(), Box<dyn error::Error>> {
-        let nfs_path_pb: path::PathBuf = [&workspace_spec.nfs_tftp_dir, &instance_spec.mac_addr]
+        let mount_path_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR],
+        );
+
+        let mount_path = mount_path_pb.to_str().ok_or("invalid path")?;
+
+        println!("removing the contents of {}", mount_path);
+
+        self.remove_dir_contents(&mount_path_pb)
+    }
+
+    async fn cleanup(
+        &self,
+        _workspace_spec: &config::WorkspaceConfig,
+        _instance_spec: &config::InstanceConfig,
+    ) {
+    }
+}
+
+/// Updates the kernel command line to boot via iSCSI.
+pub struct UpdateCmdlineStep {
+    pub exec: sync::Arc<dyn exec::Executor>,
+}
+
+impl UpdateCmdlineStep {
+    /// Returns the fstab entry for a partition on the iSCSI LUN, given the device as the fstab
+    /// names it.
+    fn partition_entry(&self, partition: &DiskPartition, device: &str) -> fstab::Entry {
+        let fs = &partition.config.filesystem;
+
+        if fs.fstype == config::FilesystemType::Swap {
+            return fstab::Entry {
+                spec: device.to_string(),
+                file: String::from("none"),
+                vfstype: fs.fstype.to_string(),
+                mntops: String::from("sw"),
+                freq: 0,
+                passno: 0,
+            };
+        }
+
+        let mut mount_options = vec![String::from("_netdev")];
+
+        mount_options.extend(fs.mount_options.iter().cloned());
+
+        // xfs and btrfs check themselves when mounted, and their fsck tools do nothing at boot.
+        let passno = match fs.fstype {
+            config::FilesystemType::Ext4 | config::FilesystemType::F2fs if partition.is_root() => 1,
+            config::FilesystemType::Ext4 | config::FilesystemType::F2fs => 2,
+            _ => 0,
+        };
+
+        fstab::Entry {
+            spec: device.to_string(),
+            file: partition.config.mount_point.clone().unwrap_or_default(),
+            vfstype: fs.fstype.to_string(),
+            mntops: mount_options.join(","),
+            freq: 0,
+            passno,
+        }
+    }
+
+    /// Returns the fstab entries to write: one for each partition on the iSCSI LUN, and the boot
+    /// directory on NFS. The root and boot entries replace the image's own, and the rest replace
+    /// matching entries or are added.
+    fn fstab_entries(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+        devices: &[(DiskPartition, String)],
+    ) -> Vec<fstab::Entry> {
+        let mut entries: Vec<fstab::Entry> = devices
             .iter()
+            .map(|(partition, device)| self.partition_entry(partition, device))
             .collect();
 
-        let nfs_path = nfs_path_pb.to_str().ok_or("invalid path")?;
+        entries.push(fstab::Entry {
+            spec: format!(
+                "{}:{}/{}",
+                workspace_spec.nfs_server_ip, workspace_spec.nfs_tftp_dir, instance_spec.mac_addr
+            ),
+            file: String::from(BOOT_MOUNT_POINT),
+            vfstype: String::from("nfs"),
+            mntops: String::from("defaults,vers=4.1,proto=tcp"),
+            freq: 0,
+            passno: 0,
+        });
+
+        entries
+    }
 
-        // The mount syscall for NFS is a little funky. This bit of code inspired by a StackOverflow post
-        // seems to work.
+    /// Returns whether the entry replaces one the image's fstab must already have.
+    fn replaces_image_entry(&self, entry: &fstab::Entry) -> bool {
+        entry.file == ROOT_MOUNT_POINT || entry.file == BOOT_MOUNT_POINT
+    }
 
-        let nfs_mount_src = format!(":{}", nfs_path);
+    /// Returns the current contents of the fstab at the given path, and its contents with the
+    /// entries set and every other line kept as it was.
+    fn updated_fstab(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+        devices: &[(DiskPartition, String)],
+        fstab_path: &path::Path,
+    ) -> Result<(String, String), Box<dyn error::Error>> {
+        let contents = fs::read_to_string(fstab_path)?;
 
-        let nfs_mount_addr_option = format!("addr={}", workspace_spec.nfs_server_ip);
+        let mut fstab = fstab::Fstab::parse(&contents)?;
 
-        let mount_path_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
+        for entry in self.fstab_entries(workspace_spec, instance_spec, devices) {
+            if self.replaces_image_entry(&entry) {
+                fstab.replace(&entry.file.clone(), entry)?;
+            } else {
+                fstab.set(entry)?;
+            }
+        }
 
-        let mount_path = mount_path_pb.to_str().ok_or("invalid path")?;
+        Ok((contents, fstab.to_string()))
+    }
 
-        println!("mounting {} at {}", nfs_mount_src, mount_path);
+    /// Returns the kernel parameters to set: those that boot from the iSCSI partition, followed
+    /// by the extra parameters from the workspace config and then the instance config.
+    fn kernel_params(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+        devices: &[(DiskPartition, String)],
+    ) -> Result<Vec<cmdline::Param>, Box<dyn error::Error>> {
+        let (root, root_device) = devices
+            .iter()
+            .find(|(partition, _)| partition.is_root())
+            .expect("partitions include a root partition");
+
+        let portal = instance_spec.iscsi_portal(workspace_spec)?;
+
+        let mut params = vec![
+            cmdline::Param::new("root", root_device),
+            cmdline::Param::new("rootfstype", &root.config.filesystem.fstype.to_string()),
+            cmdline::Param::new("ip", "dhcp"),
+            cmdline::Param::new("ISCSI_INITIATOR", &instance_spec.iscsi_initiator_iqn),
+            cmdline::Param::new("ISCSI_TARGET_NAME", &instance_spec.iscsi_target_iqn),
+            cmdline::Param::new("ISCSI_TARGET_IP", &portal.host.to_string()),
+            cmdline::Param::new("ISCSI_TARGET_PORT", &portal.port.to_string()),
+        ];
 
-        // The mount here should persist indefinitely instead of being auto-unmounted
-        // on drop
-        sys_mount::Mount::builder()
-            .fstype("nfs")
-            .data(&nfs_mount_addr_option)
-            .mount(&nfs_mount_src, mount_path)?;
+        // The initramfs logs in through the first portal, and through the others from this list so
+        // that multipath can combine the paths.
+        if instance_spec.multipath() {
+            let addresses: Vec<String> = instance_spec
+                .iscsi_portals(workspace_spec)?
+                .iter()
+                .map(|p| p.address())
+                .collect();
 
-        println!("removing the contents of {}", mount_path);
+            params.push(cmdline::Param::new(PORTALS_PARAM, &addresses.join(",")));
+        }
 
-        // Remove the contents of the mount directory before proceeding
-        self.remove_dir_contents(&mount_path_pb)?;
+        // The initramfs logs into the target with the same credentials used to provision it.
+        instance_spec.validate_chap()?;
 
-        Ok(())
+        if let Some(chap) = &instance_spec.chap {
+            params.push(cmdline::Param::new("ISCSI_USERNAME", &chap.username));
+            params.push(cmdline::Param::new("ISCSI_PASSWORD", &chap.password));
+        }
+
+        if let Some(mutual_chap) = &instance_spec.mutual_chap {
+            params.push(cmdline::Param::new(
+                "ISCSI_IN_USERNAME",
+                &mutual_chap.username,
+            ));
+            params.push(cmdline::Param::new(
+                "ISCSI_IN_PASSWORD",
+                &mutual_chap.password,
+            ));
+        }
+
+        params.push(cmdline::Param::flag("rw"));
+
+        for extra in workspace_spec
+            .kernel_params
+            .iter()
+            .chain(instance_spec.kernel_params.iter())
+        {
+            params.extend_from_slice(cmdline::Cmdline::parse(extra)?.params());
+        }
+
+        Ok(params)
     }
 
-    async fn cleanup(
+    /// Returns the current contents of the cmdline.txt at the given path, and its contents with
+    /// the kernel parameters set and every other parameter kept as it was.
+    fn updated_cmdline(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> () {
-        let mount_path_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
+        devices: &[(DiskPartition, String)],
+        cmdline_path: &path::Path,
+    ) -> Result<(String, String), Box<dyn error::Error>> {
+        if instance_spec.multipath() {
+            let script_pb = instance_path(
+                workspace_spec,
+                instance_spec,
+                &[
+                    MOUNT_DIR,
+                    INSTANCE_MOUNT_DIR,
+                    ROOTFS_MOUNT_DIR,
+                    MULTIPATH_BOOT_SCRIPT,
+                ],
+            );
+
+            if !script_pb.exists() {
+                return Err(format!(
+                    "the image has no /{}, so the instance could only boot through its first portal; build the image with multipath boot support",
+                    MULTIPATH_BOOT_SCRIPT
+                )
+                .into());
+            }
+        }
 
-        let mount_path_result = mount_path_pb
-            .to_str()
-            .ok_or(String::from("invalid mount path"));
+        let contents = fs::read_to_string(cmdline_path)?;
 
-        let mount_path = match mount_path_result {
-            Ok(p) => p,
-            Err(e) => {
-                println!("error constructing mount path: {}", e);
-                return ();
-            }
-        };
+        let mut cmdline = cmdline::Cmdline::parse(&contents)?;
 
-        match sys_mount::unmount(mount_path, sys_mount::UnmountFlags::DETACH) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("error unmounting {}: {}", mount_path, e);
+        let params = self.kernel_params(workspace_spec, instance_spec, devices)?;
+
+        // Credentials left over from an earlier CHAP configuration would make the initramfs
+        // attempt CHAP against a target that no longer expects it, and portals left over from an
+        // earlier multipath configuration would make it log into another target's portals.
+        for key in CHAP_PARAMS.into_iter().chain([PORTALS_PARAM]) {
+            if !params.iter().any(|p| p.key == key) {
+                cmdline.remove(key);
             }
-        };
+        }
+
+        cmdline.set_all(&params);
+
+        Ok((contents, format!("{}\n", cmdline)))
     }
-}
 
-/// Updates the kernel command line to boot via iSCSI.
-pub struct UpdateCmdlineStep {}
+    /// Returns the paths of the fstab and cmdline.txt to update.
+    fn config_paths(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> (path::PathBuf, path::PathBuf) {
+        let fstab_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, ROOTFS_MOUNT_DIR, "etc/fstab"],
+        );
+
+        let cmdline_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR, "cmdline.txt"],
+        );
+
+        (fstab_pb, cmdline_pb)
+    }
+
+    /// Returns each partition on the instance's iSCSI LUN along with the device the instance
+    /// finds it by at boot, its PARTUUID. On instances with more than one portal, the initramfs
+    /// logs in through each and multipath claims the paths, so the PARTUUID resolves to the
+    /// partition on the multipath device.
+    async fn partition_devices(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<(DiskPartition, String)>, Box<dyn error::Error>> {
+        let mut devices = Vec::new();
+
+        let lun_device = lun_device(self.exec.as_ref(), workspace_spec, instance_spec).await?;
+
+        let partitions = disk_partitions(&lun_device, workspace_spec, instance_spec)?;
+
+        for partition in partitions {
+            println!("getting PARTUUID for {}", partition.path);
+
+            let lsblk_stdout =
+                StepCommand::new("lsblk", &["-n", "-o", "PARTUUID", &partition.path])
+                    .output(self.exec.as_ref())
+                    .await?;
+            let partuuid = lsblk_stdout.trim_end().to_string();
+
+            println!("PARTUUID for {} is: {}", partition.path, partuuid);
+
+            devices.push((partition, format!("PARTUUID={}", partuuid)));
+        }
+
+        Ok(devices)
+    }
+}
 
 #[async_trait]
 impl Step for UpdateCmdlineStep {
@@ -580,93 +2065,112 @@ impl Step for UpdateCmdlineStep {
         String::from("update command line")
     }
 
-    async fn run(
+    fn plan(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> Result<(), Box<dyn error::Error>> {
-        let rootfs_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let rootfs_path = rootfs_pb.to_str().ok_or("invalid root path")?;
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let (fstab_pb, cmdline_pb) = self.config_paths(workspace_spec, instance_spec);
+
+        let lun_device = planned_lun_device(workspace_spec, instance_spec)?;
+
+        // PARTUUIDs are only known once the partitions have been created, so the plan refers to
+        // them by the partitions they belong to.
+        let devices: Vec<(DiskPartition, String)> =
+            disk_partitions(&lun_device, workspace_spec, instance_spec)?
+                .into_iter()
+                .map(|partition| {
+                    let device = format!("PARTUUID=<PARTUUID of {}>", partition.path);
+
+                    (partition, device)
+                })
+                .collect();
+
+        let mut plan = Vec::new();
+
+        for entry in self.fstab_entries(workspace_spec, instance_spec, &devices) {
+            if self.replaces_image_entry(&entry) {
+                plan.push(format!(
+                    "replace the {} entry in {} with '{}'",
+                    entry.file,
+                    path_str(&fstab_pb)?,
+                    entry
+                ));
+            } else {
+                plan.push(format!("set '{}' in {}", entry, path_str(&fstab_pb)?));
+            }
+        }
 
-        let fstab_pb: path::PathBuf = [&rootfs_path, "etc/fstab"].iter().collect();
+        // Secrets are left out of the plan, since it is printed.
+        let params: Vec<String> = self
+            .kernel_params(workspace_spec, instance_spec, &devices)?
+            .into_iter()
+            .map(|p| match p.key.as_str() {
+                "ISCSI_PASSWORD" | "ISCSI_IN_PASSWORD" => {
+                    cmdline::Param::new(&p.key, config::SECRET_PLACEHOLDER).to_string()
+                }
+                _ => p.to_string(),
+            })
+            .collect();
 
-        let fstab_path = fstab_pb.to_str().ok_or("invalid fstab path")?;
+        plan.push(format!(
+            "set kernel parameters in {}: {}",
+            path_str(&cmdline_pb)?,
+            params.join(" ")
+        ));
 
-        let cmdline_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-            "cmdline.txt",
-        ]
-        .iter()
-        .collect();
+        Ok(plan)
+    }
 
-        let cmdline_path = cmdline_pb.to_str().ok_or("invalid cmdline.txt path")?;
+    async fn satisfied(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let (fstab_pb, cmdline_pb) = self.config_paths(workspace_spec, instance_spec);
 
-        let findmnt_output = t_process::Command::new("findmnt")
-            .args(["-n", "-o", "SOURCE", rootfs_path])
-            .output()
+        let devices = self
+            .partition_devices(workspace_spec, instance_spec)
             .await?;
 
-        let findmnt_stdout = output_or_err(findmnt_output)?;
-        let mount_source = findmnt_stdout.trim_end();
+        let (fstab, updated_fstab) =
+            self.updated_fstab(workspace_spec, instance_spec, &devices, &fstab_pb)?;
 
-        println!("getting PARTUUID for {}", mount_source);
+        let (cmdline, updated_cmdline) =
+            self.updated_cmdline(workspace_spec, instance_spec, &devices, &cmdline_pb)?;
 
-        let lsblk_output = t_process::Command::new("lsblk")
-            .args(["-n", "-o", "PARTUUID", mount_source])
-            .output()
-            .await?;
+        Ok(fstab == updated_fstab && cmdline == updated_cmdline)
+    }
 
-        let lsblk_stdout = output_or_err(lsblk_output)?;
-        let partuuid = lsblk_stdout.trim_end();
+    async fn run(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let (fstab_pb, cmdline_pb) = self.config_paths(workspace_spec, instance_spec);
 
-        println!("PARTUUID for {} is: {}", mount_source, partuuid);
+        let devices = self
+            .partition_devices(workspace_spec, instance_spec)
+            .await?;
 
-        let fstab_sed_expr = format!(
-            "s@.*/ +.*@PARTUUID={} / ext4 _netdev,noatime 0 1@;s@.*/boot/firmware +.*@{}:{}/{} /boot/firmware nfs defaults,vers=4.1,proto=tcp 0 0@",
-            partuuid,
-            workspace_spec.nfs_server_ip,
-            workspace_spec.nfs_tftp_dir,
-            instance_spec.mac_addr,
+        println!(
+            "updating partition and boot entries in {}",
+            fstab_pb.display()
         );
 
-        println!("updating {} with {}", fstab_path, fstab_sed_expr);
+        let (_, updated_fstab) = self
+            .updated_fstab(workspace_spec, instance_spec, &devices, &fstab_pb)
+            .map_err(|e| format!("error updating {}: {}", fstab_pb.display(), e))?;
 
-        output_or_err(
-            t_process::Command::new("sed")
-                .args(["-i", "-r", "-e", &fstab_sed_expr, &fstab_path])
-                .output()
-                .await?,
-        )?;
+        fs::write(&fstab_pb, updated_fstab)?;
 
-        let cmdline_sed_expr = format!(
-            "s/root=PARTUUID=[0-9a-f-]+/root=PARTUUID={}/;s/$/ ip=dhcp ISCSI_INITIATOR={} ISCSI_TARGET_NAME={} ISCSI_TARGET_IP={} rw/g",
-            partuuid,
-            instance_spec.iscsi_initiator_iqn,
-            instance_spec.iscsi_target_iqn,
-            workspace_spec.iscsi_target_ip,
-        );
+        println!("setting kernel parameters in {}", cmdline_pb.display());
 
-        println!("updating {} with {}", cmdline_path, cmdline_sed_expr);
+        let (_, updated_cmdline) = self
+            .updated_cmdline(workspace_spec, instance_spec, &devices, &cmdline_pb)
+            .map_err(|e| format!("error updating {}: {}", cmdline_pb.display(), e))?;
 
-        output_or_err(
-            t_process::Command::new("sed")
-                .args(["-i", "-r", "-e", &cmdline_sed_expr, &cmdline_path])
-                .output()
-                .await?,
-        )?;
+        fs::write(&cmdline_pb, updated_cmdline)?;
 
         Ok(())
     }
@@ -675,21 +2179,80 @@ impl Step for UpdateCmdlineStep {
         &self,
         _workspace_spec: &config::WorkspaceConfig,
         _instance_spec: &config::InstanceConfig,
-    ) -> () {
-        ()
+    ) {
     }
 }
 
 /// Copies Raspberry Pi OS image data to the boot and rootfs mounts.
-pub struct CopyDataStep {}
+pub struct CopyDataStep {
+    exec: sync::Arc<dyn exec::Executor>,
+    /// Copies in progress, by instance ID.
+    copies: sync::Mutex<collections::HashMap<String, RunningCopy>>,
+}
+
+/// A copy of image data running on a blocking thread. The thread carries on if the step's future
+/// is dropped, so it has to be stopped and waited for before the image can be unmounted.
+struct RunningCopy {
+    /// Set to stop the copy.
+    cancel: sync::Arc<atomic::AtomicBool>,
+    /// Held by the copying thread until it finishes.
+    done: sync::Arc<t_sync::Mutex<()>>,
+}
 
 impl CopyDataStep {
+    /// Creates a new CopyDataStep.
+    pub fn new(exec: sync::Arc<dyn exec::Executor>) -> CopyDataStep {
+        CopyDataStep {
+            exec,
+            copies: sync::Mutex::new(collections::HashMap::new()),
+        }
+    }
+
+    /// Returns the image partition offset, the path to mount the image partition at, and the
+    /// path to copy the image partition's contents into, for the given partition directory.
+    fn copy_paths(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+        layout: &image::ImageLayout,
+        partition_dir: &str,
+    ) -> (u64, path::PathBuf, path::PathBuf) {
+        let offset = match partition_dir {
+            BOOT_MOUNT_DIR => layout.boot_offset,
+            _ => layout.rootfs_offset,
+        };
+
+        let img_mount_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, IMG_MOUNT_DIR, partition_dir],
+        );
+
+        let target_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, partition_dir],
+        );
+
+        (offset, img_mount_pb, target_pb)
+    }
+
+    /// Returns the copy options for the given partition directory. The boot partition is FAT,
+    /// which has no ownership to preserve, and is copied onto NFS, which may squash root.
+    fn copy_options(&self, partition_dir: &str) -> copy::Options {
+        copy::Options {
+            preserve_ownership: partition_dir != BOOT_MOUNT_DIR,
+        }
+    }
+
     async fn copy_from_img(
         &self,
+        instance_id: &str,
         img_path: &String,
         offset: u64,
         mnt_path: &path::Path,
         target_path: &path::Path,
+        options: copy::Options,
     ) -> Result<(), Box<dyn error::Error>> {
         let mnt_path_str = mnt_path.to_str().ok_or("invalid mount path")?;
 
@@ -697,93 +2260,171 @@ impl CopyDataStep {
 
         println!("mounting {} at {} on {}", img_path, offset, mnt_path_str);
 
-        // We don't actually use this value but we hold onto it
-        // so we unmount on drop
-        let _mount_result = sys_mount::Mount::builder()
-            .explicit_loopback()
-            .loopback_offset(offset)
-            .flags(sys_mount::MountFlags::RDONLY)
-            .mount_autodrop(img_path, mnt_path, sys_mount::UnmountFlags::DETACH)?;
+        self.exec.mount(&exec::Mount {
+            source: img_path.clone(),
+            target: mnt_path.to_path_buf(),
+            read_only: true,
+            loop_offset: Some(offset),
+            ..exec::Mount::default()
+        })?;
+
+        let cancel = sync::Arc::new(atomic::AtomicBool::new(false));
+        let done = sync::Arc::new(t_sync::Mutex::new(()));
+        let done_guard = done.clone().try_lock_owned().expect("new lock is unlocked");
+
+        // If the step is cancelled or times out while copying, the copy is left registered so that
+        // cleanup or the next attempt can stop it.
+        self.copies.lock().expect("copies lock poisoned").insert(
+            instance_id.to_string(),
+            RunningCopy {
+                cancel: cancel.clone(),
+                done,
+            },
+        );
+
+        let copy_result = self
+            .copy_tree(mnt_path, target_path, options, cancel, done_guard)
+            .await;
+
+        self.copies
+            .lock()
+            .expect("copies lock poisoned")
+            .remove(instance_id);
+
+        // Unmount the image whether or not the copy succeeded.
+        self.exec.unmount(mnt_path)?;
+
+        let progress = copy_result?;
+
+        println!(
+            "copied {} files, {} bytes from {} to {}",
+            progress.files, progress.bytes, mnt_path_str, target_path_str
+        );
+
+        Ok(())
+    }
+
+    async fn copy_tree(
+        &self,
+        mnt_path: &path::Path,
+        target_path: &path::Path,
+        options: copy::Options,
+        cancel: sync::Arc<atomic::AtomicBool>,
+        done_guard: t_sync::OwnedMutexGuard<()>,
+    ) -> Result<copy::Progress, Box<dyn error::Error>> {
+        let mnt_path_str = mnt_path.to_str().ok_or("invalid mount path")?;
+
+        let target_path_str = target_path.to_str().ok_or("invalid target path")?;
 
         println!(
             "copying contents of {} to {}",
             mnt_path_str, target_path_str
         );
 
-        let cp_output = t_process::Command::new("cp")
-            .args(["-r", &mnt_path_str, &target_path_str])
-            .output()
-            .await?;
+        let src = mnt_path.to_path_buf();
+        let dst = target_path.to_path_buf();
 
-        let _ = output_or_err(cp_output)?;
+        let progress = task::spawn_blocking(move || {
+            let _done_guard = done_guard;
+            let mut last_report = time::Instant::now();
 
-        Ok(())
+            copy::copy_tree(&src, &dst, options, &cancel, |p| {
+                if last_report.elapsed() >= COPY_PROGRESS_INTERVAL {
+                    println!(
+                        "copying {}: {} files, {} bytes so far",
+                        src.display(),
+                        p.files,
+                        p.bytes
+                    );
+
+                    last_report = time::Instant::now();
+                }
+            })
+            .map_err(|e| e.to_string())
+        })
+        .await??;
+
+        Ok(progress)
     }
 
-    async fn copy_boot(
+    async fn copy_partition(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
+        layout: &image::ImageLayout,
+        partition_dir: &str,
     ) -> Result<(), Box<dyn error::Error>> {
-        let img_boot_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            IMG_MOUNT_DIR,
-            BOOT_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
+        let (offset, img_mount_pb, target_pb) =
+            self.copy_paths(workspace_spec, instance_spec, layout, partition_dir);
 
-        let mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
+        let stamp_pb = target_pb.join(IMAGE_STAMP_FILE);
+
+        // Remove any stamp from an earlier copy first, so that an interrupted copy is never
+        // mistaken for a complete one.
+        if read_if_exists(&stamp_pb)?.is_some() {
+            fs::remove_file(&stamp_pb)?;
+        }
 
         self.copy_from_img(
+            &instance_spec.id,
             &workspace_spec.img_path,
-            workspace_spec.img_boot_offset,
-            &img_boot_mount_pb,
-            &mount_pb,
+            offset,
+            &img_mount_pb,
+            &target_pb,
+            self.copy_options(partition_dir),
         )
-        .await
+        .await?;
+
+        fs::write(&stamp_pb, image_stamp(workspace_spec, offset)?)?;
+
+        Ok(())
+    }
+
+    /// Stops the copy running for the given instance, if there is one, and waits for it to finish.
+    /// Returns whether there was one.
+    async fn stop_copy(&self, instance_id: &str) -> bool {
+        let running = self
+            .copies
+            .lock()
+            .expect("copies lock poisoned")
+            .remove(instance_id);
+
+        let running = match running {
+            Some(running) => running,
+            None => return false,
+        };
+
+        println!("stopping copy of image data for {}", instance_id);
+
+        running.cancel.store(true, atomic::Ordering::Relaxed);
+
+        // The copying thread holds the lock until it finishes.
+        let _ = running.done.lock().await;
+
+        true
     }
 
-    async fn copy_rootfs(
+    /// Unmounts both image partitions, ignoring partitions that are not mounted.
+    fn unmount_img(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
-    ) -> Result<(), Box<dyn error::Error>> {
-        let img_rootfs_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            IMG_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        let rootfs_mount_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-        ]
-        .iter()
-        .collect();
-
-        self.copy_from_img(
-            &workspace_spec.img_path,
-            workspace_spec.img_rootfs_offset,
-            &img_rootfs_mount_pb,
-            &rootfs_mount_pb,
-        )
-        .await
+    ) {
+        for partition_dir in [BOOT_MOUNT_DIR, ROOTFS_MOUNT_DIR] {
+            let img_mount_pb = instance_path(
+                workspace_spec,
+                instance_spec,
+                &[MOUNT_DIR, IMG_MOUNT_DIR, partition_dir],
+            );
+
+            match self.exec.unmount(&img_mount_pb) {
+                Ok(_) => {}
+                Err(e) if is_not_mounted(e.as_ref()) => {}
+                Err(e) => {
+                    println!("error unmounting {}: {}", img_mount_pb.display(), e);
+                }
+            };
+        }
     }
 }
 
@@ -793,23 +2434,105 @@ impl Step for CopyDataStep {
         String::from("copy data")
     }
 
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let layout = image::layout(workspace_spec)?;
+
+        let mut actions = vec![format!(
+            "read {} partition table of {}: boot partition at offset {}, rootfs partition at offset {}",
+            layout.table, workspace_spec.img_path, layout.boot_offset, layout.rootfs_offset
+        )];
+
+        for partition_dir in [BOOT_MOUNT_DIR, ROOTFS_MOUNT_DIR] {
+            let (offset, img_mount_pb, target_pb) =
+                self.copy_paths(workspace_spec, instance_spec, &layout, partition_dir);
+
+            let img_mount_path = path_str(&img_mount_pb)?;
+
+            actions.push(format!(
+                "mount {} at offset {} read-only at {}",
+                workspace_spec.img_path, offset, img_mount_path
+            ));
+
+            let preserved = match self.copy_options(partition_dir).preserve_ownership {
+                true => "ownership, permissions, links, special files, xattrs and timestamps",
+                false => "permissions, links, special files, xattrs and timestamps",
+            };
+
+            actions.push(format!(
+                "copy contents of {} to {} preserving {}",
+                img_mount_path,
+                path_str(&target_pb)?,
+                preserved
+            ));
+
+            actions.push(format!(
+                "record the image copied to {} in {}",
+                path_str(&target_pb)?,
+                IMAGE_STAMP_FILE
+            ));
+        }
+
+        Ok(actions)
+    }
+
+    async fn satisfied(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let layout = image::layout(workspace_spec)?;
+
+        for partition_dir in [BOOT_MOUNT_DIR, ROOTFS_MOUNT_DIR] {
+            let (offset, _, target_pb) =
+                self.copy_paths(workspace_spec, instance_spec, &layout, partition_dir);
+
+            if !holds_image_copy(workspace_spec, offset, &target_pb)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
     async fn run(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
     ) -> Result<(), Box<dyn error::Error>> {
-        self.copy_boot(workspace_spec, instance_spec).await?;
-        self.copy_rootfs(workspace_spec, instance_spec).await?;
+        let layout = image::layout(workspace_spec)?;
+
+        println!(
+            "found {} partition table in {}: boot partition at offset {}, rootfs partition at offset {}",
+            layout.table, workspace_spec.img_path, layout.boot_offset, layout.rootfs_offset
+        );
+
+        // An earlier attempt that timed out may have left a copy running with the image mounted.
+        if self.stop_copy(&instance_spec.id).await {
+            self.unmount_img(workspace_spec, instance_spec);
+        }
+
+        self.copy_partition(workspace_spec, instance_spec, &layout, BOOT_MOUNT_DIR)
+            .await?;
+        self.copy_partition(workspace_spec, instance_spec, &layout, ROOTFS_MOUNT_DIR)
+            .await?;
 
         Ok(())
     }
 
     async fn cleanup(
         &self,
-        _workspace_spec: &config::WorkspaceConfig,
-        _instance_spec: &config::InstanceConfig,
-    ) -> () {
-        ()
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) {
+        // A copy interrupted by a timeout or signal is still writing into the instance's
+        // filesystems, and keeps the image busy until it stops.
+        self.stop_copy(&instance_spec.id).await;
+
+        self.unmount_img(workspace_spec, instance_spec);
     }
 }
 
@@ -822,6 +2545,80 @@ impl Step for ConfigureUserAuthStep {
         String::from("configure auth")
     }
 
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let userconf_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                BOOT_MOUNT_DIR,
+                "userconf.txt",
+            ],
+        );
+
+        let authorized_keys_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                ROOTFS_MOUNT_DIR,
+                "root/.ssh/authorized_keys",
+            ],
+        );
+
+        Ok(vec![
+            format!(
+                "write the configured user password to {}",
+                path_str(&userconf_pb)?
+            ),
+            format!(
+                "write the configured root SSH key to {}",
+                path_str(&authorized_keys_pb)?
+            ),
+        ])
+    }
+
+    async fn satisfied(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let userconf_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                BOOT_MOUNT_DIR,
+                "userconf.txt",
+            ],
+        );
+
+        let authorized_keys_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                ROOTFS_MOUNT_DIR,
+                "root/.ssh/authorized_keys",
+            ],
+        );
+
+        let userconf_contents = format!("{}\n", instance_spec.user_password);
+
+        let authorized_keys_contents = format!("{}\n", instance_spec.root_ssh_key);
+
+        Ok(read_if_exists(&userconf_pb)? == Some(userconf_contents)
+            && read_if_exists(&authorized_keys_pb)? == Some(authorized_keys_contents))
+    }
+
     async fn run(
         &self,
         workspace_spec: &config::WorkspaceConfig,
@@ -858,13 +2655,14 @@ impl Step for ConfigureUserAuthStep {
         &self,
         _workspace_spec: &config::WorkspaceConfig,
         _instance_spec: &config::InstanceConfig,
-    ) -> () {
-        ()
+    ) {
     }
 }
 
 /// Configures the hostname for the instance.
-pub struct ConfigureHostnameStep {}
+pub struct ConfigureHostnameStep {
+    pub exec: sync::Arc<dyn exec::Executor>,
+}
 
 impl ConfigureHostnameStep {
     fn configure_etc_hostname(
@@ -888,33 +2686,33 @@ impl ConfigureHostnameStep {
         Ok(())
     }
 
+    fn hosts_sed_command(
+        &self,
+        instance_spec: &config::InstanceConfig,
+        hosts_path: &str,
+    ) -> StepCommand {
+        let hosts_sed_expr = format!("s/(.*)raspberrypi(.*?)$/\\1{}\\2/g", instance_spec.id);
+
+        StepCommand::new("sed", &["-i", "-r", "-e", &hosts_sed_expr, hosts_path])
+    }
+
     async fn configure_etc_hosts(
         &self,
         workspace_spec: &config::WorkspaceConfig,
         instance_spec: &config::InstanceConfig,
     ) -> Result<(), Box<dyn error::Error>> {
-        let hosts_pb: path::PathBuf = [
-            &workspace_spec.path,
-            &instance_spec.id,
-            MOUNT_DIR,
-            INSTANCE_MOUNT_DIR,
-            ROOTFS_MOUNT_DIR,
-            "etc/hosts",
-        ]
-        .iter()
-        .collect();
+        let hosts_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, ROOTFS_MOUNT_DIR, "etc/hosts"],
+        );
 
         let hosts_path = hosts_pb.to_str().ok_or("invalid /etc/hosts path")?;
 
-        let hosts_sed_expr = format!("s/(.*)raspberrypi(.*?)$/\\1{}\\2/g", instance_spec.id);
-
-        let sed_output = t_process::Command::new("sed")
-            .args(["-i", "-r", "-e", &hosts_sed_expr, &hosts_path])
-            .output()
-            .await?;
-
         // We don't actually care about the output here, but we do care if the command failed
-        output_or_err(sed_output)?;
+        self.hosts_sed_command(instance_spec, hosts_path)
+            .output(self.exec.as_ref())
+            .await?;
 
         Ok(())
     }
@@ -926,6 +2724,74 @@ impl Step for ConfigureHostnameStep {
         String::from("configure hostname")
     }
 
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let hostname_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                ROOTFS_MOUNT_DIR,
+                "etc/hostname",
+            ],
+        );
+
+        let hosts_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, ROOTFS_MOUNT_DIR, "etc/hosts"],
+        );
+
+        Ok(vec![
+            format!(
+                "write hostname {} to {}",
+                instance_spec.id,
+                path_str(&hostname_pb)?
+            ),
+            self.hosts_sed_command(instance_spec, path_str(&hosts_pb)?)
+                .describe(),
+        ])
+    }
+
+    async fn satisfied(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<bool, Box<dyn error::Error>> {
+        let hostname_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                ROOTFS_MOUNT_DIR,
+                "etc/hostname",
+            ],
+        );
+
+        let hosts_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, ROOTFS_MOUNT_DIR, "etc/hosts"],
+        );
+
+        let hostname_configured = match read_if_exists(&hostname_pb)? {
+            Some(hostname) => hostname.trim() == instance_spec.id,
+            None => false,
+        };
+
+        let hosts_configured = match read_if_exists(&hosts_pb)? {
+            Some(hosts) => hosts_maps_hostname(&hosts, &instance_spec.id),
+            None => false,
+        };
+
+        Ok(hostname_configured && hosts_configured)
+    }
+
     async fn run(
         &self,
         workspace_spec: &config::WorkspaceConfig,
@@ -941,8 +2807,148 @@ impl Step for ConfigureHostnameStep {
         &self,
         _workspace_spec: &config::WorkspaceConfig,
         _instance_spec: &config::InstanceConfig,
-    ) -> () {
-        ()
+    ) {
+    }
+}
+
+/// Returns whether the given /etc/hosts contents map 127.0.1.1, the address Debian gives the local
+/// hostname, to the given hostname.
+fn hosts_maps_hostname(hosts: &str, hostname: &str) -> bool {
+    hosts.lines().any(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+
+        fields.next() == Some("127.0.1.1") && fields.any(|name| name == hostname)
+    })
+}
+
+/// Checks that the instance's boot and root filesystems are configured as expected.
+pub struct VerifyStep {}
+
+impl VerifyStep {
+    /// Returns the files to check along with a line fragment each file is expected to contain.
+    fn expectations(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<(path::PathBuf, String)>, Box<dyn error::Error>> {
+        let portal = instance_spec.iscsi_portal(workspace_spec)?;
+
+        let cmdline_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[MOUNT_DIR, INSTANCE_MOUNT_DIR, BOOT_MOUNT_DIR, "cmdline.txt"],
+        );
+
+        let userconf_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                BOOT_MOUNT_DIR,
+                "userconf.txt",
+            ],
+        );
+
+        let hostname_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                ROOTFS_MOUNT_DIR,
+                "etc/hostname",
+            ],
+        );
+
+        let authorized_keys_pb = instance_path(
+            workspace_spec,
+            instance_spec,
+            &[
+                MOUNT_DIR,
+                INSTANCE_MOUNT_DIR,
+                ROOTFS_MOUNT_DIR,
+                "root/.ssh/authorized_keys",
+            ],
+        );
+
+        Ok(vec![
+            (
+                cmdline_pb.clone(),
+                format!("ISCSI_INITIATOR={}", instance_spec.iscsi_initiator_iqn),
+            ),
+            (
+                cmdline_pb.clone(),
+                format!("ISCSI_TARGET_NAME={}", instance_spec.iscsi_target_iqn),
+            ),
+            (
+                cmdline_pb.clone(),
+                format!("ISCSI_TARGET_IP={}", portal.host),
+            ),
+            (cmdline_pb, format!("ISCSI_TARGET_PORT={}", portal.port)),
+            (userconf_pb, instance_spec.user_password.clone()),
+            (hostname_pb, instance_spec.id.clone()),
+            (authorized_keys_pb, instance_spec.root_ssh_key.clone()),
+        ])
+    }
+}
+
+#[async_trait]
+impl Step for VerifyStep {
+    fn name(&self) -> String {
+        String::from("verify")
+    }
+
+    fn plan(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        self.expectations(workspace_spec, instance_spec)?
+            .iter()
+            .map(|(pb, expected)| {
+                Ok(format!(
+                    "check that {} contains '{}'",
+                    path_str(pb)?,
+                    expected
+                ))
+            })
+            .collect()
+    }
+
+    async fn run(
+        &self,
+        workspace_spec: &config::WorkspaceConfig,
+        instance_spec: &config::InstanceConfig,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let mut problems = Vec::new();
+
+        for (pb, expected) in self.expectations(workspace_spec, instance_spec)? {
+            let path_str = path_str(&pb)?;
+
+            match fs::read_to_string(&pb) {
+                Ok(contents) => {
+                    if !contents.lines().any(|l| l.contains(&expected)) {
+                        problems.push(format!("{} does not contain '{}'", path_str, expected));
+                    }
+                }
+                Err(e) => problems.push(format!("error reading {}: {}", path_str, e)),
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("; ").into())
+        }
+    }
+
+    async fn cleanup(
+        &self,
+        _workspace_spec: &config::WorkspaceConfig,
+        _instance_spec: &config::InstanceConfig,
+    ) {
     }
 }
 
@@ -955,6 +2961,14 @@ impl Step for FinishStep {
         String::from("finish")
     }
 
+    fn plan(
+        &self,
+        _workspace_spec: &config::WorkspaceConfig,
+        _instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(Vec::new())
+    }
+
     async fn run(
         &self,
         _workspace_spec: &config::WorkspaceConfig,
@@ -969,22 +2983,31 @@ impl Step for FinishStep {
         &self,
         _workspace_spec: &config::WorkspaceConfig,
         _instance_spec: &config::InstanceConfig,
-    ) -> () {
-        ()
+    ) {
     }
 }
 
-/// Echoes a message.
+/// Echoes a message. Useful for testing graphs.
+#[cfg(test)]
 pub struct EchoStep {
     pub msg: &'static str,
 }
 
+#[cfg(test)]
 #[async_trait]
 impl Step for EchoStep {
     fn name(&self) -> String {
         format!("echo {}", self.msg)
     }
 
+    fn plan(
+        &self,
+        _workspace_spec: &config::WorkspaceConfig,
+        _instance_spec: &config::InstanceConfig,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        Ok(vec![format!("echo {}", self.msg)])
+    }
+
     async fn run(
         &self,
         _workspace_spec: &config::WorkspaceConfig,
@@ -1002,7 +3025,7 @@ impl Step for EchoStep {
         &self,
         _workspace_spec: &config::WorkspaceConfig,
         _instance_spec: &config::InstanceConfig,
-    ) -> () {
+    ) {
         println!("{}: cleaning up {}", self.name(), self.msg);
     }
 }
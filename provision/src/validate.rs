@@ -0,0 +1,696 @@
+use std::collections;
+use std::fmt;
+use std::net;
+use std::path;
+
+use base64::Engine;
+
+use crate::config;
+
+/// The SSH public key types OpenSSH accepts in `authorized_keys`.
+const SSH_KEY_TYPES: [&str; 8] = [
+    "ssh-ed25519",
+    "ssh-rsa",
+    "ssh-dss",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// A problem with a config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// The config file with the problem.
+    pub path: path::PathBuf,
+    /// The field with the problem, e.g. `disk.partitions[1].size`, if the problem is with a single
+    /// field.
+    pub field: Option<String>,
+    /// What is wrong.
+    pub msg: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{}: {}: {}", self.path.display(), field, self.msg),
+            None => write!(f, "{}: {}", self.path.display(), self.msg),
+        }
+    }
+}
+
+/// ValidationError represents every problem found in a set of config files.
+#[derive(thiserror::Error)]
+pub struct ValidationError {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.problems.len() {
+            1 => write!(f, "found 1 problem in config files:")?,
+            n => write!(f, "found {} problems in config files:", n)?,
+        }
+
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Errors returned from main are printed with Debug, so it lists the problems the same way.
+impl fmt::Debug for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Loads the workspace config and every instance config, and checks them for problems that would
+/// otherwise only show up partway through a run or once an instance boots, like malformed MAC
+/// addresses or SSH keys, or two instances sharing a MAC address. Returns every problem in every
+/// file rather than stopping at the first.
+pub fn load_configs(
+    workspace_path: &path::Path,
+    instances_dir: &path::Path,
+) -> Result<(config::WorkspaceConfig, Vec<config::InstanceConfig>), ValidationError> {
+    let mut problems = Vec::new();
+
+    // Instance configs build on the workspace's instance defaults and profiles, so they cannot be
+    // loaded without it.
+    let workspace_spec = match config::load_workspace_config(workspace_path) {
+        Ok(workspace_spec) => workspace_spec,
+        Err(problem) => {
+            return Err(ValidationError {
+                problems: vec![problem],
+            });
+        }
+    };
+
+    problems.extend(field_problems(
+        workspace_path,
+        check_workspace(&workspace_spec),
+    ));
+
+    let instance_files = match config::load_instance_configs(instances_dir, &workspace_spec) {
+        Ok(instance_files) => instance_files,
+        Err(e) => {
+            problems.push(Problem {
+                path: instances_dir.to_path_buf(),
+                field: None,
+                msg: e.to_string(),
+            });
+
+            Vec::new()
+        }
+    };
+
+    let mut loaded = Vec::with_capacity(instance_files.len());
+
+    for instance_file in instance_files {
+        match instance_file.instance_spec {
+            Ok(instance_spec) => {
+                problems.extend(field_problems(
+                    &instance_file.path,
+                    check_instance(&workspace_spec, &instance_spec),
+                ));
+
+                loaded.push((instance_file.path, instance_spec));
+            }
+            Err(problem) => problems.push(problem),
+        }
+    }
+
+    problems.extend(check_unique(&loaded));
+
+    let instance_specs = loaded.into_iter().map(|(_, spec)| spec).collect();
+
+    if !problems.is_empty() {
+        return Err(ValidationError { problems });
+    }
+
+    Ok((workspace_spec, instance_specs))
+}
+
+fn field_problems(
+    path: &path::Path,
+    problems: Vec<(Option<&'static str>, String)>,
+) -> impl Iterator<Item = Problem> {
+    problems.into_iter().map(|(field, msg)| Problem {
+        path: path.to_path_buf(),
+        field: field.map(String::from),
+        msg,
+    })
+}
+
+/// Returns the problems with a workspace config, each with the field it is in if there is one.
+fn check_workspace(
+    workspace_spec: &config::WorkspaceConfig,
+) -> Vec<(Option<&'static str>, String)> {
+    let mut problems = Vec::new();
+
+    if workspace_spec.nfs_server_ip.parse::<net::IpAddr>().is_err() {
+        problems.push((
+            Some("nfs_server_ip"),
+            format!(
+                "'{}' is not an IPv4 or IPv6 address",
+                workspace_spec.nfs_server_ip
+            ),
+        ));
+    }
+
+    if let Err(e) = workspace_spec.iscsi_portals() {
+        problems.push((None, e.to_string()));
+    }
+
+    problems
+}
+
+/// Returns the problems with an instance config, each with the field it is in if there is one.
+fn check_instance(
+    workspace_spec: &config::WorkspaceConfig,
+    instance_spec: &config::InstanceConfig,
+) -> Vec<(Option<&'static str>, String)> {
+    let mut problems = Vec::new();
+
+    let field_checks = [
+        ("id", check_hostname(&instance_spec.id)),
+        (
+            "iscsi_initiator_iqn",
+            check_iqn(&instance_spec.iscsi_initiator_iqn),
+        ),
+        (
+            "iscsi_target_iqn",
+            check_iqn(&instance_spec.iscsi_target_iqn),
+        ),
+        ("mac_addr", check_mac_addr(&instance_spec.mac_addr)),
+        (
+            "user_password",
+            check_user_password(&instance_spec.user_password),
+        ),
+        ("root_ssh_key", check_ssh_key(&instance_spec.root_ssh_key)),
+    ];
+
+    for (field, result) in field_checks {
+        if let Err(msg) = result {
+            problems.push((Some(field), msg));
+        }
+    }
+
+    if let Err(e) = instance_spec.validate_chap() {
+        problems.push((None, e.to_string()));
+    }
+
+    // Problems with the workspace's portals are reported once, for the workspace config.
+    if workspace_spec.iscsi_portals().is_ok()
+        && let Err(e) = instance_spec.iscsi_portals(workspace_spec)
+    {
+        problems.push((None, e.to_string()));
+    }
+
+    if let Err(e) = instance_spec.partitions(workspace_spec) {
+        problems.push((None, e.to_string()));
+    }
+
+    problems
+}
+
+/// Returns problems for instances that share an ID, MAC address, initiator IQN or target IQN with
+/// an instance in an earlier file. Instances sharing any of these would share a boot directory,
+/// knock each other's iSCSI sessions off, or format the same LUN. A target in particular cannot be
+/// shared, since logging out of it on cleanup drops every session on the target, including those
+/// of other instances. Values are compared ignoring case, since hostnames, MAC addresses and IQNs
+/// are all case-insensitive.
+fn check_unique(instances: &[(path::PathBuf, config::InstanceConfig)]) -> Vec<Problem> {
+    type Describe = fn(&config::InstanceConfig) -> String;
+
+    let fields: [(&str, Describe); 4] = [
+        ("id", |spec| format!("id {}", spec.id)),
+        ("mac_addr", |spec| format!("MAC address {}", spec.mac_addr)),
+        ("iscsi_initiator_iqn", |spec| {
+            format!("initiator {}", spec.iscsi_initiator_iqn)
+        }),
+        ("iscsi_target_iqn", |spec| {
+            format!("target {}", spec.iscsi_target_iqn)
+        }),
+    ];
+
+    let mut problems = Vec::new();
+
+    for (field, describe) in fields {
+        let mut first_paths: collections::HashMap<String, &path::PathBuf> =
+            collections::HashMap::new();
+
+        for (path, spec) in instances {
+            let description = describe(spec);
+
+            match first_paths.get(&description.to_lowercase()) {
+                Some(first_path) => problems.push(Problem {
+                    path: path.clone(),
+                    field: Some(field.to_string()),
+                    msg: format!(
+                        "{} is already used by {}",
+                        description,
+                        first_path.display()
+                    ),
+                }),
+                None => {
+                    first_paths.insert(description.to_lowercase(), path);
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+/// Checks that the given ID can be used as a hostname, i.e. that it is a valid DNS label.
+fn check_hostname(id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > 63 {
+        return Err(String::from(
+            "must be between 1 and 63 characters long, since it is used as the hostname",
+        ));
+    }
+
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(format!(
+            "'{}' must only contain letters, digits and hyphens, since it is used as the hostname",
+            id
+        ));
+    }
+
+    if id.starts_with('-') || id.ends_with('-') {
+        return Err(format!("'{}' must not start or end with a hyphen", id));
+    }
+
+    Ok(())
+}
+
+/// Checks that the given name is an iSCSI qualified name, e.g. `iqn.2024-01.com.example:node1`.
+fn check_iqn(iqn: &str) -> Result<(), String> {
+    let err = || {
+        format!(
+            "'{}' is not an IQN of the form iqn.YYYY-MM.reversed.domain[:name]",
+            iqn
+        )
+    };
+
+    let rest = iqn.strip_prefix("iqn.").ok_or_else(err)?;
+
+    let (date, rest) = rest.split_once('.').ok_or_else(err)?;
+
+    let (authority, name) = match rest.split_once(':') {
+        Some((authority, name)) => (authority, Some(name)),
+        None => (rest, None),
+    };
+
+    let date_valid = match date.split_once('-') {
+        Some((year, month)) => {
+            year.len() == 4
+                && year.chars().all(|c| c.is_ascii_digit())
+                && matches!(month.parse::<u32>(), Ok(1..=12))
+                && month.len() == 2
+        }
+        None => false,
+    };
+
+    let authority_valid = authority.split('.').all(|label| {
+        !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+
+    let name_valid = match name {
+        Some(name) => !name.is_empty() && !name.chars().any(|c| c.is_whitespace()),
+        None => true,
+    };
+
+    if !date_valid || !authority_valid || !name_valid || iqn.len() > 223 {
+        return Err(err());
+    }
+
+    Ok(())
+}
+
+/// Checks that the given MAC address is in the form the Raspberry Pi bootloader uses for its TFTP
+/// directory, e.g. `dc-a6-32-00-00-01`.
+fn check_mac_addr(mac_addr: &str) -> Result<(), String> {
+    let octets: Vec<&str> = mac_addr.split('-').collect();
+
+    let valid = octets.len() == 6
+        && octets.iter().all(|o| {
+            o.len() == 2
+                && o.chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        });
+
+    if !valid {
+        return Err(format!(
+            "'{}' is not a MAC address of the form aa-bb-cc-dd-ee-ff with lowercase hex digits",
+            mac_addr
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the given user and password are in the form `<username>:<hash>`, where the hash is
+/// a SHA-512 crypt hash like those from `openssl passwd -6`.
+fn check_user_password(user_password: &str) -> Result<(), String> {
+    let (username, hash) = match user_password.split_once(':') {
+        Some((username, hash)) if !username.is_empty() => (username, hash),
+        _ => return Err(String::from("must be of the form <username>:<hash>")),
+    };
+
+    if username.chars().any(|c| c.is_whitespace()) {
+        return Err(format!(
+            "username '{}' must not contain whitespace",
+            username
+        ));
+    }
+
+    let is_crypt_char = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '/';
+
+    let mut fields: Vec<&str> = hash.split('$').collect();
+
+    // `$6$rounds=N$salt$hash` splits into ["", "6", "rounds=N", "salt", "hash"].
+    if fields.len() == 5 && fields[2].starts_with("rounds=") {
+        fields.remove(2);
+    }
+
+    let valid = match fields.as_slice() {
+        ["", "6", salt, hash] => {
+            (1..=16).contains(&salt.len())
+                && salt.chars().all(is_crypt_char)
+                && hash.len() == 86
+                && hash.chars().all(is_crypt_char)
+        }
+        _ => false,
+    };
+
+    if !valid {
+        return Err(String::from(
+            "the password hash must be a SHA-512 crypt hash starting with $6$, e.g. from openssl passwd -6",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the given key is an OpenSSH public key, i.e. a line from `authorized_keys` of the
+/// form `<type> <base64 key> [comment]` whose key data matches its type.
+fn check_ssh_key(key: &str) -> Result<(), String> {
+    if key.trim().lines().count() != 1 {
+        return Err(String::from("must be a single public key on one line"));
+    }
+
+    let mut parts = key.split_whitespace();
+
+    let (key_type, data) = match (parts.next(), parts.next()) {
+        (Some(key_type), Some(data)) => (key_type, data),
+        _ => {
+            return Err(String::from(
+                "is not an OpenSSH public key of the form <type> <base64 key> [comment]",
+            ));
+        }
+    };
+
+    if !SSH_KEY_TYPES.contains(&key_type) {
+        return Err(format!("'{}' is not a supported SSH key type", key_type));
+    }
+
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| format!("the key data is not valid base64: {}", e))?;
+
+    // The key data starts with its type as a length-prefixed string.
+    let embedded_type = data
+        .get(..4)
+        .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+        .and_then(|len| data.get(4..4 + len));
+
+    if embedded_type != Some(key_type.as_bytes()) {
+        return Err(format!(
+            "the key data is not a {} key; check that the key was copied whole",
+            key_type
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use config::fixtures;
+
+    fn workspace_json(nfs_server_ip: &str) -> serde_json::Value {
+        let mut workspace_json = fixtures::workspace_json(path::Path::new("/srv/provision"));
+
+        workspace_json["nfs_server_ip"] = serde_json::json!(nfs_server_ip);
+
+        workspace_json
+    }
+
+    /// Writes a workspace config and the given instance configs to a new directory, returning the
+    /// directory and the paths of the workspace config and the instances directory.
+    fn write_configs(
+        workspace: &serde_json::Value,
+        instances: &[(&str, serde_json::Value)],
+    ) -> (tempfile::TempDir, path::PathBuf, path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let instances_dir = dir.path().join("instances");
+        let workspace_path = dir.path().join("workspace.json");
+
+        fs::create_dir(&instances_dir).unwrap();
+        fs::write(&workspace_path, workspace.to_string()).unwrap();
+
+        for (name, instance) in instances {
+            fs::write(
+                instances_dir.join(format!("{}.json", name)),
+                instance.to_string(),
+            )
+            .unwrap();
+        }
+
+        (dir, workspace_path, instances_dir)
+    }
+
+    fn load_problems(workspace_path: &path::Path, instances_dir: &path::Path) -> ValidationError {
+        match load_configs(workspace_path, instances_dir) {
+            Ok(_) => panic!("invalid configs loaded"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn checks_fields() {
+        assert_eq!(check_hostname("node-1"), Ok(()));
+        assert!(check_hostname("node_1").is_err());
+        assert!(check_hostname("-node1").is_err());
+        assert!(check_hostname(&"a".repeat(64)).is_err());
+
+        assert_eq!(check_iqn("iqn.2024-01.com.example:node1"), Ok(()));
+        assert_eq!(check_iqn("iqn.2000-01.com.nas"), Ok(()));
+        assert!(check_iqn("iqn.2024-13.com.example:node1").is_err());
+        assert!(check_iqn("iqn.24-01.com.example").is_err());
+        assert!(check_iqn("iqn.2024-01..example").is_err());
+        assert!(check_iqn("eui.02004567A425678D").is_err());
+
+        assert_eq!(check_mac_addr("dc-a6-32-00-00-01"), Ok(()));
+        assert!(check_mac_addr("dc:a6:32:00:00:01").is_err());
+        assert!(check_mac_addr("DC-A6-32-00-00-01").is_err());
+        assert!(check_mac_addr("dc-a6-32-00-00").is_err());
+
+        assert_eq!(check_user_password(fixtures::USER_PASSWORD), Ok(()));
+        assert!(check_user_password("pi:password").is_err());
+        assert!(check_user_password("$6$saltsalt$abc").is_err());
+        assert!(check_user_password(&fixtures::USER_PASSWORD.replace("$6$", "$5$")).is_err());
+
+        assert_eq!(check_ssh_key(fixtures::SSH_KEY), Ok(()));
+        assert_eq!(check_ssh_key("ssh-rsa AAAAB3NzaC1yc2EAAAADAQAB"), Ok(()));
+        assert_eq!(
+            check_ssh_key("ssh-ed25519 AAAA"),
+            Err(String::from(
+                "the key data is not a ssh-ed25519 key; check that the key was copied whole"
+            ))
+        );
+        assert_eq!(
+            check_ssh_key(&fixtures::SSH_KEY.replace("ssh-ed25519 ", "ssh-rsa ")),
+            Err(String::from(
+                "the key data is not a ssh-rsa key; check that the key was copied whole"
+            ))
+        );
+        assert!(check_ssh_key("ssh-ed25519 not!base64").is_err());
+        assert!(check_ssh_key("ssh-foo AAAA").is_err());
+        assert!(check_ssh_key(&format!("{}\n{}", fixtures::SSH_KEY, fixtures::SSH_KEY)).is_err());
+    }
+
+    #[test]
+    fn reports_every_problem_in_every_file() {
+        let mut node2 = fixtures::instance_json("node_2", "dc:a6:32:00:00:02");
+
+        node2["disk"] = serde_json::json!({"partitions": [{"mount_point": "/", "size": "2XB"}]});
+
+        let mut node3 = fixtures::instance_json("node3", "dc-a6-32-00-00-03");
+
+        node3["disk"] = serde_json::json!({"lun": "one"});
+
+        let (_dir, workspace_path, instances_dir) = write_configs(
+            &workspace_json("nas.local"),
+            &[
+                (
+                    "node1",
+                    fixtures::instance_json("node1", "dc-a6-32-00-00-01"),
+                ),
+                ("node2", node2),
+                ("node3", node3),
+            ],
+        );
+
+        fs::write(instances_dir.join("node4.json"), "{\"id\": \"node4\",").unwrap();
+
+        let err = load_problems(&workspace_path, &instances_dir);
+
+        let problems: Vec<(String, Option<&str>)> = err
+            .problems
+            .iter()
+            .map(|p| {
+                (
+                    p.path.file_name().unwrap().to_string_lossy().to_string(),
+                    p.field.as_deref(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            problems,
+            [
+                (String::from("workspace.json"), Some("nfs_server_ip")),
+                (String::from("node2.json"), Some("id")),
+                (String::from("node2.json"), Some("mac_addr")),
+                (String::from("node2.json"), None),
+                (String::from("node3.json"), Some("disk.lun")),
+                (String::from("node4.json"), None),
+            ]
+        );
+
+        let msg = err.to_string();
+
+        assert!(
+            msg.starts_with("found 6 problems in config files:\n"),
+            "{}",
+            msg
+        );
+        assert!(
+            msg.contains("node4.json: EOF while parsing a value at line 1 column 15"),
+            "{}",
+            msg
+        );
+
+        // Once the problems are fixed, the configs load.
+        fs::write(&workspace_path, workspace_json("10.0.0.3").to_string()).unwrap();
+
+        for (id, mac_addr) in [
+            ("node2", "dc-a6-32-00-00-02"),
+            ("node3", "dc-a6-32-00-00-03"),
+            ("node4", "dc-a6-32-00-00-04"),
+        ] {
+            fs::write(
+                instances_dir.join(format!("{}.json", id)),
+                fixtures::instance_json(id, mac_addr).to_string(),
+            )
+            .unwrap();
+        }
+
+        let (_, instance_specs) = load_configs(&workspace_path, &instances_dir).unwrap();
+
+        assert_eq!(instance_specs.len(), 4);
+    }
+
+    #[test]
+    fn reports_duplicate_instances() {
+        let node1 = fixtures::instance_json("node1", "dc-a6-32-00-00-01");
+
+        // Shares node1's MAC address, initiator and target, though on another LUN.
+        let mut node2 = fixtures::instance_json("node2", "DC-A6-32-00-00-01");
+
+        node2["iscsi_initiator_iqn"] = node1["iscsi_initiator_iqn"].clone();
+        node2["iscsi_target_iqn"] = node1["iscsi_target_iqn"].clone();
+        node2["disk"] = serde_json::json!({"lun": 2});
+
+        // Shares node1's ID, and so its initiator, and its target on the same LUN.
+        let mut node3 = fixtures::instance_json("node1", "dc-a6-32-00-00-03");
+
+        node3["iscsi_target_iqn"] = serde_json::json!("iqn.2024-01.TEST:target-node1");
+
+        let (dir, workspace_path, instances_dir) = write_configs(
+            &workspace_json("10.0.0.3"),
+            &[("node1", node1), ("node2", node2), ("node3", node3)],
+        );
+
+        let err = load_problems(&workspace_path, &instances_dir);
+
+        let msg = err
+            .to_string()
+            .replace(&format!("{}/", dir.path().display()), "");
+
+        // The uppercase MAC address of node2 is also reported as malformed.
+        assert_eq!(
+            msg,
+            "found 7 problems in config files:
+  instances/node2.json: mac_addr: 'DC-A6-32-00-00-01' is not a MAC address of the form aa-bb-cc-dd-ee-ff with lowercase hex digits
+  instances/node3.json: id: id node1 is already used by instances/node1.json
+  instances/node2.json: mac_addr: MAC address DC-A6-32-00-00-01 is already used by instances/node1.json
+  instances/node2.json: iscsi_initiator_iqn: initiator iqn.2024-01.test:node1 is already used by instances/node1.json
+  instances/node3.json: iscsi_initiator_iqn: initiator iqn.2024-01.test:node1 is already used by instances/node1.json
+  instances/node2.json: iscsi_target_iqn: target iqn.2024-01.test:target-node1 is already used by instances/node1.json
+  instances/node3.json: iscsi_target_iqn: target iqn.2024-01.TEST:target-node1 is already used by instances/node1.json"
+        );
+    }
+
+    #[test]
+    fn refuses_targets_shared_across_luns() {
+        let node1 = fixtures::instance_json("node1", "dc-a6-32-00-00-01");
+
+        let mut node2 = fixtures::instance_json("node2", "dc-a6-32-00-00-02");
+
+        node2["iscsi_target_iqn"] = node1["iscsi_target_iqn"].clone();
+        node2["disk"] = serde_json::json!({"lun": 2});
+
+        let (_dir, workspace_path, instances_dir) = write_configs(
+            &workspace_json("10.0.0.3"),
+            &[("node1", node1), ("node2", node2)],
+        );
+
+        let err = load_problems(&workspace_path, &instances_dir);
+
+        assert_eq!(err.problems.len(), 1, "{}", err);
+        assert_eq!(err.problems[0].field.as_deref(), Some("iscsi_target_iqn"));
+        assert!(err.problems[0].path.ends_with("instances/node2.json"));
+    }
+
+    #[test]
+    fn rejects_mount_points_outside_the_rootfs() {
+        let mut node1 = fixtures::instance_json("node1", "dc-a6-32-00-00-01");
+
+        node1["disk"] = serde_json::json!({"partitions": [
+            {"mount_point": "/", "size": "1GiB"},
+            {"mount_point": "/../../etc"},
+        ]});
+
+        let (_dir, workspace_path, instances_dir) =
+            write_configs(&workspace_json("10.0.0.3"), &[("node1", node1)]);
+
+        let err = load_problems(&workspace_path, &instances_dir);
+
+        assert_eq!(err.problems.len(), 1, "{}", err);
+        assert_eq!(
+            err.problems[0].msg,
+            "partition 2 has mount point '/../../etc', which must not have empty, '.' or '..' components or whitespace"
+        );
+    }
+}